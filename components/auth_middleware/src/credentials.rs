@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::ops::BitOr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What a principal may do, as a set of `READ`, `WRITE` and `DELETE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    pub const READ: Permissions = Permissions(1);
+    pub const WRITE: Permissions = Permissions(1 << 1);
+    pub const DELETE: Permissions = Permissions(1 << 2);
+
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permissions::READ => "read",
+            Permissions::WRITE => "write",
+            Permissions::DELETE => "delete",
+            _ => "mixed",
+        }
+    }
+
+    /// Parses a list such as `["read", "write"]`.
+    pub fn parse<S: AsRef<str>>(names: &[S]) -> Result<Permissions, String> {
+        names
+            .iter()
+            .try_fold(Permissions::NONE, |acc, name| match name.as_ref() {
+                "read" => Ok(acc | Permissions::READ),
+                "write" => Ok(acc | Permissions::WRITE),
+                "delete" => Ok(acc | Permissions::DELETE),
+                name => Err(format!("unknown permission {}", name)),
+            })
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, rhs: Permissions) -> Permissions {
+        Permissions(self.0 | rhs.0)
+    }
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Non empty lines that are not `#` comments, with their line number.
+fn lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Who may access the cache and with which permissions.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    // User name to bcrypt hash.
+    htpasswd: HashMap<String, String>,
+    // Token to principal.
+    tokens: HashMap<String, Arc<str>>,
+    permissions: HashMap<String, Permissions>,
+    default_permissions: Permissions,
+}
+
+impl Credentials {
+    pub fn new(default_permissions: Permissions) -> Credentials {
+        Credentials {
+            default_permissions,
+            ..Credentials::default()
+        }
+    }
+
+    /// Loads `user:hash` lines, as written by `htpasswd -B`. Only bcrypt
+    /// hashes are accepted.
+    pub fn load_htpasswd(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        for (no, line) in lines(&content) {
+            let (user, hash) = line.split_once(':').ok_or_else(|| {
+                invalid_data(format!("{}:{}: expect user:hash", path.display(), no))
+            })?;
+            if !(hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")) {
+                return Err(invalid_data(format!(
+                    "{}:{}: user {} is not hashed with bcrypt",
+                    path.display(),
+                    no,
+                    user
+                )));
+            }
+            self.htpasswd.insert(user.to_owned(), hash.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Loads `token principal` lines.
+    pub fn load_tokens(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        for (no, line) in lines(&content) {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(token), Some(principal), None) => {
+                    self.tokens.insert(token.to_owned(), Arc::from(principal));
+                }
+                _ => {
+                    return Err(invalid_data(format!(
+                        "{}:{}: expect token principal",
+                        path.display(),
+                        no
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_permissions(&mut self, principal: impl Into<String>, permissions: Permissions) {
+        self.permissions.insert(principal.into(), permissions);
+    }
+
+    pub fn permissions(&self, principal: &str) -> Permissions {
+        self.permissions
+            .get(principal)
+            .copied()
+            .unwrap_or(self.default_permissions)
+    }
+
+    pub fn principal_by_token(&self, token: &str) -> Option<Arc<str>> {
+        self.tokens.get(token).cloned()
+    }
+
+    /// Checks a basic auth password, this runs bcrypt and is slow by design.
+    pub fn verify_password(&self, user: &str, password: &str) -> bool {
+        match self.htpasswd.get(user) {
+            Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    fn file(content: &str) -> tempfile::NamedTempFile {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+        f
+    }
+
+    #[test]
+    fn test_permissions() {
+        let rw = Permissions::parse(&["read", "write"]).unwrap();
+        assert!(rw.contains(Permissions::READ));
+        assert!(rw.contains(Permissions::WRITE));
+        assert!(!rw.contains(Permissions::DELETE));
+        assert!(Permissions::parse(&["admin"]).is_err());
+    }
+
+    #[test]
+    fn test_load_credentials() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let htpasswd = file(&format!("# comment\nci:{}\n", hash));
+        let tokens = file("t0ken bot\n\n");
+
+        let mut creds = Credentials::new(Permissions::READ);
+        creds.load_htpasswd(htpasswd.path()).unwrap();
+        creds.load_tokens(tokens.path()).unwrap();
+        creds.set_permissions("ci", Permissions::READ | Permissions::WRITE);
+
+        assert!(creds.verify_password("ci", "secret"));
+        assert!(!creds.verify_password("ci", "wrong"));
+        assert!(!creds.verify_password("nobody", "secret"));
+        assert_eq!(creds.principal_by_token("t0ken").as_deref(), Some("bot"));
+        assert!(creds.principal_by_token("other").is_none());
+        assert!(creds.permissions("ci").contains(Permissions::WRITE));
+        assert_eq!(creds.permissions("bot"), Permissions::READ);
+
+        let md5 = file("old:$apr1$abc$def\n");
+        assert!(creds.load_htpasswd(md5.path()).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate slog_global;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use actix_service::{Service, Transform};
+use actix_web::body::EitherBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::Error;
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::Method;
+use actix_web::{web, HttpMessage, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::metrics::*;
+
+mod credentials;
+mod metrics;
+
+pub use crate::credentials::{Credentials, Permissions};
+
+const BASIC_CHALLENGE: &str = r#"Basic realm="greenhouse""#;
+// Verified basic credentials kept to skip bcrypt, the cache is dropped as a
+// whole once full.
+const VERIFIED_CACHE_CAPACITY: usize = 1024;
+
+/// The authenticated identity of a request, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct Principal(pub Arc<str>);
+
+/// The principal of a verified TLS client certificate, stored in the
+/// connection data by the listener.
+#[derive(Clone, Debug)]
+pub struct ClientCertPrincipal(pub Arc<str>);
+
+/// The permission a request needs, by its method.
+pub fn required_permission(method: &Method) -> Permissions {
+    match *method {
+        Method::PUT | Method::POST => Permissions::WRITE,
+        Method::DELETE => Permissions::DELETE,
+        _ => Permissions::READ,
+    }
+}
+
+struct Inner {
+    credentials: Credentials,
+    anonymous_read: bool,
+    // `Authorization` header values that passed bcrypt, to their principal.
+    verified: Mutex<HashMap<String, Arc<str>>>,
+}
+
+impl Inner {
+    async fn authenticate(self: &Arc<Self>, header: &str) -> Option<Arc<str>> {
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            return self.credentials.principal_by_token(token.trim());
+        }
+        let encoded = header.strip_prefix("Basic ")?;
+        if let Some(principal) = self.verified.lock().unwrap().get(header) {
+            AUTH_VERIFY_CACHE.with_label_values(&["hit"]).inc();
+            return Some(principal.clone());
+        }
+        AUTH_VERIFY_CACHE.with_label_values(&["miss"]).inc();
+        let decoded = base64::decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let (user, password) = (user.to_owned(), password.to_owned());
+        // bcrypt takes tens of milliseconds, keep it off the http worker.
+        let inner = self.clone();
+        let verify_user = user.clone();
+        let ok = web::block(move || inner.credentials.verify_password(&verify_user, &password))
+            .await
+            .unwrap_or(false);
+        if !ok {
+            return None;
+        }
+        let principal: Arc<str> = Arc::from(user);
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= VERIFIED_CACHE_CAPACITY {
+            verified.clear();
+        }
+        verified.insert(header.to_owned(), principal.clone());
+        Some(principal)
+    }
+}
+
+/// Authenticates requests with HTTP Basic against an htpasswd file, with
+/// bearer tokens or by their client certificate, then checks the principal may do what the method asks.
+#[derive(Clone)]
+pub struct Auth {
+    inner: Option<Arc<Inner>>,
+    required: Option<Permissions>,
+}
+
+impl Auth {
+    /// With `anonymous_read`, requests without credentials may read.
+    pub fn new(credentials: Credentials, anonymous_read: bool) -> Auth {
+        Auth {
+            inner: Some(Arc::new(Inner {
+                credentials,
+                anonymous_read,
+                verified: Mutex::new(HashMap::new()),
+            })),
+            required: None,
+        }
+    }
+
+    /// Lets every request through.
+    pub fn disabled() -> Auth {
+        Auth {
+            inner: None,
+            required: None,
+        }
+    }
+
+    /// Asks `permission` of every request instead of the one of its method.
+    pub fn require(mut self, permission: Permissions) -> Auth {
+        self.required = Some(permission);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Auth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthMiddleware {
+            service: Rc::new(service),
+            inner: self.inner.clone(),
+            required: self.required,
+        }))
+    }
+}
+
+/// Auth middleware
+pub struct AuthMiddleware<S> {
+    inner: Option<Arc<Inner>>,
+    required: Option<Permissions>,
+    service: Rc<S>,
+}
+
+fn reject<B>(
+    req: ServiceRequest,
+    reason: &'static str,
+    required: Permissions,
+) -> ServiceResponse<EitherBody<B>> {
+    AUTH_FAILURES
+        .with_label_values(&[reason, required.as_str()])
+        .inc();
+    let res = if reason == "forbidden" {
+        HttpResponse::Forbidden().finish()
+    } else {
+        HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, BASIC_CHALLENGE))
+            .finish()
+    };
+    req.into_response(res).map_into_right_body()
+}
+
+impl<S, B> Service<ServiceRequest> for AuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let required = self.required;
+        let inner = match &self.inner {
+            Some(inner) => inner.clone(),
+            None => {
+                return Box::pin(async move {
+                    service.call(req).await.map(|res| res.map_into_left_body())
+                })
+            }
+        };
+        Box::pin(async move {
+            let required = required.unwrap_or_else(|| required_permission(req.method()));
+            let header = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .map(ToOwned::to_owned);
+            let cert_principal = req.conn_data::<ClientCertPrincipal>().map(|p| p.0.clone());
+            let principal = match header {
+                // The handshake already verified the certificate.
+                None if cert_principal.is_some() => cert_principal,
+                None if inner.anonymous_read && required == Permissions::READ => None,
+                None => return Ok(reject(req, "missing", required)),
+                Some(header) => match inner.authenticate(&header).await {
+                    Some(principal) => Some(principal),
+                    None => return Ok(reject(req, "invalid", required)),
+                },
+            };
+            if let Some(principal) = principal {
+                if !inner.credentials.permissions(&principal).contains(required) {
+                    warn!(
+                        "permission denied";
+                        "principal" => &*principal,
+                        "permission" => required.as_str(),
+                        "path" => req.path(),
+                    );
+                    return Ok(reject(req, "forbidden", required));
+                }
+                req.extensions_mut().insert(Principal(principal));
+            }
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[actix_rt::test]
+    async fn test_require() {
+        let mut tokens = tempfile::NamedTempFile::new().unwrap();
+        tokens.write_all(b"r3ad reader\nwr1te writer\n").unwrap();
+        let mut creds = Credentials::new(Permissions::READ);
+        creds.load_tokens(tokens.path()).unwrap();
+        creds.set_permissions("writer", Permissions::READ | Permissions::WRITE);
+        let app = test::init_service(
+            App::new()
+                .wrap(Auth::new(creds, true).require(Permissions::WRITE))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |token: Option<&str>| {
+            let req = TestRequest::get().uri("/");
+            match token {
+                Some(token) => req.insert_header((AUTHORIZATION, format!("Bearer {}", token))),
+                None => req,
+            }
+            .to_request()
+        };
+
+        // A read, but anonymous reads don't cover it.
+        let resp = test::call_service(&app, get(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, get(Some("r3ad"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, get(Some("wr1te"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,16 @@
+use prometheus::*;
+
+lazy_static! {
+    pub static ref AUTH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_auth_failures_total",
+        "Number of requests rejected by authentication or authorization",
+        &["reason", "permission"]
+    )
+    .unwrap();
+    pub static ref AUTH_VERIFY_CACHE: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_auth_verify_cache_total",
+        "Lookups of the verified basic credentials cache",
+        &["result"]
+    )
+    .unwrap();
+}
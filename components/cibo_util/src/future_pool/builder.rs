@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
-use tokio::runtime::Builder as TokioBuilder;
+use tokio::runtime::{Builder as TokioBuilder, Runtime};
 
 use super::metrics::*;
+use crate::time::Instant;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Config {
@@ -22,20 +25,63 @@ impl Config {
     }
 }
 
+type Hook = Arc<dyn Fn() + Send + Sync>;
+
+/// What every runtime of a pool is built with, the first one and those
+/// replacing it on `FuturePool::resize`.
+#[derive(Clone)]
+pub(super) struct RuntimeSettings {
+    name: Option<String>,
+    enable_all: bool,
+    stack_size: Option<usize>,
+    after_start: Option<Hook>,
+    before_stop: Option<Hook>,
+}
+
+impl RuntimeSettings {
+    pub(super) fn build(&self, workers: usize) -> io::Result<Runtime> {
+        let mut builder = TokioBuilder::new_multi_thread();
+        builder.worker_threads(workers);
+        if self.enable_all {
+            builder.enable_all();
+        }
+        if let Some(name) = &self.name {
+            builder.thread_name(name.clone());
+        }
+        if let Some(stack_size) = self.stack_size {
+            builder.thread_stack_size(stack_size);
+        }
+        if let Some(f) = self.after_start.clone() {
+            builder.on_thread_start(move || f());
+        }
+        if let Some(f) = self.before_stop.clone() {
+            builder.on_thread_stop(move || f());
+        }
+        builder.build()
+    }
+}
+
 pub struct Builder {
-    inner_builder: TokioBuilder,
-    name_prefix: Option<String>,
+    settings: RuntimeSettings,
     on_tick: Option<Box<dyn Fn() + Send + Sync>>,
     max_tasks: usize,
+    workers: usize,
 }
 
 impl Builder {
     pub fn new() -> Self {
         Self {
-            inner_builder: TokioBuilder::new_multi_thread(),
-            name_prefix: None,
+            settings: RuntimeSettings {
+                name: None,
+                enable_all: false,
+                stack_size: None,
+                after_start: None,
+                before_stop: None,
+            },
             on_tick: None,
             max_tasks: std::usize::MAX,
+            // Tokio's default, one worker per CPU.
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
         }
     }
 
@@ -51,27 +97,27 @@ impl Builder {
     }
 
     fn enable_all(&mut self) -> &mut Self {
-        self.inner_builder.enable_all();
+        self.settings.enable_all = true;
         self
     }
 
     pub fn pool_size(&mut self, val: usize) -> &mut Self {
-        self.inner_builder.worker_threads(val);
+        self.workers = val;
         self
     }
 
     pub fn stack_size(&mut self, val: usize) -> &mut Self {
-        self.inner_builder.thread_stack_size(val);
+        self.settings.stack_size = Some(val);
         self
     }
 
     pub fn name_prefix(&mut self, val: impl Into<String>) -> &mut Self {
-        let name = val.into();
-        self.name_prefix = Some(name.clone());
-        self.inner_builder.thread_name(name);
+        self.settings.name = Some(val.into());
         self
     }
 
+    /// Called by a worker about once per `TICK_INTERVAL` as it finishes a
+    /// task, after the utilization gauge is updated.
     pub fn on_tick<F>(&mut self, f: F) -> &mut Self
     where
         F: Fn() + Send + Sync + 'static,
@@ -84,7 +130,7 @@ impl Builder {
     where
         F: Fn() + Send + Sync + 'static,
     {
-        self.inner_builder.on_thread_stop(f);
+        self.settings.before_stop = Some(Arc::new(f));
         self
     }
 
@@ -92,7 +138,7 @@ impl Builder {
     where
         F: Fn() + Send + Sync + 'static,
     {
-        self.inner_builder.on_thread_start(f);
+        self.settings.after_start = Some(Arc::new(f));
         self
     }
 
@@ -102,7 +148,7 @@ impl Builder {
     }
 
     pub fn build(&mut self) -> super::FuturePool {
-        let name = if let Some(name) = &self.name_prefix {
+        let name = if let Some(name) = &self.settings.name {
             name.as_str()
         } else {
             "future_pool"
@@ -111,13 +157,27 @@ impl Builder {
             on_tick: self.on_tick.take(),
             metrics_running_task_count: FUTUREPOOL_RUNNING_TASK_VEC.with_label_values(&[name]),
             metrics_handled_task_count: FUTUREPOOL_HANDLED_TASK_VEC.with_label_values(&[name]),
+            metrics_rejected_task_count: FUTUREPOOL_REJECTED_TASK_VEC.with_label_values(&[name]),
+            metrics_queue_wait: FUTUREPOOL_QUEUE_WAIT_HISTOGRAM_VEC.with_label_values(&[name]),
+            metrics_task_duration: FUTUREPOOL_TASK_DURATION_HISTOGRAM_VEC
+                .with_label_values(&[name]),
+            metrics_utilization: FUTUREPOOL_UTILIZATION_GAUGE_VEC.with_label_values(&[name]),
+            workers: AtomicUsize::new(self.workers),
+            queued_tasks: Arc::new(AtomicUsize::new(0)),
+            busy_nanos: AtomicU64::new(0),
+            last_report: Mutex::new(Instant::now_coarse()),
         });
-        let pool = Arc::new(self.inner_builder.build().unwrap());
+        super::spawn_utilization_ticker(name, &env).unwrap();
+        let generation = super::Generation {
+            runtime: self.settings.build(self.workers).unwrap(),
+            tasks: Arc::new(AtomicUsize::new(0)),
+        };
         super::FuturePool {
             name: name.to_string(),
-            pool,
+            runtime: Arc::new(RwLock::new(Arc::new(generation))),
+            settings: Arc::new(self.settings.clone()),
             env,
-            max_tasks: self.max_tasks,
+            max_tasks: Arc::new(AtomicUsize::new(self.max_tasks)),
         }
     }
 }
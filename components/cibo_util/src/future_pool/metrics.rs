@@ -13,4 +13,30 @@ lazy_static! {
         &["name"]
     )
     .unwrap();
+    pub static ref FUTUREPOOL_REJECTED_TASK_VEC: IntCounterVec = register_int_counter_vec!(
+        "futurepool_rejected_task_total",
+        "Total number of tasks rejected because the future_pool was full.",
+        &["name"]
+    )
+    .unwrap();
+    pub static ref FUTUREPOOL_QUEUE_WAIT_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "futurepool_queue_wait_seconds",
+        "Bucketed histogram of future_pool task time from spawn to first poll.",
+        &["name"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref FUTUREPOOL_TASK_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "futurepool_task_duration_seconds",
+        "Bucketed histogram of future_pool task time from first poll to completion.",
+        &["name"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref FUTUREPOOL_UTILIZATION_GAUGE_VEC: GaugeVec = register_gauge_vec!(
+        "futurepool_worker_utilization",
+        "Share of future_pool worker time spent polling tasks since the last tick.",
+        &["name"]
+    )
+    .unwrap();
 }
@@ -3,24 +3,32 @@ mod metrics;
 
 pub use self::builder::{Builder, Config};
 
+use self::builder::RuntimeSettings;
+
 use std::cell::Cell;
 use std::error::Error;
 use std::future::Future;
+use std::io;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
+use std::thread;
 use std::time::Duration;
 
 use actix_web::{HttpResponse, ResponseError};
 use futures::future::FutureExt;
 use pin_project::pin_project;
-use prometheus::{IntCounter, IntGauge};
+use prometheus::{Gauge, Histogram, IntCounter, IntGauge};
 use tokio::runtime::{Handle, Runtime};
 use tokio::task::JoinHandle;
 
-use crate::time::Instant;
+use crate::logger::propagate_request_id;
+use crate::time::{duration_to_nanos, duration_to_sec, Instant};
 
 const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// How often a runtime replaced by `resize` is checked for running tasks.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
 
 thread_local! {
     static THREAD_LAST_TICK_TIME: Cell<Instant> = Cell::new(Instant::now_coarse());
@@ -35,6 +43,12 @@ where
     fut: F,
     #[pin]
     env: Arc<Env>,
+    spawned: Instant,
+    // Set by the first poll.
+    started: Option<Instant>,
+    // Counts the task as queued until the first poll, or until it is
+    // dropped without one.
+    queued: Option<TaskGuard>,
 }
 
 pub fn wrap_future<F>(f: F, env: Arc<Env>) -> FutureWrap<F>
@@ -42,7 +56,14 @@ where
     F: Future + Send + 'static,
     F::Output: Send + 'static,
 {
-    FutureWrap { fut: f, env }
+    let queued = Some(TaskGuard::new(&env.queued_tasks));
+    FutureWrap {
+        fut: f,
+        env,
+        spawned: Instant::now(),
+        started: None,
+        queued,
+    }
 }
 
 impl<F> Future for FutureWrap<F>
@@ -55,9 +76,22 @@ where
         let this = self.project();
 
         let env = this.env.clone();
-        env.metrics_running_task_count.inc();
+        let now = Instant::now();
+        // Counted once, a task may be polled many times.
+        if this.started.is_none() {
+            *this.started = Some(now);
+            this.queued.take();
+            env.metrics_queue_wait
+                .observe(duration_to_sec(now.duration_since(*this.spawned)));
+            env.metrics_running_task_count.inc();
+        }
 
-        let result = futures::ready!(this.fut.poll(ctx));
+        let poll = this.fut.poll(ctx);
+        env.busy_nanos
+            .fetch_add(duration_to_nanos(now.elapsed()), Ordering::Relaxed);
+        let result = futures::ready!(poll);
+        env.metrics_task_duration
+            .observe(this.started.unwrap().elapsed_secs());
         env.metrics_handled_task_count.inc();
         env.metrics_running_task_count.dec();
         try_tick_thread(&env);
@@ -69,14 +103,68 @@ pub struct Env {
     on_tick: Option<Box<dyn Fn() + Send + Sync>>,
     metrics_running_task_count: IntGauge,
     metrics_handled_task_count: IntCounter,
+    metrics_rejected_task_count: IntCounter,
+    metrics_queue_wait: Histogram,
+    metrics_task_duration: Histogram,
+    metrics_utilization: Gauge,
+    workers: AtomicUsize,
+    // Tasks spawned but not polled yet.
+    queued_tasks: Arc<AtomicUsize>,
+    // Nanoseconds spent polling tasks since the last utilization report.
+    busy_nanos: AtomicU64,
+    last_report: Mutex<Instant>,
+}
+
+impl Env {
+    /// Sets the utilization gauge from the polling time since the last
+    /// report, at most once per `TICK_INTERVAL` whichever thread ticks.
+    fn report_utilization(&self) {
+        let mut last_report = self.last_report.lock().unwrap();
+        let now = Instant::now_coarse();
+        let elapsed = now.duration_since(*last_report);
+        if elapsed < TICK_INTERVAL {
+            return;
+        }
+        *last_report = now;
+        let busy = self.busy_nanos.swap(0, Ordering::Relaxed) as f64;
+        let workers = self.workers.load(Ordering::Relaxed);
+        let capacity = duration_to_nanos(elapsed) as f64 * workers as f64;
+        self.metrics_utilization.set((busy / capacity).min(1.0));
+    }
+}
+
+/// A runtime and the tasks spawned on it that are not done yet.
+struct Generation {
+    runtime: Runtime,
+    tasks: Arc<AtomicUsize>,
+}
+
+/// Holds one count of a task counter until dropped, such as the tasks of
+/// a generation not done yet.
+struct TaskGuard(Arc<AtomicUsize>);
+
+impl TaskGuard {
+    fn new(tasks: &Arc<AtomicUsize>) -> TaskGuard {
+        tasks.fetch_add(1, Ordering::SeqCst);
+        TaskGuard(tasks.clone())
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 #[derive(Clone)]
 pub struct FuturePool {
     name: String,
-    pool: Arc<Runtime>,
+    // Takes new tasks. One replaced by `resize` is dropped after its last
+    // task.
+    runtime: Arc<RwLock<Arc<Generation>>>,
+    settings: Arc<RuntimeSettings>,
     env: Arc<Env>,
-    max_tasks: usize,
+    max_tasks: Arc<AtomicUsize>,
 }
 
 impl std::fmt::Debug for FuturePool {
@@ -97,17 +185,86 @@ impl FuturePool {
         self.env.metrics_running_task_count.get() as usize
     }
 
+    /// Gets the number of tasks waiting for a worker to start them.
+    #[inline]
+    pub fn get_queued_task_count(&self) -> usize {
+        self.env.queued_tasks.load(Ordering::SeqCst)
+    }
+
+    /// Gets the name of the pool, which is also the metrics label.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if the pool would reject a new task right now.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.gate_spawn().is_err()
+    }
+
+    /// Gets the number of worker threads taking new tasks.
+    #[inline]
+    pub fn workers(&self) -> usize {
+        self.env.workers.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn max_tasks(&self) -> usize {
+        self.max_tasks.load(Ordering::Relaxed)
+    }
+
+    /// Sets how many tasks may be running at once, checked from the next
+    /// spawn on. Tasks over a lowered limit are not cancelled.
+    pub fn set_max_tasks(&self, max_tasks: usize) {
+        self.max_tasks.store(max_tasks, Ordering::Relaxed);
+    }
+
+    /// Moves new tasks to a runtime of `workers` threads. Tasks already
+    /// spawned run to completion on the old runtime, whose threads exit
+    /// once its last task is done.
+    pub fn resize(&self, workers: usize) -> io::Result<()> {
+        if workers == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "future pool needs a worker",
+            ));
+        }
+        let mut current = self.runtime.write().unwrap();
+        if workers == self.workers() {
+            return Ok(());
+        }
+        let generation = Generation {
+            runtime: self.settings.build(workers)?,
+            tasks: Arc::new(AtomicUsize::new(0)),
+        };
+        let old = std::mem::replace(&mut *current, Arc::new(generation));
+        self.env.workers.store(workers, Ordering::Relaxed);
+        drop(current);
+        thread::Builder::new()
+            .name(format!("{}-drain", self.name))
+            .spawn(move || {
+                // A spawn that took the old runtime before the swap counts
+                // its task before letting go of it.
+                while Arc::strong_count(&old) > 1 || old.tasks.load(Ordering::SeqCst) > 0 {
+                    thread::sleep(DRAIN_INTERVAL);
+                }
+            })?;
+        Ok(())
+    }
+
     fn gate_spawn(&self) -> Result<(), ErrorPoolFull> {
-        if self.max_tasks == std::usize::MAX {
+        let max_tasks = self.max_tasks();
+        if max_tasks == std::usize::MAX {
             return Ok(());
         }
 
         let current_tasks = self.get_running_task_count();
-        if current_tasks >= self.max_tasks {
+        if current_tasks >= max_tasks {
             Err(ErrorPoolFull {
                 name: self.name.clone(),
                 current_tasks,
-                max_tasks: self.max_tasks,
+                max_tasks,
             })
         } else {
             Ok(())
@@ -138,13 +295,39 @@ impl FuturePool {
         T: Future + Send + 'static,
         T::Output: Send + 'static,
     {
-        self.gate_spawn()?;
-        let futures = wrap_future(future_fn, self.env.clone());
+        if let Err(e) = self.gate_spawn() {
+            self.env.metrics_rejected_task_count.inc();
+            return Err(e);
+        }
+        // Keep logging the id of the request that spawned the task.
+        let futures = wrap_future(propagate_request_id(future_fn), self.env.clone());
         // self.wrap_user_future(future_fn)
-        Ok(self.pool.spawn(futures))
+        let generation = self.runtime.read().unwrap().clone();
+        let guard = TaskGuard::new(&generation.tasks);
+        Ok(generation.runtime.spawn(async move {
+            let _guard = guard;
+            futures.await
+        }))
     }
 }
 
+/// Ticks finish with tasks, so a pool that went idle would keep showing
+/// its last utilization. This reports it every `TICK_INTERVAL` as well,
+/// until the pool is dropped.
+fn spawn_utilization_ticker(name: &str, env: &Arc<Env>) -> io::Result<()> {
+    let env = Arc::downgrade(env);
+    thread::Builder::new()
+        .name(format!("{}-tick", name))
+        .spawn(move || loop {
+            thread::sleep(TICK_INTERVAL);
+            match env.upgrade() {
+                Some(env) => env.report_utilization(),
+                None => return,
+            }
+        })?;
+    Ok(())
+}
+
 /// Tries to trigger a tick in current thread.
 ///
 /// This function is effective only when it is called in thread pool worker
@@ -158,6 +341,7 @@ fn try_tick_thread(env: &Env) {
             return;
         }
         tls_last_tick.set(now);
+        env.report_utilization();
         if let Some(f) = &env.on_tick {
             f();
         }
@@ -173,7 +357,11 @@ pub struct ErrorPoolFull {
 
 impl std::fmt::Display for ErrorPoolFull {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(fmt, "{} {}", self.name, self)
+        write!(
+            fmt,
+            "{} future pool is full ({} of {} tasks)",
+            self.name, self.current_tasks, self.max_tasks
+        )
     }
 }
 
@@ -404,6 +592,71 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_rejected_task_count() {
+        let pool = Builder::new()
+            .name_prefix("future_pool_test_rejected") // The name is important
+            .pool_size(1)
+            .max_tasks(1)
+            .build();
+        pool.spawn(async { thread::sleep(Duration::from_millis(300)) })
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(pool.is_full());
+        // Only refused spawns count, not checks.
+        assert_eq!(pool.env.metrics_rejected_task_count.get(), 0);
+        let e = pool.spawn(async {}).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "future_pool_test_rejected future pool is full (1 of 1 tasks)"
+        );
+        assert_eq!(pool.env.metrics_rejected_task_count.get(), 1);
+    }
+
+    #[test]
+    fn test_idle_utilization() {
+        let pool = Builder::new()
+            .name_prefix("future_pool_test_idle_utilization") // The name is important
+            .pool_size(1)
+            .build();
+        // As left by a busy second.
+        pool.env.metrics_utilization.set(1.0);
+        thread::sleep(TICK_INTERVAL * 3);
+        assert_eq!(pool.env.metrics_utilization.get(), 0.0);
+    }
+
+    #[test]
+    fn test_resize() {
+        let pool = Builder::new()
+            .name_prefix("future_pool_test_resize") // The name is important
+            .pool_size(1)
+            .max_tasks(1)
+            .build();
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(async move {
+            thread::sleep(Duration::from_millis(300));
+            tx.send(1).unwrap();
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(pool.is_full());
+
+        // Limits apply at once.
+        pool.set_max_tasks(2);
+        assert!(!pool.is_full());
+        assert!(pool.resize(0).is_err());
+        pool.resize(2).unwrap();
+        assert_eq!(pool.workers(), 2);
+
+        // The task on the old runtime is not dropped, and new tasks do not
+        // wait for it.
+        let (tx2, rx2) = mpsc::channel();
+        pool.spawn(async move { tx2.send(2).unwrap() }).unwrap();
+        assert_eq!(rx2.recv_timeout(Duration::from_millis(100)).unwrap(), 2);
+        assert_eq!(rx.recv().unwrap(), 1);
+    }
+
     #[test]
     fn test_full() {
         let (tx, rx) = mpsc::channel();
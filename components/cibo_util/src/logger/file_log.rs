@@ -1,9 +1,14 @@
+use std::ffi::OsString;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Error, ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime};
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveDateTime};
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
 
 use crate::config::{ReadableDuration, ReadableSize};
 
@@ -22,6 +27,173 @@ fn open_log_file(path: impl AsRef<Path>) -> io::Result<File> {
     OpenOptions::new().append(true).create(true).open(path)
 }
 
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut new_path = OsString::from(path);
+    new_path.push(suffix);
+    PathBuf::from(new_path)
+}
+
+const ROTATED_TIME_FORMAT: &str = "%Y-%m-%d-%H:%M:%S";
+
+/// Names a rotated log file "{original name}.{%Y-%m-%d-%H:%M:%S}.{seq}", with
+/// the lowest sequence number not taken by an earlier rotation in the same
+/// second, compressed or not.
+pub fn rename_by_sequence(path: &Path) -> io::Result<PathBuf> {
+    let time = Local::now().format(ROTATED_TIME_FORMAT);
+    let base = with_suffix(path, &format!(".{}", time));
+    for seq in 1u32.. {
+        let candidate = with_suffix(&base, &format!(".{}", seq));
+        let taken = [Compression::None, Compression::Gzip, Compression::Zstd]
+            .iter()
+            .any(|c| with_suffix(&candidate, c.extension()).exists());
+        if !taken {
+            return Ok(candidate);
+        }
+    }
+    Err(Error::new(
+        ErrorKind::Other,
+        "Run out of sequence numbers for the rotated log file",
+    ))
+}
+
+/// Whether `name` is one `rename_by_sequence` gave a rotated `log_name`,
+/// compressed or not.
+fn is_rotated_by_sequence(name: &str, log_name: &str) -> bool {
+    let rest = match name
+        .strip_prefix(log_name)
+        .and_then(|rest| rest.strip_prefix('.'))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let rest = [Compression::Gzip, Compression::Zstd]
+        .iter()
+        .find_map(|c| rest.strip_suffix(c.extension()))
+        .unwrap_or(rest);
+    match rest.rsplit_once('.') {
+        Some((time, seq)) => {
+            !seq.is_empty()
+                && seq.bytes().all(|b| b.is_ascii_digit())
+                && NaiveDateTime::parse_from_str(time, ROTATED_TIME_FORMAT).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// How rotated log files are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The suffix appended to a rotated file once compressed.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    fn compress(self, path: &Path) -> io::Result<()> {
+        if self == Compression::None {
+            return Ok(());
+        }
+        let mut source = File::open(path)?;
+        let target = File::create(with_suffix(path, self.extension()))?;
+        match self {
+            Compression::None => unreachable!(),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(target, flate2::Compression::default());
+                io::copy(&mut source, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Compression::Zstd => zstd::stream::copy_encode(source, target, 0)?,
+        }
+        fs::remove_file(path)
+    }
+}
+
+/// What happens to log files after they are rotated. Zero `max_files` or
+/// `max_age` keeps files forever.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retention {
+    pub compression: Compression,
+    pub max_files: usize,
+    pub max_age: ReadableDuration,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            compression: Compression::None,
+            max_files: 0,
+            max_age: ReadableDuration(Duration::from_secs(0)),
+        }
+    }
+}
+
+impl Retention {
+    fn is_enabled(&self) -> bool {
+        self.compression != Compression::None || self.max_files != 0 || !self.max_age.is_zero()
+    }
+
+    /// Compresses the file just rotated, then removes the rotated files of
+    /// `path` that are beyond `max_files` or older than `max_age`. Only names
+    /// given by `rename_by_sequence` count as rotated files. A file that
+    /// fails is logged and left for the next run.
+    fn apply(&self, path: &Path, rotated: &Path) -> io::Result<()> {
+        if let Err(e) = self.compression.compress(rotated) {
+            warn!("failed to compress rotated log"; "file" => %rotated.display(), "err" => %e);
+        }
+        if self.max_files == 0 && self.max_age.is_zero() {
+            return Ok(());
+        }
+
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let log_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let is_rotated = entry
+                .file_name()
+                .to_str()
+                .map_or(false, |name| is_rotated_by_sequence(name, log_name));
+            if is_rotated && entry.file_type()?.is_file() {
+                files.push((entry.metadata()?.modified()?, entry.path()));
+            }
+        }
+        // Newest first.
+        files.sort_by(|a, b| b.cmp(a));
+
+        let now = SystemTime::now();
+        for (i, (modified, file)) in files.into_iter().enumerate() {
+            let too_many = self.max_files != 0 && i >= self.max_files;
+            let too_old = !self.max_age.is_zero()
+                && now
+                    .duration_since(modified)
+                    .map_or(false, |age| age > self.max_age.0);
+            if too_many || too_old {
+                if let Err(e) = fs::remove_file(&file) {
+                    warn!("failed to remove rotated log"; "file" => %file.display(), "err" => %e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A trait that describes a file rotation operation.
 pub trait Rotator: Send {
     /// Check if the option is enabled in configuration.
@@ -44,13 +216,18 @@ pub trait Rotator: Send {
 /// This `FileLogger` will iterate over a series of `Rotators`,
 /// once the context trigger the `Rotator`, it will execute a rotation.
 ///
-/// After rotating, the original log file would be renamed to "{original name}.{%Y-%m-%d-%H:%M:%S}".
-/// Note: log file will *not* be compressed or otherwise modified.
+/// After rotating, the original log file is renamed by `rename`, e.g. to
+/// "{original name}.{%Y-%m-%d-%H:%M:%S}.{seq}" by `rename_by_sequence`. The
+/// `Retention` then runs on a background thread, so compressing a large file
+/// does not hold up logging.
 pub struct RotatingFileLogger {
     path: PathBuf,
     file: File,
     rename: Box<dyn Send + Fn(&Path) -> io::Result<PathBuf>>,
     rotators: Vec<Box<dyn Rotator>>,
+    // Rotated files for the retention thread, `None` without retention.
+    retention_tx: Option<Sender<PathBuf>>,
+    retention_handle: Option<JoinHandle<()>>,
 }
 
 /// Builder for `RotatingFileLogger`.
@@ -58,6 +235,7 @@ pub struct RotatingFileLoggerBuilder {
     rotators: Vec<Box<dyn Rotator>>,
     path: PathBuf,
     rename: Box<dyn Send + Fn(&Path) -> io::Result<PathBuf>>,
+    retention: Retention,
 }
 
 impl RotatingFileLoggerBuilder {
@@ -69,9 +247,15 @@ impl RotatingFileLoggerBuilder {
             path: path.as_ref().to_path_buf(),
             rotators: vec![],
             rename: Box::new(rename),
+            retention: Retention::default(),
         }
     }
 
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
     pub fn add_rotator<R: 'static + Rotator>(mut self, rotator: R) -> Self {
         if rotator.is_enabled() {
             self.rotators.push(Box::new(rotator));
@@ -86,15 +270,48 @@ impl RotatingFileLoggerBuilder {
             rotator.prepare(&file)?;
         }
 
+        let (mut retention_tx, mut retention_handle) = (None, None);
+        if self.retention.is_enabled() {
+            let (tx, rx) = mpsc::channel();
+            let retention = self.retention;
+            let path = self.path.clone();
+            let handle = thread::Builder::new()
+                .name(thd_name!("log-retention"))
+                .spawn(move || run_retention(retention, path, rx))?;
+            retention_tx = Some(tx);
+            retention_handle = Some(handle);
+        }
+
         Ok(RotatingFileLogger {
             rotators: self.rotators,
             path: self.path,
             rename: self.rename,
             file,
+            retention_tx,
+            retention_handle,
         })
     }
 }
 
+/// Applies `retention` to each rotated file in turn, so two runs never
+/// compress the same file. Returns once the logger is dropped.
+fn run_retention(retention: Retention, path: PathBuf, rx: Receiver<PathBuf>) {
+    for rotated in rx {
+        if let Err(e) = retention.apply(&path, &rotated) {
+            warn!("failed to clean up rotated logs"; "file" => %rotated.display(), "err" => %e);
+        }
+    }
+}
+
+impl RotatingFileLogger {
+    fn start_retention(&self, rotated: PathBuf) {
+        if let Some(tx) = &self.retention_tx {
+            // Only fails once the thread panicked, the file is kept then.
+            let _ = tx.send(rotated);
+        }
+    }
+}
+
 impl Write for RotatingFileLogger {
     fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
         // Updates all roators' states.
@@ -112,6 +329,7 @@ impl Write for RotatingFileLogger {
                 let new_path = (self.rename)(&self.path)?;
                 fs::rename(&self.path, &new_path)?;
                 self.file = open_log_file(&self.path)?;
+                self.start_retention(new_path);
 
                 // Updates all roators' states.
                 for rotator in self.rotators.iter_mut() {
@@ -128,6 +346,11 @@ impl Write for RotatingFileLogger {
 impl Drop for RotatingFileLogger {
     fn drop(&mut self) {
         let _ = self.file.flush();
+        // Lets the thread finish the files sent so far.
+        self.retention_tx.take();
+        if let Some(handle) = self.retention_handle.take() {
+            let _ = handle.join();
+        }
     }
 }
 
@@ -462,4 +685,71 @@ mod tests {
         // dropping the logger still should not panic.
         drop(logger);
     }
+
+    #[test]
+    fn test_rename_by_sequence() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("test_rename_by_sequence.log");
+
+        let first = rename_by_sequence(&path).unwrap();
+        File::create(&first).unwrap();
+        let second = rename_by_sequence(&path).unwrap();
+        assert_ne!(first, second);
+        // A compressed file takes its sequence number too.
+        File::create(with_suffix(&second, ".gz")).unwrap();
+        let third = rename_by_sequence(&path).unwrap();
+        assert_ne!(second, third);
+        assert_ne!(first, third);
+    }
+
+    #[test]
+    fn test_retention() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("test_retention.log");
+
+        let retention = Retention {
+            compression: Compression::Gzip,
+            max_files: 2,
+            ..Retention::default()
+        };
+        // Look like rotated files, but are not.
+        let others = [
+            "test_retention.log.bak",
+            "test_retention.log.2020-01-01-00:00:00.1.old",
+            "test_retention.log.2020-01-01-00:00:00.gz",
+            "test_retention.log.x.2020-01-01-00:00:00.1",
+        ];
+        for name in &others {
+            File::create(tmp_dir.path().join(name)).unwrap();
+        }
+        let mut logger = RotatingFileLoggerBuilder::new(path.clone(), rename_by_sequence)
+            .add_rotator(RotateBySize::new(ReadableSize::kb(1)))
+            .retention(retention)
+            .build()
+            .unwrap();
+        for _ in 0..3 {
+            logger.write_all(&[0xff; 1025]).unwrap();
+            logger.flush().unwrap();
+        }
+        // Waits for the last retention run.
+        drop(logger);
+
+        let mut rotated: Vec<_> = fs::read_dir(tmp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "test_retention.log" && !others.contains(&name.as_str()))
+            .collect();
+        rotated.sort();
+        assert_eq!(rotated.len(), 2, "{:?}", rotated);
+        for name in rotated {
+            assert!(name.ends_with(".gz"), "{}", name);
+            let file = File::open(tmp_dir.path().join(name)).unwrap();
+            let mut data = vec![];
+            io::copy(&mut flate2::read::GzDecoder::new(file), &mut data).unwrap();
+            assert_eq!(data, vec![0xff; 1025]);
+        }
+        for name in &others {
+            assert!(file_exists(tmp_dir.path().join(name)), "{}", name);
+        }
+    }
 }
@@ -0,0 +1,132 @@
+//! The global log level, which can be changed while running.
+//!
+//! A change either replaces the level for good, or holds for a ttl after
+//! which the level falls back to the last permanent one.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use slog::{Drain, Level, OwnedKVList, Record};
+
+use super::convert_slog_level_to_log_level;
+
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(4); // Level::Info
+
+struct LevelState {
+    // The level restored when a temporary change expires.
+    base: Level,
+    // Bumped on every change, so a revert only applies to its own change.
+    generation: u64,
+    expires_at: Option<Instant>,
+}
+
+lazy_static! {
+    static ref LEVEL_STATE: Mutex<LevelState> = Mutex::new(LevelState {
+        base: Level::Info,
+        generation: 0,
+        expires_at: None,
+    });
+}
+
+/// Returns the current global log level.
+pub fn get_log_level() -> Level {
+    Level::from_usize(LOG_LEVEL.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+}
+
+/// Returns the level a temporary change falls back to, and when it does.
+pub fn get_log_level_revert() -> Option<(Level, Instant)> {
+    let state = LEVEL_STATE.lock().unwrap();
+    state.expires_at.map(|at| (state.base, at))
+}
+
+fn apply(level: Level) {
+    LOG_LEVEL.store(level.as_usize(), Ordering::Relaxed);
+    // Records from the `log` crate are dropped by its own max level before
+    // they reach the slog drain.
+    log::set_max_level(convert_slog_level_to_log_level(level).to_level_filter());
+}
+
+/// Sets the global log level of both the slog drain and the std `log` bridge.
+pub fn set_log_level(level: Level) {
+    let mut state = LEVEL_STATE.lock().unwrap();
+    state.base = level;
+    state.generation += 1;
+    state.expires_at = None;
+    apply(level);
+}
+
+/// Sets the global log level for `ttl`, then restores the last level set by
+/// `set_log_level`.
+pub fn set_log_level_with_ttl(level: Level, ttl: Duration) {
+    let generation = {
+        let mut state = LEVEL_STATE.lock().unwrap();
+        state.generation += 1;
+        state.expires_at = Some(Instant::now() + ttl);
+        apply(level);
+        state.generation
+    };
+    let res = thread::Builder::new()
+        .name(thd_name!("log-level"))
+        .spawn(move || {
+            thread::sleep(ttl);
+            let mut state = LEVEL_STATE.lock().unwrap();
+            if state.generation == generation {
+                state.expires_at = None;
+                apply(state.base);
+                info!("log level reverted"; "level" => super::get_string_by_level(state.base));
+            }
+        });
+    if let Err(e) = res {
+        error!("failed to spawn log level revert thread"; "err" => %e);
+    }
+}
+
+/// Drops records below the global log level.
+pub struct GlobalLevelFilter<D>(pub D);
+
+impl<D: Drain> Drain for GlobalLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(get_log_level()) {
+            self.0.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[inline]
+    fn is_enabled(&self, level: Level) -> bool {
+        level.is_at_least(get_log_level()) && self.0.is_enabled(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_level_ttl() {
+        set_log_level(Level::Info);
+        assert_eq!(get_log_level(), Level::Info);
+        assert_eq!(log::max_level(), log::LevelFilter::Info);
+
+        set_log_level_with_ttl(Level::Debug, Duration::from_millis(100));
+        assert_eq!(get_log_level(), Level::Debug);
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+        assert_eq!(get_log_level_revert().unwrap().0, Level::Info);
+
+        // A newer change cancels the pending revert of an older one.
+        set_log_level_with_ttl(Level::Trace, Duration::from_millis(400));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(get_log_level(), Level::Trace);
+
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(get_log_level(), Level::Info);
+        assert_eq!(log::max_level(), log::LevelFilter::Info);
+        assert!(get_log_level_revert().is_none());
+    }
+}
@@ -1,5 +1,7 @@
 mod file_log;
 mod formatter;
+mod level;
+mod request_id;
 
 use std::env;
 use std::fmt;
@@ -12,9 +14,19 @@ use slog::{self, Drain, Key, OwnedKVList, Record, KV};
 use slog_async::{Async, OverflowStrategy};
 use slog_term::{Decorator, PlainDecorator, RecordDecorator, TermDecorator};
 
-use self::file_log::{RotateBySize, RotateByTime, RotatingFileLogger, RotatingFileLoggerBuilder};
+pub use self::file_log::{
+    rename_by_sequence, Compression, Retention, RotateBySize, RotateByTime, Rotator,
+    RotatingFileLogger, RotatingFileLoggerBuilder,
+};
 use crate::config::{ReadableDuration, ReadableSize};
 
+pub use self::level::{
+    get_log_level, get_log_level_revert, set_log_level, set_log_level_with_ttl, GlobalLevelFilter,
+};
+pub use self::request_id::{
+    current_request_id, generate_request_id, propagate_request_id, with_request_id,
+    RequestIdDrain, WithRequestId, REQUEST_ID_KEY,
+};
 pub use slog::Level;
 
 // Default is 128.
@@ -65,19 +77,20 @@ where
             .chan_size(SLOG_CHANNEL_SIZE)
             .overflow_strategy(SLOG_CHANNEL_OVERFLOW_STRATEGY)
             .thread_name(thd_name!("slogger"))
-            .build()
-            .filter_level(level)
-            .fuse();
-        slog::Logger::root(drain, slog_o!())
+            .build();
+        let drain = GlobalLevelFilter(drain).fuse();
+        slog::Logger::root(RequestIdDrain(drain), slog_o!())
     } else {
-        let drain = LogAndFuse(Mutex::new(filtered).filter_level(level));
-        slog::Logger::root(drain, slog_o!())
+        let drain = LogAndFuse(GlobalLevelFilter(Mutex::new(filtered)));
+        slog::Logger::root(RequestIdDrain(drain), slog_o!())
     };
 
     slog_global::set_global(logger);
     if init_stdlog {
         slog_global::redirect_std_log(Some(level))?;
     }
+    // After the redirect, which sets the max level of `log` too.
+    set_log_level(level);
 
     Ok(())
 }
@@ -87,11 +100,13 @@ where
 pub type RotatingFileDecorator = PlainDecorator<BufWriter<RotatingFileLogger>>;
 
 /// Constructs a new file drainer which outputs log to a file at the specified
-/// path. The file drainer rotates for the specified timespan.
+/// path. The file drainer rotates for the specified timespan and size, and
+/// applies `retention` to the rotated files.
 pub fn file_drainer<N>(
     path: impl AsRef<Path>,
     rotation_timespan: ReadableDuration,
     rotation_size: ReadableSize,
+    retention: Retention,
     rename: N,
 ) -> io::Result<TikvFormat<RotatingFileDecorator>>
 where
@@ -101,6 +116,7 @@ where
         RotatingFileLoggerBuilder::new(path, rename)
             .add_rotator(RotateByTime::new(rotation_timespan))
             .add_rotator(RotateBySize::new(rotation_size))
+            .retention(retention)
             .build()?,
     );
     let decorator = PlainDecorator::new(logger);
@@ -0,0 +1,122 @@
+//! Per-request id attached to every log record emitted while the request is
+//! being handled.
+//!
+//! The id lives in a thread local that is set for the duration of each poll
+//! of a future wrapped by `with_request_id`, so it follows the request across
+//! the http worker and the future pools it spawns into.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use pin_project::pin_project;
+use rand::{thread_rng, Rng};
+use slog::{BorrowedKV, Drain, OwnedKVList, Record, RecordStatic, SingleKV};
+
+pub const REQUEST_ID_KEY: &str = "request_id";
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<Arc<str>>> = RefCell::new(None);
+}
+
+/// Returns the id of the request being handled by the current thread.
+pub fn current_request_id() -> Option<Arc<str>> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Generates a random 128 bit id in hex.
+pub fn generate_request_id() -> String {
+    let id: u128 = thread_rng().gen();
+    format!("{:032x}", id)
+}
+
+/// Runs `fut` with `id` as the current request id.
+pub fn with_request_id<F: Future>(id: Option<Arc<str>>, fut: F) -> WithRequestId<F> {
+    WithRequestId { fut, id }
+}
+
+/// Runs `fut` with the request id of the caller, used when handing work over
+/// to another thread.
+pub fn propagate_request_id<F: Future>(fut: F) -> WithRequestId<F> {
+    with_request_id(current_request_id(), fut)
+}
+
+#[pin_project]
+pub struct WithRequestId<F> {
+    #[pin]
+    fut: F,
+    id: Option<Arc<str>>,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if this.id.is_none() {
+            return this.fut.poll(cx);
+        }
+        let prev = REQUEST_ID.with(|id| id.replace(this.id.clone()));
+        let res = this.fut.poll(cx);
+        REQUEST_ID.with(|id| *id.borrow_mut() = prev);
+        res
+    }
+}
+
+/// Adds the current request id to each record. It must wrap the async drain,
+/// since the id is only known on the thread that logs.
+pub struct RequestIdDrain<D>(pub D);
+
+impl<D: Drain> Drain for RequestIdDrain<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let id = match current_request_id() {
+            Some(id) => id,
+            None => return self.0.log(record, values),
+        };
+        let rs = RecordStatic {
+            location: record.location(),
+            level: record.level(),
+            tag: record.tag(),
+        };
+        let msg = *record.msg();
+        let kv = (record.kv(), SingleKV(REQUEST_ID_KEY, &*id));
+        self.0.log(&Record::new(&rs, &msg, BorrowedKV(&kv)), values)
+    }
+}
+
+impl<D> fmt::Debug for RequestIdDrain<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestIdDrain")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_propagate_request_id() {
+        assert!(current_request_id().is_none());
+        let id: Arc<str> = Arc::from("abc");
+        let seen = block_on(with_request_id(Some(id), async {
+            propagate_request_id(async { current_request_id() }).await
+        }));
+        assert_eq!(seen.as_deref(), Some("abc"));
+        assert!(current_request_id().is_none());
+    }
+
+    #[test]
+    fn test_generate_request_id() {
+        let id = generate_request_id();
+        assert_eq!(id.len(), 32);
+        assert_ne!(id, generate_request_id());
+    }
+}
@@ -3,9 +3,15 @@
 use prometheus::core::{Collector, Desc};
 use prometheus::proto::MetricFamily;
 use prometheus::{IntGaugeVec, Opts, Result};
+use std::time::Duration;
 
-pub fn monitor_allocator_stats<S: Into<String>>(namespace: S) -> Result<()> {
-    prometheus::register(Box::new(AllocStatsCollector::new(namespace)?))
+use super::SampledCollector;
+
+/// Monitors the stats of whichever allocator is compiled in, reading them at
+/// most once per `interval`.
+pub fn monitor_allocator_stats<S: Into<String>>(namespace: S, interval: Duration) -> Result<()> {
+    let collector = AllocStatsCollector::new(namespace)?;
+    prometheus::register(Box::new(SampledCollector::new(collector, interval)))
 }
 
 struct AllocStatsCollector {
@@ -23,12 +23,14 @@ mod process_dummy;
 pub use self::process_dummy::monitor_process;
 
 pub use self::allocator_metrics::monitor_allocator_stats;
+pub use self::sampled::SampledCollector;
 
 pub mod allocator_metrics;
 
 pub use self::metrics_reader::HistogramReader;
 
 mod metrics_reader;
+mod sampled;
 
 pub fn dump() -> String {
     let mut buffer = vec![];
@@ -4,15 +4,19 @@
 //! to avoid memory fragmentation issues when open fd is large.
 
 use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
 
 use prometheus::core::{Collector, Desc};
 use prometheus::{proto, Counter, Gauge, Opts};
 use std::sync::Mutex;
 
-/// Monitors current process.
-pub fn monitor_process() -> Result<()> {
+use super::SampledCollector;
+
+/// Monitors current process, reading procfs at most once per `interval`. An
+/// empty `namespace` keeps the standard `process_*` names.
+pub fn monitor_process<S: Into<String>>(namespace: S, interval: Duration) -> Result<()> {
     let pid = unsafe { libc::getpid() };
-    let tc = ProcessCollector::new(pid);
+    let tc = SampledCollector::new(ProcessCollector::new(pid, namespace), interval);
     prometheus::register(Box::new(tc)).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
 }
 
@@ -27,36 +31,49 @@ pub struct ProcessCollector {
 }
 
 impl ProcessCollector {
-    pub fn new(pid: libc::pid_t) -> Self {
+    pub fn new<S: Into<String>>(pid: libc::pid_t, namespace: S) -> Self {
         let mut descs = Vec::new();
+        let ns = namespace.into();
 
-        let cpu_total = Counter::with_opts(Opts::new(
-            "process_cpu_seconds_total",
-            "Total user and system CPU time spent in \
+        let cpu_total = Counter::with_opts(
+            Opts::new(
+                "process_cpu_seconds_total",
+                "Total user and system CPU time spent in \
                  seconds.",
-        ))
+            )
+            .namespace(ns.clone()),
+        )
         .unwrap();
         descs.extend(cpu_total.desc().into_iter().cloned());
 
-        let vsize = Gauge::with_opts(Opts::new(
-            "process_virtual_memory_bytes",
-            "Virtual memory size in bytes.",
-        ))
+        let vsize = Gauge::with_opts(
+            Opts::new(
+                "process_virtual_memory_bytes",
+                "Virtual memory size in bytes.",
+            )
+            .namespace(ns.clone()),
+        )
         .unwrap();
         descs.extend(vsize.desc().into_iter().cloned());
 
-        let rss = Gauge::with_opts(Opts::new(
-            "process_resident_memory_bytes",
-            "Resident memory size in bytes.",
-        ))
+        let rss = Gauge::with_opts(
+            Opts::new(
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes.",
+            )
+            .namespace(ns.clone()),
+        )
         .unwrap();
         descs.extend(rss.desc().into_iter().cloned());
 
-        let start_time = Gauge::with_opts(Opts::new(
-            "process_start_time_seconds",
-            "Start time of the process since unix epoch \
+        let start_time = Gauge::with_opts(
+            Opts::new(
+                "process_start_time_seconds",
+                "Start time of the process since unix epoch \
                  in seconds.",
-        ))
+            )
+            .namespace(ns),
+        )
         .unwrap();
         descs.extend(start_time.desc().into_iter().cloned());
 
@@ -0,0 +1,64 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+
+/// Reads the inner collector at most once per `interval`, scrapes in between
+/// get the last result. Keeps collectors that walk procfs or refresh
+/// allocator stats cheap when scraped often.
+pub struct SampledCollector<C> {
+    inner: C,
+    interval: Duration,
+    last: Mutex<Option<(Instant, Vec<MetricFamily>)>>,
+}
+
+impl<C: Collector> SampledCollector<C> {
+    pub fn new(inner: C, interval: Duration) -> SampledCollector<C> {
+        SampledCollector {
+            inner,
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+}
+
+impl<C: Collector> Collector for SampledCollector<C> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut last = self.last.lock().unwrap();
+        if let Some((at, mfs)) = &*last {
+            if at.elapsed() < self.interval {
+                return mfs.clone();
+            }
+        }
+        let mfs = self.inner.collect();
+        *last = Some((Instant::now(), mfs.clone()));
+        mfs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prometheus::{IntCounter, Opts};
+
+    #[test]
+    fn test_sampled_collector() {
+        let counter = IntCounter::with_opts(Opts::new("sampled", "test")).unwrap();
+        let sampled = SampledCollector::new(counter.clone(), Duration::from_secs(3600));
+        let value = |mfs: Vec<MetricFamily>| mfs[0].get_metric()[0].get_counter().get_value();
+        assert_eq!(value(sampled.collect()), 0.0);
+        counter.inc();
+        assert_eq!(value(sampled.collect()), 0.0);
+
+        let sampled = SampledCollector::new(counter, Duration::from_secs(0));
+        assert_eq!(value(sampled.collect()), 1.0);
+    }
+}
@@ -8,10 +8,11 @@ other than Linux. PRs are welcome!
 */
 
 use std::io;
+use std::time::Duration;
 
 use crate::collections::HashMap;
 
-pub fn monitor_threads<S: Into<String>>(_: S) -> io::Result<()> {
+pub fn monitor_threads<S: Into<String>>(_: S, _: Duration) -> io::Result<()> {
     Ok(())
 }
 
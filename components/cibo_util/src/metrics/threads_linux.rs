@@ -12,11 +12,18 @@ use prometheus::{self, proto, CounterVec, IntCounterVec, IntGaugeVec, Opts};
 
 use procinfo::pid;
 
-/// Monitors threads of the current process.
-pub fn monitor_threads<S: Into<String>>(namespace: S) -> Result<()> {
+use super::SampledCollector;
+
+/// Monitors threads of the current process, reading procfs at most once per
+/// `interval`. Besides the per thread totals it exports CPU usage and IO
+/// rates summed by thread name, which is one series per pool.
+pub fn monitor_threads<S: Into<String>>(namespace: S, interval: Duration) -> Result<()> {
     let pid = unsafe { libc::getpid() };
-    let tc = ThreadsCollector::new(pid, namespace);
-    prometheus::register(Box::new(tc)).map_err(|e| to_io_err(format!("{:?}", e)))
+    let ns = namespace.into();
+    let tc = SampledCollector::new(ThreadsCollector::new(pid, ns.clone()), interval);
+    prometheus::register(Box::new(tc)).map_err(|e| to_io_err(format!("{:?}", e)))?;
+    let rc = SampledCollector::new(ThreadRatesCollector::new(ns), interval);
+    prometheus::register(Box::new(rc)).map_err(|e| to_io_err(format!("{:?}", e)))
 }
 
 struct Metrics {
@@ -440,6 +447,80 @@ impl ThreadInfoStatistics {
     }
 }
 
+/// Exports the rates of `ThreadInfoStatistics`, the rates cover the time
+/// between two collects.
+struct ThreadRatesCollector {
+    descs: Vec<Desc>,
+    stats: Mutex<ThreadInfoStatistics>,
+    cpu_usages: IntGaugeVec,
+    io_rates: IntGaugeVec,
+}
+
+impl ThreadRatesCollector {
+    fn new<S: Into<String>>(namespace: S) -> ThreadRatesCollector {
+        let mut descs: Vec<Desc> = vec![];
+        let ns = namespace.into();
+        let cpu_usages = IntGaugeVec::new(
+            Opts::new(
+                "thread_cpu_usage_percent",
+                "CPU usage of threads with the same name, in percent of one core.",
+            )
+            .namespace(ns.clone()),
+            &["name"],
+        )
+        .unwrap();
+        descs.extend(cpu_usages.desc().into_iter().cloned());
+        let io_rates = IntGaugeVec::new(
+            Opts::new(
+                "thread_io_bytes_per_second",
+                "IO rates of threads with the same name.",
+            )
+            .namespace(ns),
+            &["name", "io"],
+        )
+        .unwrap();
+        descs.extend(io_rates.desc().into_iter().cloned());
+        ThreadRatesCollector {
+            descs,
+            stats: Mutex::new(ThreadInfoStatistics::new()),
+            cpu_usages,
+            io_rates,
+        }
+    }
+}
+
+impl Collector for ThreadRatesCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<proto::MetricFamily> {
+        let mut stats = self.stats.lock().unwrap();
+        stats.record();
+        // Drop the series of threads that are gone.
+        self.cpu_usages.reset();
+        self.io_rates.reset();
+        for (name, usage) in stats.get_cpu_usages() {
+            self.cpu_usages
+                .with_label_values(&[name.as_str()])
+                .set(usage as i64);
+        }
+        for (name, rate) in stats.get_read_io_rates() {
+            self.io_rates
+                .with_label_values(&[name.as_str(), "read"])
+                .set(rate as i64);
+        }
+        for (name, rate) in stats.get_write_io_rates() {
+            self.io_rates
+                .with_label_values(&[name.as_str(), "write"])
+                .set(rate as i64);
+        }
+        let mut mfs = self.cpu_usages.collect();
+        mfs.extend(self.io_rates.collect());
+        mfs
+    }
+}
+
 const TID_MIN_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
 const TID_MAX_UPDATE_INTERVAL: Duration = Duration::from_secs(10 * 60);
 
@@ -726,6 +807,6 @@ mod tests {
         let tc = ThreadsCollector::new(pid, "smoke");
         tc.collect();
         tc.desc();
-        monitor_threads("smoke").unwrap();
+        monitor_threads("smoke", Duration::from_secs(1)).unwrap();
     }
 }
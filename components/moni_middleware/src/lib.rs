@@ -4,13 +4,14 @@ extern crate lazy_static;
 extern crate slog_global;
 
 use futures_core::ready;
-use std::borrow::Cow;
+use std::cell::Cell;
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::{
     env,
@@ -19,39 +20,125 @@ use std::{
 
 use actix_service::{Service, Transform};
 use actix_utils::future::{ready, Ready};
-use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, USER_AGENT};
 use actix_web::http::Method;
-use actix_web::HttpResponse;
 use actix_web::{
     body::{BodySize, MessageBody},
     error::Error,
 };
+use actix_web::{HttpMessage, HttpResponse};
 use awc::http::header::HeaderName;
 use awc::ResponseBody;
 use bytes::Bytes;
+use cibo_util::logger::{generate_request_id, with_request_id, WithRequestId};
+use futures::StreamExt;
 use log::{debug, warn};
+use namespace::Namespace;
 use pin_project_lite::pin_project;
-use regex::{Regex, RegexSet};
-use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use regex::Regex;
+pub use regex::RegexSet;
+use time::format_description::{self, well_known::Rfc3339, FormatItem};
+use time::OffsetDateTime;
 
 use crate::metrics::*;
+use crate::trace::{RequestSpan, Traced};
+pub use crate::writer::LogWriter;
 
 mod metrics;
+mod trace;
+mod writer;
 
 pub struct Moni(Rc<Inner>);
 
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Reuses the client's request id if it is sane, otherwise makes a new one.
+fn extract_request_id(req: &ServiceRequest) -> Arc<str> {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+        .map(Arc::from)
+        .unwrap_or_else(|| Arc::from(generate_request_id()))
+}
+
+const COMMON_LOG_FORMAT: &str = r#"%a - - [%t] "%r" %s %b"#;
+const COMBINED_LOG_FORMAT: &str = r#"%a - - [%t] "%r" %s %b "%{Referer}i" "%{User-Agent}i""#;
+
+lazy_static! {
+    // Of the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`.
+    static ref CLF_TIME: Vec<FormatItem<'static>> = format_description::parse(
+        "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] \
+         [offset_hour sign:mandatory][offset_minute]"
+    )
+    .unwrap();
+}
+
+/// How an access log line is rendered.
+#[derive(Debug, Clone)]
+pub enum AccessLogFormat {
+    Json,
+    Template(Format),
+}
+
+impl AccessLogFormat {
+    /// Parses `json`, `common`, `combined`, or else a template such as `%a %r %s %b %T`.
+    pub fn parse(s: &str) -> Result<AccessLogFormat, String> {
+        let template = match s {
+            "json" => return Ok(AccessLogFormat::Json),
+            "common" => COMMON_LOG_FORMAT,
+            "combined" => COMBINED_LOG_FORMAT,
+            template => template,
+        };
+        Format::new(template).map(AccessLogFormat::Template)
+    }
+}
+
+impl Default for AccessLogFormat {
+    fn default() -> AccessLogFormat {
+        AccessLogFormat::Template(Format::default())
+    }
+}
+
 struct Inner {
+    format: AccessLogFormat,
     exclude: HashSet<String>,
-    log_target: Cow<'static, str>,
+    exclude_regex: RegexSet,
+    // Ratio of 2xx lines written to the access log.
+    sample_success: f64,
+    success_seen: Cell<u64>,
+    writer: Option<LogWriter>,
+}
+
+impl Inner {
+    fn is_excluded(&self, path: &str) -> bool {
+        self.exclude.contains(path) || self.exclude_regex.is_match(path)
+    }
+
+    /// Samples 2xx lines evenly, non-2xx lines are always written.
+    fn should_log(&self, status_code: u16) -> bool {
+        if status_code / 100 != 2 || self.sample_success >= 1.0 {
+            return true;
+        }
+        let seen = self.success_seen.get();
+        self.success_seen.set(seen + 1);
+        (seen as f64 * self.sample_success).floor()
+            != ((seen + 1) as f64 * self.sample_success).floor()
+    }
+
+    fn write(&self, line: String) {
+        if let Some(ref writer) = self.writer {
+            writer.write(line);
+        }
+    }
 }
 
 impl Moni {
-    /// Create `Moni` middleware with the specified `format`.
+    /// Create `Moni` middleware with the default format.
     pub fn new() -> Moni {
-        Moni(Rc::new(Inner {
-            exclude: HashSet::new(),
-            log_target: Cow::Borrowed(module_path!()),
-        }))
+        Moni::default()
     }
 
     /// Ignore and do not log access info for specified path.
@@ -62,14 +149,42 @@ impl Moni {
             .insert(path.into());
         self
     }
+
+    /// Ignore and do not log access info for paths that match any of `set`.
+    pub fn exclude_regex(mut self, set: RegexSet) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().exclude_regex = set;
+        self
+    }
+
+    /// Write access log lines to `writer` instead of the application log.
+    pub fn access_log(mut self, writer: LogWriter) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().writer = Some(writer);
+        self
+    }
+
+    pub fn log_format(mut self, format: AccessLogFormat) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().format = format;
+        self
+    }
+
+    /// Only write `ratio` of the 2xx lines to the access log.
+    pub fn sample_success(mut self, ratio: f64) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().sample_success = ratio;
+        self
+    }
 }
 
 impl Default for Moni {
     /// Create `Moni` middleware with format:
+    /// `%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T`
     fn default() -> Moni {
         Moni(Rc::new(Inner {
+            format: AccessLogFormat::default(),
             exclude: HashSet::new(),
-            log_target: Cow::Borrowed(module_path!()),
+            exclude_regex: RegexSet::empty(),
+            sample_success: 1.0,
+            success_seen: Cell::new(0),
+            writer: None,
         }))
     }
 }
@@ -110,28 +225,70 @@ where
 
     actix_service::forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
         TOTAL_TRANSACTION.inc();
-        if self.inner.exclude.contains(req.path()) {
+        let request_id = extract_request_id(&req);
+        let span = trace::start(&req, &request_id);
+        if self.inner.is_excluded(req.path()) {
+            let fut = with_request_id(Some(request_id.clone()), self.service.call(req));
             MoniResponse {
-                fut: self.service.call(req),
+                fut: trace::instrument(&span, fut),
+                span: Some(span),
+                request_id,
                 info: None,
                 time: OffsetDateTime::now_utc(),
                 format: None,
-                log_target: Cow::Borrowed(""),
+                inner: self.inner.clone(),
                 _phantom: Default::default(),
             }
         } else {
             let now = OffsetDateTime::now_utc();
-            let mut info: Info = Default::default();
-            info.method = req.method().clone();
-            info.url_path = req.path().to_string();
+            // Count the request body as the handler consumes it, chunked
+            // uploads carry no Content-Length.
+            let request_size = Rc::new(Cell::new(0));
+            let counter = request_size.clone();
+            let payload = req.take_payload().inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    counter.set(counter.get() + chunk.len());
+                }
+            });
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(payload),
+            });
+            let info = Info {
+                request_id: request_id.clone(),
+                status_code: 0,
+                url_path: req.path().to_string(),
+                query: req.query_string().to_string(),
+                method: req.method().clone(),
+                namespace: Namespace::from_path(req.path()),
+                remote_addr: req.connection_info().peer_addr().map(ToOwned::to_owned),
+                user_agent: req
+                    .headers()
+                    .get(USER_AGENT)
+                    .and_then(|v| v.to_str().ok())
+                    .map(ToOwned::to_owned),
+                request_size,
+            };
+            let format = match self.inner.format {
+                AccessLogFormat::Template(ref format) if self.inner.writer.is_some() => {
+                    let mut format = format.clone();
+                    for unit in &mut format.0 {
+                        unit.render_request(now, &req);
+                    }
+                    Some(format)
+                }
+                _ => None,
+            };
+            let fut = with_request_id(Some(request_id.clone()), self.service.call(req));
             MoniResponse {
-                fut: self.service.call(req),
+                fut: trace::instrument(&span, fut),
+                span: Some(span),
+                request_id,
                 info: Some(info),
-                format: None,
+                format,
                 time: now,
-                log_target: self.inner.log_target.clone(),
+                inner: self.inner.clone(),
                 _phantom: PhantomData,
             }
         }
@@ -145,10 +302,12 @@ pin_project! {
         S: Service<ServiceRequest>,
     {
         #[pin]
-        fut: S::Future,
+        fut: Traced<WithRequestId<S::Future>>,
+        span: Option<RequestSpan>,
+        request_id: Arc<str>,
         time: OffsetDateTime,
         format: Option<Format>,
-        log_target: Cow<'static, str>,
+        inner: Rc<Inner>,
         info: Option<Info>,
         _phantom: PhantomData<B>,
     }
@@ -164,24 +323,38 @@ where
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
-        let res = match futures::ready!(this.fut.poll(cx)) {
+        let mut res = match futures::ready!(this.fut.poll(cx)) {
             Ok(res) => res,
             Err(e) => return Poll::Ready(Err(e)),
         };
+        if let Ok(value) = HeaderValue::from_str(&**this.request_id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
 
         let time = *this.time;
         let mut info = this.info.take();
         if let Some(ref mut i) = info {
             i.status_code = res.status().as_u16();
         }
-        let log_target = this.log_target.clone();
+        let mut format = this.format.take();
+        if let Some(ref mut format) = format {
+            for unit in &mut format.0 {
+                unit.render_response(res.response());
+            }
+        }
+        let inner = this.inner.clone();
+        let status_code = res.status().as_u16();
+        let span = this.span.take();
         Poll::Ready(Ok(res.map_body(move |_, body| MoniLog {
             body,
             size: 0,
+            status_code,
+            span,
             time,
             info,
-            log_target,
-            format: None,
+            inner,
+            format,
         })))
     }
 }
@@ -193,53 +366,103 @@ pin_project! {
         format: Option<Format>,
         info: Option<Info>,
         size: usize,
+        status_code: u16,
+        span: Option<RequestSpan>,
         time: OffsetDateTime,
-        log_target: Cow<'static, str>,
+        inner: Rc<Inner>,
     }
 
     impl<B> PinnedDrop for MoniLog<B> {
         fn drop(this: Pin<&mut Self>) {
+            if let Some(ref span) = this.span {
+                trace::finish(span, this.status_code, this.size);
+            }
             if let Some(ref info) = this.info {
+                let namespace = info.namespace.as_str();
+                let method = info.method.as_str();
+                let status = status_class(info.status_code);
+                let request_size = info.request_size.get();
+                let rt = OffsetDateTime::now_utc() - this.time;
+
+                GREENHOUSE_REQUEST_COUNTER
+                    .with_label_values(&[namespace, method, status])
+                    .inc();
+                GREENHOUSE_REQUEST_DURATION
+                    .with_label_values(&[namespace, method, status])
+                    .observe(rt.as_seconds_f64());
+                GREENHOUSE_REQUEST_BYTES
+                    .with_label_values(&[namespace, method])
+                    .observe(request_size as f64);
+                GREENHOUSE_RESPONSE_BYTES
+                    .with_label_values(&[namespace, method])
+                    .observe(this.size as f64);
+
                 if info.method == Method::GET {
                     GREENHOUSE_READING_COUNT.inc();
-                    if info.url_path.contains("/ac/") {
-                        match info.status_code {
-                        200 => {
-                            ACTION_CACHE_HITS.inc();
-                            GREENHOUSE_SIZE_HISTOGRAM.observe(this.size as f64);
-                        }
-                        404 => ACTION_CACHE_MISSES.inc(),
-                        _ => GREENHOUSE_HTTP_ERROR.inc(),
+                    match (info.namespace, info.status_code) {
+                        (Namespace::Ac, 200) => ACTION_CACHE_HITS.inc(),
+                        (Namespace::Ac, 404) => ACTION_CACHE_MISSES.inc(),
+                        (Namespace::Cas, 200) => CAS_HITS.inc(),
+                        (Namespace::Cas, 404) => CAS_MISSES.inc(),
+                        _ => {}
                     }
+                    if info.status_code == 200 {
+                        GREENHOUSE_SIZE_HISTOGRAM.observe(this.size as f64);
                     }
                 } else {
                     GREENHOUSE_WRITING_COUNT.inc();
-                    match info.status_code {
-                        200 => {
-                            CAS_HITS.inc();
-                            GREENHOUSE_SIZE_HISTOGRAM.observe(this.size as f64);
-                        }
-                        404 => CAS_MISSES.inc(),
-                        _ => GREENHOUSE_HTTP_ERROR.inc(),
+                    if info.status_code == 200 {
+                        GREENHOUSE_SIZE_HISTOGRAM.observe(request_size as f64);
                     }
                 }
-                let rt = OffsetDateTime::now_utc() - this.time;
-                let rt_fmt = rt.subsec_nanoseconds() / 1_000_000;
+                if info.status_code >= 400 && info.status_code != 404 {
+                    GREENHOUSE_HTTP_ERROR.inc();
+                }
+
+                let rt_fmt = rt.whole_milliseconds();
                 GREENHOUSE_BUSINESS_TIMING_SUM.inc_by(rt_fmt as i64);
                 GREENHOUSE_BUSINESS_TIMING_COUNT.inc();
 
-                let url = format!("{} {}", info.method, info.url_path);
-                info!(
-                    "{}",url,
-                    ;"status" =>  info.status_code.to_string() ,"size" => this.size.to_string(),
-                    "cost" => rt_fmt.to_string()
-                );
+                if this.inner.writer.is_none() {
+                    let url = format!("{} {}", info.method, info.url_path);
+                    info!(
+                        "{}",url,
+                        ;"status" =>  info.status_code.to_string() ,"size" => this.size.to_string(),
+                        "request_size" => request_size.to_string(), "cost" => rt_fmt.to_string(),
+                        "request_id" => &*info.request_id
+                    );
+                } else if this.inner.should_log(info.status_code) {
+                    let line = match this.format {
+                        Some(ref format) => {
+                            let render = |fmt: &mut fmt::Formatter<'_>| {
+                                for unit in &format.0 {
+                                    unit.render(fmt, this.size, this.time)?;
+                                }
+                                Ok(())
+                            };
+                            FormatDisplay(&render).to_string()
+                        }
+                        None => info.to_json(this.time, this.size, rt.as_seconds_f64()),
+                    };
+                    this.inner.write(line);
+                }
             }
 
         }
     }
 }
 
+fn status_class(status_code: u16) -> &'static str {
+    match status_code / 100 {
+        1 => "1xx",
+        2 => "2xx",
+        3 => "3xx",
+        4 => "4xx",
+        5 => "5xx",
+        _ => "unknown",
+    }
+}
+
 impl<B: MessageBody> MessageBody for MoniLog<B> {
     type Error = B::Error;
 
@@ -265,29 +488,55 @@ impl<B: MessageBody> MessageBody for MoniLog<B> {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Info {
+    request_id: Arc<str>,
     status_code: u16,
     url_path: String,
+    query: String,
     method: Method,
+    namespace: Namespace,
+    remote_addr: Option<String>,
+    user_agent: Option<String>,
+    request_size: Rc<Cell<usize>>,
+}
+
+impl Info {
+    fn to_json(&self, time: OffsetDateTime, size: usize, duration: f64) -> String {
+        serde_json::json!({
+            "time": time.format(&Rfc3339).unwrap_or_default(),
+            "request_id": &*self.request_id,
+            "remote_addr": self.remote_addr,
+            "method": self.method.as_str(),
+            "path": self.url_path,
+            "query": self.query,
+            "namespace": self.namespace.as_str(),
+            "status": self.status_code,
+            "request_size": self.request_size.get(),
+            "response_size": size,
+            "duration": duration,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
 }
 
 /// A formatting style for the `Logger` consisting of multiple concatenated `FormatText` items.
 #[derive(Debug, Clone)]
-struct Format(Vec<FormatText>);
+pub struct Format(Vec<FormatText>);
 
 impl Default for Format {
     /// Return the default formatting style for the `Logger`:
     fn default() -> Format {
-        Format::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        Format::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#).unwrap()
     }
 }
 
 impl Format {
     /// Create a `Format` from a format string.
     ///
-    /// Returns `None` if the format string syntax is incorrect.
-    pub fn new(s: &str) -> Format {
+    /// Returns an error if the format string syntax is incorrect.
+    pub fn new(s: &str) -> Result<Format, String> {
         log::trace!("Access log format: {}", s);
         let fmt = Regex::new(r"%(\{([A-Za-z0-9\-_]+)\}([aioe]|xi)|[%atPrUsbTD]?)").unwrap();
 
@@ -302,19 +551,22 @@ impl Format {
             idx = m.end();
 
             if let Some(key) = cap.get(2) {
+                let header = || {
+                    HeaderName::try_from(key.as_str()).map_err(|e| {
+                        format!(
+                            "invalid header {} in access log format: {}",
+                            key.as_str(),
+                            e
+                        )
+                    })
+                };
                 results.push(match cap.get(3).unwrap().as_str() {
-                    "a" => {
-                        if key.as_str() == "r" {
-                            FormatText::RealIpRemoteAddr
-                        } else {
-                            unreachable!()
-                        }
-                    }
-                    "i" => FormatText::RequestHeader(HeaderName::try_from(key.as_str()).unwrap()),
-                    "o" => FormatText::ResponseHeader(HeaderName::try_from(key.as_str()).unwrap()),
+                    "a" if key.as_str() == "r" => FormatText::RealIpRemoteAddr,
+                    "i" => FormatText::RequestHeader(header()?),
+                    "o" => FormatText::ResponseHeader(header()?),
                     "e" => FormatText::EnvironHeader(key.as_str().to_owned()),
                     "xi" => FormatText::CustomRequest(key.as_str().to_owned(), None),
-                    _ => unreachable!(),
+                    _ => return Err(format!("unknown access log directive {}", m.as_str())),
                 })
             } else {
                 let m = cap.get(1).unwrap();
@@ -336,7 +588,7 @@ impl Format {
             results.push(FormatText::Str(s[idx..].to_owned()));
         }
 
-        Format(results)
+        Ok(Format(results))
     }
 }
 
@@ -454,7 +706,9 @@ impl FormatText {
                 };
             }
             FormatText::UrlPath => *self = FormatText::Str(req.path().to_string()),
-            FormatText::RequestTime => *self = FormatText::Str(now.format(&Rfc3339).unwrap()),
+            FormatText::RequestTime => {
+                *self = FormatText::Str(now.format(&*CLF_TIME).unwrap_or_default())
+            }
             FormatText::RequestHeader(ref name) => {
                 let s = if let Some(val) = req.headers().get(name) {
                     if let Ok(s) = val.to_str() {
@@ -504,3 +758,145 @@ impl<'a> fmt::Display for FormatDisplay<'a> {
         (self.0)(fmt)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    fn render_request(format: &str, now: OffsetDateTime) -> String {
+        let mut format = Format::new(format).unwrap();
+        let req = TestRequest::get()
+            .uri("/cas/abc?x=1")
+            .insert_header((USER_AGENT, "bazel/5.0"))
+            .to_srv_request();
+        for unit in &mut format.0 {
+            unit.render_request(now, &req);
+        }
+        let render = |fmt: &mut fmt::Formatter<'_>| {
+            for unit in &format.0 {
+                unit.render(fmt, 42, now)?;
+            }
+            Ok(())
+        };
+        FormatDisplay(&render).to_string()
+    }
+
+    #[test]
+    fn test_format_parse() {
+        assert!(matches!(
+            AccessLogFormat::parse("json"),
+            Ok(AccessLogFormat::Json)
+        ));
+        assert!(AccessLogFormat::parse("common").is_ok());
+        assert!(AccessLogFormat::parse("combined").is_ok());
+        assert!(AccessLogFormat::parse("%a %{r}a %{X-Trace}o %{HOME}e %T").is_ok());
+        // Only `%{r}a` exists.
+        assert!(AccessLogFormat::parse("%{x}a").is_err());
+        assert!(Format::new("%{x}a").is_err());
+
+        let now = OffsetDateTime::from_unix_timestamp(971_211_336).unwrap();
+        assert_eq!(
+            render_request(r#"[%t] "%r" %b %{User-Agent}i %U 100%%"#, now),
+            r#"[10/Oct/2000:20:55:36 +0000] "GET /cas/abc?x=1 HTTP/1.1" 42 bazel/5.0 /cas/abc 100%"#
+        );
+    }
+
+    #[test]
+    fn test_sample_success() {
+        let moni = Moni::new().sample_success(0.25);
+        let logged = (0..100).filter(|_| moni.0.should_log(200)).count();
+        assert_eq!(logged, 25);
+        // Errors are always logged.
+        assert!((0..10).all(|_| moni.0.should_log(500)));
+        assert!((0..10).all(|_| moni.0.should_log(404)));
+
+        let moni = Moni::new();
+        assert!((0..10).all(|_| moni.0.should_log(200)));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let moni = Moni::new()
+            .exclude("/healthz")
+            .exclude_regex(RegexSet::new(&["^/debug/", r"\.tmp$"]).unwrap());
+        assert!(moni.0.is_excluded("/healthz"));
+        assert!(moni.0.is_excluded("/debug/pprof/heap"));
+        assert!(moni.0.is_excluded("/cas/abc.tmp"));
+        assert!(!moni.0.is_excluded("/healthz/more"));
+        assert!(!moni.0.is_excluded("/cas/debug/abc"));
+    }
+
+    #[actix_web::test]
+    async fn test_request_metrics() {
+        use actix_web::{test, web, App, HttpResponse};
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Moni::new())
+                .route(
+                    "/ac/hit",
+                    web::get().to(|| async { HttpResponse::Ok().body("hello") }),
+                )
+                .route(
+                    "/cas/miss",
+                    web::get().to(|| async { HttpResponse::NotFound().finish() }),
+                )
+                .route(
+                    "/cas/new",
+                    web::put().to(|_: web::Bytes| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+        let requests = |labels: &[&str]| GREENHOUSE_REQUEST_COUNTER.with_label_values(labels).get();
+        let duration = |labels: &[&str]| {
+            GREENHOUSE_REQUEST_DURATION
+                .with_label_values(labels)
+                .get_sample_count()
+        };
+        let request_bytes = |labels: &[&str]| {
+            GREENHOUSE_REQUEST_BYTES
+                .with_label_values(labels)
+                .get_sample_sum()
+        };
+        let response_bytes = |labels: &[&str]| {
+            GREENHOUSE_RESPONSE_BYTES
+                .with_label_values(labels)
+                .get_sample_sum()
+        };
+        let hit = ["ac", "GET", "2xx"];
+        let miss = ["cas", "GET", "4xx"];
+        let put = ["cas", "PUT", "2xx"];
+        let before = (
+            requests(&hit),
+            requests(&miss),
+            requests(&put),
+            duration(&hit),
+            response_bytes(&hit[..2]),
+            request_bytes(&put[..2]),
+            ACTION_CACHE_HITS.get(),
+            CAS_MISSES.get(),
+        );
+
+        // Recorded once the body is sent and dropped.
+        let req = TestRequest::get().uri("/ac/hit").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "hello");
+        let req = TestRequest::get().uri("/cas/miss").to_request();
+        test::call_and_read_body(&app, req).await;
+        let req = TestRequest::put()
+            .uri("/cas/new")
+            .set_payload(vec![0; 2048])
+            .to_request();
+        test::call_and_read_body(&app, req).await;
+
+        assert_eq!(requests(&hit), before.0 + 1);
+        assert_eq!(requests(&miss), before.1 + 1);
+        assert_eq!(requests(&put), before.2 + 1);
+        assert_eq!(duration(&hit), before.3 + 1);
+        assert_eq!(response_bytes(&hit[..2]), before.4 + 5.0);
+        assert_eq!(request_bytes(&put[..2]), before.5 + 2048.0);
+        assert_eq!(ACTION_CACHE_HITS.get(), before.6 + 1.0);
+        assert_eq!(CAS_MISSES.get(), before.7 + 1.0);
+    }
+}
@@ -37,18 +37,18 @@ lazy_static! {
     )
     .unwrap();
     pub static ref ACTION_CACHE_HITS: Counter = register_counter!(opts!(
-        "bazel_cache_cas_hits",
+        "bazel_cache_action_hits",
         "Approximate number of Action Cache hits since last server start"
     ))
     .unwrap();
     pub static ref CAS_HITS: Counter = register_counter!(opts!(
-        "bazel_cache_action_hits",
+        "bazel_cache_cas_hits",
         "Approximate number of Content Addressed Storage cache hits since last server start"
     ))
     .unwrap();
     pub static ref ACTION_CACHE_MISSES: Counter = register_counter!(opts!(
         "bazel_cache_action_misses",
-        "Approximate number of Content Addressed Storage cache misses since last server start"
+        "Approximate number of Action Cache misses since last server start"
     ))
     .unwrap();
     pub static ref CAS_MISSES: Counter = register_counter!(opts!(
@@ -56,4 +56,37 @@ lazy_static! {
         "Approximate number of Content Addressed Storage cache misses since last server start"
     ))
     .unwrap();
+    pub static ref GREENHOUSE_REQUEST_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_request_total",
+        "Total number of http requests",
+        &["namespace", "method", "status"]
+    )
+    .unwrap();
+    pub static ref GREENHOUSE_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "greenhouse_request_duration_seconds",
+        "Bucketed histogram of http request duration, until the response body is sent",
+        &["namespace", "method", "status"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref GREENHOUSE_REQUEST_BYTES: HistogramVec = register_histogram_vec!(
+        "greenhouse_request_bytes",
+        "Bucketed histogram of http request body size",
+        &["namespace", "method"],
+        exponential_buckets(1024.0, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref GREENHOUSE_RESPONSE_BYTES: HistogramVec = register_histogram_vec!(
+        "greenhouse_response_bytes",
+        "Bucketed histogram of http response body size",
+        &["namespace", "method"],
+        exponential_buckets(1024.0, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref LOG_DROPPED_LINES: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_log_dropped_lines_total",
+        "Total number of log lines dropped because the writer fell behind",
+        &["log"]
+    )
+    .unwrap();
 }
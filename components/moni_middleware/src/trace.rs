@@ -0,0 +1,87 @@
+//! Server span of a request, the parent of the spans made by the handler and
+//! the storage pools. Everything here is a no-op unless the crate is built
+//! with the `tracing` feature.
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use actix_web::dev::ServiceRequest;
+    use actix_web::http::header::HeaderMap;
+    use futures::Future;
+    use opentelemetry::global;
+    use opentelemetry::propagation::Extractor;
+    use opentelemetry::trace::{FutureExt, SpanKind, TraceContextExt, Tracer, WithContext};
+    use opentelemetry::{Context, KeyValue};
+
+    const TRACER_NAME: &str = "greenhouse-http";
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl<'a> Extractor for HeaderExtractor<'a> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|k| k.as_str()).collect()
+        }
+    }
+
+    pub type RequestSpan = Context;
+
+    pub type Traced<F> = WithContext<F>;
+
+    /// Starts the server span, continuing the trace of a W3C `traceparent`
+    /// header if the client sent one.
+    pub fn start(req: &ServiceRequest, request_id: &str) -> RequestSpan {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(format!("{} {}", req.method(), req.path()))
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.method", req.method().to_string()),
+                KeyValue::new("http.target", req.path().to_string()),
+                KeyValue::new("request_id", request_id.to_string()),
+            ])
+            .start_with_context(&tracer, &parent);
+        parent.with_span(span)
+    }
+
+    pub fn instrument<F: Future>(span: &RequestSpan, fut: F) -> Traced<F> {
+        fut.with_context(span.clone())
+    }
+
+    pub fn finish(span: &RequestSpan, status_code: u16, size: usize) {
+        let span = span.span();
+        span.set_attribute(KeyValue::new("http.status_code", i64::from(status_code)));
+        span.set_attribute(KeyValue::new("http.response_size", size as i64));
+        span.end();
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use actix_web::dev::ServiceRequest;
+    use futures::Future;
+
+    pub struct RequestSpan;
+
+    pub type Traced<F> = F;
+
+    #[inline]
+    pub fn start(_req: &ServiceRequest, _request_id: &str) -> RequestSpan {
+        RequestSpan
+    }
+
+    #[inline]
+    pub fn instrument<F: Future>(_span: &RequestSpan, fut: F) -> Traced<F> {
+        fut
+    }
+
+    #[inline]
+    pub fn finish(_span: &RequestSpan, _status_code: u16, _size: usize) {}
+}
+
+pub(crate) use self::imp::{finish, instrument, start, RequestSpan, Traced};
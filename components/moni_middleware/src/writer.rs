@@ -0,0 +1,110 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::metrics::LOG_DROPPED_LINES;
+
+/// Lines a writer thread may be behind before new ones are dropped.
+const CHANNEL_SIZE: usize = 4096;
+
+/// Destination of log lines, shared by all http workers. Lines are written
+/// by a thread of its own, so a slow disk never holds up a request, and are
+/// dropped while that thread is `CHANNEL_SIZE` lines behind.
+///
+/// Clones share the thread, which writes out what is left and exits once the
+/// last clone is dropped.
+#[derive(Clone)]
+pub struct LogWriter(Arc<Inner>);
+
+struct Inner {
+    name: String,
+    tx: Option<SyncSender<String>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LogWriter {
+    /// `name` is given to the thread and to the dropped lines metric.
+    pub fn new<W>(name: &str, writer: W) -> io::Result<LogWriter>
+    where
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(CHANNEL_SIZE);
+        let thread_name = name.to_owned();
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || run(&thread_name, writer, rx))?;
+        Ok(LogWriter(Arc::new(Inner {
+            name: name.to_owned(),
+            tx: Some(tx),
+            handle: Some(handle),
+        })))
+    }
+
+    /// Queues `line`, a newline is added. Never blocks.
+    pub fn write(&self, line: String) {
+        match self.0.tx.as_ref().unwrap().try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                LOG_DROPPED_LINES.with_label_values(&[&self.0.name]).inc()
+            }
+            // Only once the thread panicked.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run<W: Write>(name: &str, mut writer: W, rx: Receiver<String>) {
+    while let Ok(line) = rx.recv() {
+        // One flush for all the lines that queued up meanwhile.
+        let res = std::iter::once(line)
+            .chain(rx.try_iter())
+            .try_for_each(|line| writeln!(writer, "{}", line))
+            .and_then(|_| writer.flush());
+        if let Err(e) = res {
+            error!("failed to write log"; "log" => name, "err" => e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_lines_written_out_on_drop() {
+        let buffer = Buffer::default();
+        let writer = LogWriter::new("test-log", buffer.clone()).unwrap();
+        let clone = writer.clone();
+        writer.write("first".to_owned());
+        clone.write("second".to_owned());
+        drop(writer);
+        drop(clone);
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(written, "first\nsecond\n");
+    }
+}
@@ -0,0 +1,48 @@
+//! The keyspace a request or a stored key belongs to, shared by the
+//! storage and the http middleware.
+
+/// Bazel remote cache keyspaces. Keys look like `[instance/]ac/<digest>` or
+/// `[instance/]cas/<digest>`; everything else falls into `Other`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Namespace {
+    Ac,
+    Cas,
+    Other,
+}
+
+impl Namespace {
+    pub fn from_path(path: &str) -> Namespace {
+        // Only the last directory before the digest decides the namespace.
+        let parent = path.split('/').filter(|s| !s.is_empty()).rev().nth(1);
+        match parent {
+            Some("ac") => Namespace::Ac,
+            Some("cas") => Namespace::Cas,
+            _ => Namespace::Other,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Namespace::Ac => "ac",
+            Namespace::Cas => "cas",
+            Namespace::Other => "other",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Namespace;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(Namespace::from_path("/ac/abc"), Namespace::Ac);
+        assert_eq!(Namespace::from_path("/cas/abc"), Namespace::Cas);
+        assert_eq!(Namespace::from_path("instance/cas/abc"), Namespace::Cas);
+        assert_eq!(Namespace::from_path("/cas/"), Namespace::Other);
+        assert_eq!(Namespace::from_path("/cas"), Namespace::Other);
+        assert_eq!(Namespace::from_path("/ac/cas"), Namespace::Ac);
+        assert_eq!(Namespace::from_path("/foo/abc"), Namespace::Other);
+        assert_eq!(Namespace::from_path(""), Namespace::Other);
+    }
+}
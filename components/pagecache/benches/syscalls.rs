@@ -0,0 +1,120 @@
+//! Syscalls per operation of each IO engine on a segment-like workload:
+//! appends of small records, then reads of them in batches.
+//!
+//! ```text
+//! cargo bench -p pagecache --features io_uring --bench syscalls
+//! ```
+//!
+//! Without the feature only the thread pool runs. io_uring modes the
+//! kernel refuses are skipped.
+
+use std::fs::{File, OpenOptions};
+use std::sync::Arc;
+use std::time::Instant;
+
+use pagecache::{IoEngine, ReadRequest, ThreadPoolIo};
+
+const FILES: usize = 4;
+const RECORDS: usize = 20_000;
+const RECORD_SIZE: usize = 512;
+const BATCH: usize = 32;
+
+fn run(engine: &dyn IoEngine, files: &[Arc<File>]) {
+    for file in files {
+        engine.register_file(file);
+    }
+    let record = vec![7; RECORD_SIZE];
+
+    let (start, syscalls) = (Instant::now(), engine.syscalls());
+    for i in 0..RECORDS {
+        let offset = (i / FILES * RECORD_SIZE) as u64;
+        engine
+            .pwrite_all(&files[i % FILES], &record, offset, false)
+            .unwrap();
+    }
+    report(engine.name(), "write", start, engine.syscalls() - syscalls);
+
+    let mut bufs = vec![vec![0; RECORD_SIZE]; BATCH];
+    let (start, syscalls) = (Instant::now(), engine.syscalls());
+    for batch in 0..RECORDS / BATCH {
+        let mut reads: Vec<ReadRequest<'_>> = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(j, buf)| {
+                let i = batch * BATCH + j;
+                ReadRequest {
+                    file: &files[i % FILES],
+                    offset: (i / FILES * RECORD_SIZE) as u64,
+                    buf,
+                }
+            })
+            .collect();
+        engine.pread_batch(&mut reads).unwrap();
+    }
+    report(engine.name(), "read", start, engine.syscalls() - syscalls);
+
+    for file in files {
+        engine.unregister_file(file);
+    }
+}
+
+fn report(engine: &str, op: &str, start: Instant, syscalls: u64) {
+    let ops = (RECORDS / BATCH * BATCH) as f64;
+    println!(
+        "{:<24} {:<6} {:>8.3} syscalls/op {:>12.0} ops/s",
+        engine,
+        op,
+        syscalls as f64 / ops,
+        ops / start.elapsed().as_secs_f64()
+    );
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let files: Vec<Arc<File>> = (0..FILES)
+        .map(|i| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(dir.path().join(format!("{}.seg", i)))
+                .unwrap();
+            Arc::new(file)
+        })
+        .collect();
+
+    run(&ThreadPoolIo::new(4).unwrap(), &files);
+
+    #[cfg(all(unix, feature = "io_uring"))]
+    {
+        use pagecache::{UringConfig, UringEngine};
+
+        let modes = vec![
+            (
+                "current",
+                UringConfig {
+                    fixed_files: 0,
+                    write_buffers: 0,
+                    ..UringConfig::default()
+                },
+            ),
+            ("fixed files+buffers", UringConfig::default()),
+            (
+                "sqpoll",
+                UringConfig {
+                    sqpoll: true,
+                    ..UringConfig::default()
+                },
+            ),
+        ];
+        for (mode, cfg) in modes {
+            match UringEngine::new(&cfg) {
+                Ok(engine) => {
+                    println!("io_uring {}:", mode);
+                    run(&engine, &files);
+                }
+                Err(e) => println!("io_uring {}: skipped, {}", mode, e),
+            }
+        }
+    }
+}
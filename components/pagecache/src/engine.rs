@@ -0,0 +1,297 @@
+//! How segment files are read and written. `io_uring` submits a whole batch
+//! with one syscall; where the kernel refuses it, a pool of threads runs
+//! plain `pread` and `pwrite` instead.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{Error, LogOffset, Result};
+
+/// Fills `buf` from `file` at `offset`.
+pub struct ReadRequest<'a> {
+    pub file: &'a Arc<File>,
+    pub offset: LogOffset,
+    pub buf: &'a mut [u8],
+}
+
+/// How `open` sets up io_uring. The thread pool ignores it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UringConfig {
+    pub queue_depth: usize,
+    /// A kernel thread polls the submission queue, so submitting takes no
+    /// syscall while it is awake. Before Linux 5.11 it needs
+    /// CAP_SYS_ADMIN and the poll thread only takes files in fixed slots;
+    /// `open` goes on without polling if either is missing.
+    pub sqpoll: bool,
+    /// How long the poll thread spins without work before it sleeps.
+    pub sqpoll_idle: Duration,
+    /// Pins the poll thread to this CPU.
+    pub sqpoll_cpu: Option<u32>,
+    /// Files registered in fixed slots, which saves the kernel looking up
+    /// the file on every request. Files past that are used by descriptor.
+    pub fixed_files: usize,
+    /// Buffers registered for writes, saving the kernel mapping the pages
+    /// on every request. Writes go through them in chunks of
+    /// `write_buffer_size`.
+    pub write_buffers: usize,
+    pub write_buffer_size: usize,
+}
+
+impl Default for UringConfig {
+    fn default() -> UringConfig {
+        UringConfig {
+            queue_depth: 64,
+            sqpoll: false,
+            sqpoll_idle: Duration::from_secs(1),
+            sqpoll_cpu: None,
+            fixed_files: 256,
+            write_buffers: 4,
+            write_buffer_size: 64 * 1024,
+        }
+    }
+}
+
+/// Every call returns once its IO is complete, so buffers are only
+/// borrowed.
+pub trait IoEngine: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Syscalls issued so far for IO, to compare engines.
+    fn syscalls(&self) -> u64;
+
+    /// Lets the engine keep `file` open and at hand until `unregister_file`.
+    /// Best effort, files not registered are used by descriptor.
+    fn register_file(&self, _file: &Arc<File>) {}
+
+    fn unregister_file(&self, _file: &Arc<File>) {}
+
+    /// Writes all of `buf` at `offset`, then syncs the data of `file` if
+    /// `fsync`.
+    fn pwrite_all(
+        &self,
+        file: &Arc<File>,
+        buf: &[u8],
+        offset: LogOffset,
+        fsync: bool,
+    ) -> Result<()>;
+
+    /// Fills every buffer of `reads`, which may point into different
+    /// files. Reading past the end of a file fails.
+    fn pread_batch(&self, reads: &mut [ReadRequest<'_>]) -> Result<()>;
+
+    fn pread_exact(&self, file: &Arc<File>, buf: &mut [u8], offset: LogOffset) -> Result<()> {
+        self.pread_batch(&mut [ReadRequest { file, offset, buf }])
+    }
+}
+
+/// Uses io_uring if built with it and the kernel takes it, a pool of
+/// `threads` threads otherwise.
+pub fn open(threads: usize, uring: &UringConfig) -> Result<Arc<dyn IoEngine>> {
+    #[cfg(all(unix, feature = "io_uring"))]
+    {
+        use crate::io_uring::UringEngine;
+
+        // Old kernels fail with ENOSYS, seccomp profiles with EPERM.
+        if let Ok(engine) = UringEngine::new(uring) {
+            return Ok(Arc::new(engine));
+        }
+        if uring.sqpoll {
+            let cfg = UringConfig {
+                sqpoll: false,
+                ..uring.clone()
+            };
+            if let Ok(engine) = UringEngine::new(&cfg) {
+                return Ok(Arc::new(engine));
+            }
+        }
+    }
+    #[cfg(not(all(unix, feature = "io_uring")))]
+    let _ = uring;
+    Ok(Arc::new(ThreadPoolIo::new(threads)?))
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs reads and writes on a pool of threads, a batch spread over all of
+/// them.
+pub struct ThreadPoolIo {
+    // `Sender` is not `Sync`.
+    jobs: Mutex<mpsc::Sender<Job>>,
+    syscalls: Arc<AtomicU64>,
+}
+
+impl ThreadPoolIo {
+    pub fn new(threads: usize) -> Result<ThreadPoolIo> {
+        if threads == 0 {
+            return Err(Error::Unsupported("io thread pool needs a thread".into()));
+        }
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads {
+            let rx = rx.clone();
+            // Threads exit once the pool is dropped and the channel closed.
+            thread::Builder::new()
+                .name(format!("pagecache-io-{}", i))
+                .spawn(move || loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    job();
+                })?;
+        }
+        Ok(ThreadPoolIo {
+            jobs: Mutex::new(tx),
+            syscalls: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    fn spawn(&self, job: Job) -> Result<()> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(job)
+            .map_err(|_| Error::ReportableBug("io threads are gone".into()))
+    }
+}
+
+impl IoEngine for ThreadPoolIo {
+    fn name(&self) -> &'static str {
+        "thread-pool"
+    }
+
+    fn syscalls(&self) -> u64 {
+        self.syscalls.load(Ordering::Relaxed)
+    }
+
+    fn pwrite_all(
+        &self,
+        file: &Arc<File>,
+        buf: &[u8],
+        offset: LogOffset,
+        fsync: bool,
+    ) -> Result<()> {
+        let file = file.clone();
+        let buf = buf.to_vec();
+        let syscalls = self.syscalls.clone();
+        let (tx, rx) = mpsc::channel();
+        self.spawn(Box::new(move || {
+            // Counts one `pwrite`, regular files take all of it at once.
+            syscalls.fetch_add(1, Ordering::Relaxed);
+            let mut res = file.write_all_at(&buf, offset);
+            if res.is_ok() && fsync {
+                syscalls.fetch_add(1, Ordering::Relaxed);
+                res = file.sync_data();
+            }
+            let _ = tx.send(res);
+        }))?;
+        rx.recv()
+            .map_err(|_| Error::ReportableBug("io thread died".into()))?
+            .map_err(Error::from)
+    }
+
+    fn pread_batch(&self, reads: &mut [ReadRequest<'_>]) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        for (i, read) in reads.iter().enumerate() {
+            let file = read.file.clone();
+            let offset = read.offset;
+            let len = read.buf.len();
+            let syscalls = self.syscalls.clone();
+            let tx = tx.clone();
+            self.spawn(Box::new(move || {
+                syscalls.fetch_add(1, Ordering::Relaxed);
+                let mut buf = vec![0; len];
+                let res = file.read_exact_at(&mut buf, offset).map(|_| buf);
+                let _ = tx.send((i, res));
+            }))?;
+        }
+        drop(tx);
+        let mut done = 0;
+        let mut first_err = None;
+        for (i, res) in rx {
+            done += 1;
+            match res {
+                Ok(buf) => reads[i].buf.copy_from_slice(&buf),
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_err {
+            return Err(Error::Io(e));
+        }
+        if done < reads.len() {
+            return Err(Error::ReportableBug("io thread died".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::io;
+
+    pub fn check_engine(engine: &dyn IoEngine) {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |name| {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(dir.path().join(name))
+                .unwrap();
+            Arc::new(file)
+        };
+        let (a, b) = (open("a"), open("b"));
+        // Only `a` gets a slot, `b` goes by descriptor.
+        engine.register_file(&a);
+        engine.pwrite_all(&a, b"hello world", 0, false).unwrap();
+        engine.pwrite_all(&a, b"HELLO", 0, true).unwrap();
+        engine.pwrite_all(&b, &[9; 10000], 100, false).unwrap();
+
+        let (mut x, mut y, mut z) = ([0; 5], [0; 5], vec![0; 10000]);
+        let mut reads = [
+            ReadRequest {
+                file: &a,
+                offset: 6,
+                buf: &mut x,
+            },
+            ReadRequest {
+                file: &a,
+                offset: 0,
+                buf: &mut y,
+            },
+            ReadRequest {
+                file: &b,
+                offset: 100,
+                buf: &mut z,
+            },
+        ];
+        engine.pread_batch(&mut reads).unwrap();
+        assert_eq!(&x, b"world");
+        assert_eq!(&y, b"HELLO");
+        assert_eq!(z, vec![9; 10000]);
+
+        let mut past_end = [0; 4];
+        match engine.pread_exact(&a, &mut past_end, 9) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            res => panic!("read past the end: {:?}", res),
+        }
+        engine.pread_batch(&mut []).unwrap();
+        engine.unregister_file(&a);
+    }
+
+    #[test]
+    fn test_thread_pool_io() {
+        let engine = ThreadPoolIo::new(3).unwrap();
+        check_engine(&engine);
+        assert_eq!(engine.syscalls(), 8);
+        assert!(ThreadPoolIo::new(0).is_err());
+    }
+}
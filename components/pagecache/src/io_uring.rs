@@ -1,44 +1,87 @@
 #![cfg(all(unix, feature = "io_uring"))]
 
 use libc::off_t;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io;
 use std::mem;
-use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
 
+use crate::engine::{IoEngine, ReadRequest, UringConfig};
 use crate::LogOffset;
 use crate::{Error, Result};
 
 use liburing::*;
 
-/// TODO: support for SQPOLL with idle timeout
-pub(crate) struct Uring<T> {
+/// Times to poll the completion queue before sleeping in the kernel, when
+/// the SQ poll thread is doing the IO.
+const SQPOLL_SPINS: usize = 10_000;
+
+/// Set in `io_uring_params.features` from Linux 5.11, where the SQ poll
+/// thread also takes files by descriptor.
+const IORING_FEAT_SQPOLL_NONFIXED: u32 = 1 << 7;
+
+/// One read or write of a batch. The kernel may transfer less than asked,
+/// then the rest goes in a later round.
+struct Op {
+    /// What the SQE names the file by, from `Uring::target`.
+    target: (RawFd, libc::c_uint),
+    offset: LogOffset,
+    ptr: *mut u8,
+    len: usize,
+    done: usize,
+}
+
+pub(crate) struct Uring {
     /// Mutable unsafe FFI struct from liburing::
     ring: io_uring,
 
-    /// File
-    file: Arc<File>,
+    /// Most operations in flight at once.
+    size: usize,
+
+    /// Whether a kernel thread polls the submission queue.
+    sqpoll: bool,
+
+    /// The poll thread only takes files in fixed slots.
+    fixed_only: bool,
+
+    /// Fixed file slot of each registered file, by its address. The file is
+    /// held open meanwhile, so neither its address nor its descriptor can
+    /// be taken by another file while the slot points at it.
+    slots: HashMap<usize, (Arc<File>, u32)>,
 
-    /// IOVec structures that hold information about buffers
-    /// used in SQEs.
-    iovecs: Vec<libc::iovec>,
+    /// Registered slots holding no file.
+    free_slots: Vec<u32>,
 
-    /// iovec buffs. this is specifically needed to preserve buf
-    /// ptr lifetime because all write operations are async.
-    buf: Vec<Option<Arc<T>>>,
+    /// Buffers registered for writes. The kernel keeps them pinned until
+    /// the ring exits, so they are never resized.
+    write_buffers: Vec<Vec<u8>>,
 
-    /// List of free slots: iovecs, user data, etc.
-    free_slots: Vec<usize>,
+    /// `io_uring_enter` calls so far.
+    enters: u64,
+
+    /// Set when entries were left in the submission queue. They point into
+    /// memory that is gone, so the ring is never entered again.
+    broken: bool,
 }
 
 /// Pointers can't be passed safely through threads boundaries.
 /// `Uring` must enforce it, yet it is not Sync.
 #[allow(unsafe_code)]
-unsafe impl<T> Send for Uring<T> {}
+unsafe impl Send for Uring {}
+
+fn file_key(file: &File) -> usize {
+    file as *const File as usize
+}
+
+fn os_error(ret: libc::c_int) -> Error {
+    Error::Io(io::Error::from_raw_os_error(-ret))
+}
 
-impl<T> Uring<T> {
+impl Uring {
     /// Create and initialize new `io_uring` structure with
     /// `size` queue length and `flags` specified properties.
     ///
@@ -47,206 +90,584 @@ impl<T> Uring<T> {
     /// - `IORING_SETUP_SQPOLL` (1U << 1)  /* SQ poll thread */
     /// - `IORING_SETUP_SQ_AFF` (1U << 2)  /* `sq_thread_cpu` is valid */
     /// - `IORING_SETUP_CQSIZE` (1U << 3)  /* app defines CQ size */
-    pub fn new(file: Arc<File>, size: usize, flags: libc::c_uint) -> Result<Self> {
+    pub fn new(size: usize, flags: libc::c_uint) -> Result<Self> {
+        let mut params: io_uring_params = unsafe { mem::zeroed() };
+        params.flags = flags;
+        Uring::with_params(size, params)
+    }
+
+    /// Sets up polling, fixed file slots and write buffers as configured.
+    /// Slots and buffers the kernel refuses are done without.
+    pub fn with_config(cfg: &UringConfig) -> Result<Self> {
+        let mut params: io_uring_params = unsafe { mem::zeroed() };
+        if cfg.sqpoll {
+            params.flags |= IORING_SETUP_SQPOLL;
+            params.sq_thread_idle = u32::try_from(cfg.sqpoll_idle.as_millis()).unwrap_or(u32::MAX);
+            if let Some(cpu) = cfg.sqpoll_cpu {
+                params.flags |= IORING_SETUP_SQ_AFF;
+                params.sq_thread_cpu = cpu;
+            }
+        }
+        let mut uring = Uring::with_params(cfg.queue_depth, params)?;
+        uring.register_slots(cfg.fixed_files);
+        if uring.fixed_only && uring.free_slots.is_empty() {
+            return Err(Error::Unsupported(
+                "SQPOLL needs fixed files before Linux 5.11".into(),
+            ));
+        }
+        uring.register_write_buffers(cfg.write_buffers, cfg.write_buffer_size);
+        Ok(uring)
+    }
+
+    fn with_params(size: usize, mut params: io_uring_params) -> Result<Self> {
         if size & 1 != 0 || size < 2 {
             return Err(Error::Unsupported("invalid queue size".into()));
         }
 
         let ring = unsafe {
             let mut s = mem::MaybeUninit::<io_uring>::uninit();
-            let ret = io_uring_queue_init(u32::try_from(size).unwrap(), s.as_mut_ptr(), flags);
+            let ret = io_uring_queue_init_params(
+                u32::try_from(size).unwrap(),
+                s.as_mut_ptr(),
+                &mut params,
+            );
             if ret < 0 {
-                return Err(Error::Io(io::Error::from_raw_os_error(-ret)));
+                return Err(os_error(ret));
             }
 
             s.assume_init()
         };
 
-        let mut uring = Uring::<T> {
+        let sqpoll = params.flags & IORING_SETUP_SQPOLL != 0;
+        Ok(Uring {
             ring,
-            file,
-            iovecs: Vec::with_capacity(size),
-            buf: Vec::with_capacity(size),
-            free_slots: Vec::with_capacity(size),
+            size,
+            sqpoll,
+            fixed_only: sqpoll && params.features & IORING_FEAT_SQPOLL_NONFIXED == 0,
+            slots: HashMap::new(),
+            free_slots: vec![],
+            write_buffers: vec![],
+            enters: 0,
+            broken: false,
+        })
+    }
+
+    /// Registers `n` empty slots, filled by `register_file`.
+    fn register_slots(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let fds: Vec<RawFd> = vec![-1; n];
+        let ret = unsafe {
+            io_uring_register_files(&mut self.ring, fds.as_ptr(), u32::try_from(n).unwrap())
         };
+        // Kernels before 5.5 take no empty slots.
+        if ret < 0 {
+            return;
+        }
+        self.free_slots = (0..u32::try_from(n).unwrap()).rev().collect();
+    }
 
-        unsafe {
-            let ret =
-                io_uring_register_files(&mut uring.ring, [uring.file.as_raw_fd()].as_ptr(), 1);
-            if ret < 0 {
-                return Err(Error::Io(io::Error::from_raw_os_error(-ret)));
-            }
+    fn register_write_buffers(&mut self, n: usize, size: usize) {
+        if n == 0 || size == 0 {
+            return;
+        }
+        let mut buffers: Vec<Vec<u8>> = (0..n).map(|_| vec![0; size]).collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut std::ffi::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let ret = unsafe {
+            io_uring_register_buffers(&mut self.ring, iovecs.as_ptr(), u32::try_from(n).unwrap())
         };
+        // Over RLIMIT_MEMLOCK, writes go from the caller buffer.
+        if ret < 0 {
+            return;
+        }
+        self.write_buffers = buffers;
+    }
 
-        uring.iovecs.resize(
-            size,
-            libc::iovec {
-                iov_base: std::ptr::null_mut(),
-                iov_len: 0,
-            },
-        );
-        uring.buf.resize(size, None);
+    pub fn register_file(&mut self, file: &Arc<File>) {
+        if self.slots.contains_key(&file_key(file)) {
+            return;
+        }
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => return,
+        };
+        let mut fds = [file.as_raw_fd()];
+        let ret =
+            unsafe { io_uring_register_files_update(&mut self.ring, slot, fds.as_mut_ptr(), 1) };
+        if ret < 0 {
+            self.free_slots.push(slot);
+            return;
+        }
+        self.slots.insert(file_key(file), (file.clone(), slot));
+    }
 
-        // index free elements
-        for i in 0..size {
-            uring.free_slots.push(i);
+    pub fn unregister_file(&mut self, file: &File) {
+        let slot = match self.slots.remove(&file_key(file)) {
+            Some((_, slot)) => slot,
+            None => return,
+        };
+        let mut fds: [RawFd; 1] = [-1];
+        let ret =
+            unsafe { io_uring_register_files_update(&mut self.ring, slot, fds.as_mut_ptr(), 1) };
+        // A slot still holding the file is never handed out again.
+        if ret >= 0 {
+            self.free_slots.push(slot);
         }
+    }
 
-        Ok(uring)
+    /// Whether the ring can take `file`. The poll thread fails a file it
+    /// only has by descriptor with `EBADF`, those are read and written here.
+    fn takes(&self, file: &File) -> bool {
+        !self.fixed_only || self.slots.contains_key(&file_key(file))
+    }
+
+    /// What to put in an SQE for `file`: its fixed slot if it has one.
+    fn target(&self, file: &File) -> (RawFd, libc::c_uint) {
+        match self.slots.get(&file_key(file)) {
+            Some(&(_, slot)) => (slot as RawFd, IOSQE_FIXED_FILE),
+            None => (file.as_raw_fd(), 0),
+        }
+    }
+
+    unsafe fn get_sqe(&mut self) -> Result<*mut io_uring_sqe> {
+        let sqe = io_uring_get_sqe(&mut self.ring);
+        if sqe.is_null() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                "unexpected lack of sqes",
+            )));
+        }
+        Ok(sqe)
     }
 
-    unsafe fn drain_cqe(&mut self) -> Result<()> {
+    /// Returns how many entries the kernel took.
+    unsafe fn submit(&mut self) -> Result<usize> {
+        // With SQPOLL the kernel thread picks entries up by itself, liburing
+        // only enters to wake it up once it went idle.
+        if !self.sqpoll || std::ptr::read_volatile(self.ring.sq.kflags) & IORING_SQ_NEED_WAKEUP != 0
+        {
+            self.enters += 1;
+        }
+        let ret = io_uring_submit(&mut self.ring);
+        if ret < 0 {
+            return Err(os_error(ret));
+        }
+        Ok(usize::try_from(ret).unwrap())
+    }
+
+    /// Submits the `prepared` entries and passes the user data and result of
+    /// each completion to `complete`.
+    ///
+    /// Never returns while the kernel may still use memory of the batch,
+    /// which the caller frees or reuses afterwards: every entry submitted is
+    /// reaped, also after an error.
+    unsafe fn submit_batch<F>(&mut self, prepared: usize, mut complete: F) -> Result<()>
+    where
+        F: FnMut(usize, i32),
+    {
+        let mut first_err = None;
+        let (mut submitted, mut reaped) = (0, 0);
+        while submitted < prepared {
+            match self.submit() {
+                Ok(0) => {
+                    first_err.get_or_insert(Error::ReportableBug("io_uring took no entry".into()));
+                    break;
+                }
+                Ok(n) => submitted += n,
+                // The poll thread takes the entries from the ring whether
+                // waking it up failed or not.
+                Err(_) if self.sqpoll => submitted = prepared,
+                // The completion queue is full, make room and try again.
+                Err(Error::Io(ref e))
+                    if e.raw_os_error() == Some(libc::EBUSY) && reaped < submitted =>
+                {
+                    let (data, res) = self.reap();
+                    complete(usize::try_from(data).unwrap(), res);
+                    reaped += 1;
+                }
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                    break;
+                }
+            }
+        }
+        if submitted < prepared {
+            self.broken = true;
+        }
+        while reaped < submitted {
+            let (data, res) = self.reap();
+            complete(usize::try_from(data).unwrap(), res);
+            reaped += 1;
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for a completion the kernel owes. Returning without it would
+    /// let the kernel write into freed memory, so a ring that cannot be
+    /// waited on aborts the process.
+    unsafe fn reap(&mut self) -> (u64, i32) {
+        match self.wait_cqe() {
+            Ok(completion) => completion,
+            Err(e) => {
+                crit!("failed to reap io_uring completions in flight"; "err" => %e);
+                std::process::abort();
+            }
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.broken {
+            return Err(Error::ReportableBug(
+                "io_uring left entries unsubmitted".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Waits for the next completion, returns its user data and result.
+    unsafe fn wait_cqe(&mut self) -> Result<(u64, i32)> {
+        let mut spins = 0;
         loop {
             let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
             let ret = io_uring_peek_cqe(&mut self.ring, &mut cqe);
             if ret == -libc::EAGAIN {
-                // peek found nothing, no completions to drain
-                return Ok(());
+                // The poll thread is likely about to complete it.
+                if self.sqpoll && spins < SQPOLL_SPINS {
+                    spins += 1;
+                    std::hint::spin_loop();
+                    continue;
+                }
+                // peek found nothing, wait for one
+                self.enters += 1;
+                let ret = io_uring_submit_and_wait(&mut self.ring, 1);
+                if ret < 0 && ret != -libc::EINTR && ret != -libc::EAGAIN && ret != -libc::EBUSY {
+                    return Err(os_error(ret));
+                }
+                continue;
             }
             if ret < 0 {
-                return Err(Error::Io(io::Error::from_raw_os_error(-ret)));
-            }
-            let i = usize::try_from((*cqe).user_data).unwrap();
-            if (*cqe).res < 0 {
-                // ignore failed fsyncs with invalid argument due to the
-                // overlap with executing ones: 2 drain fsync do not exec twice.
-                if (*cqe).res != -libc::EINVAL
-                    || i >= self.free_slots.capacity()
-                    || self.iovecs[i].iov_len != 0
-                {
-                    return Err(Error::Io(io::Error::from_raw_os_error(-(*cqe).res)));
+                return Err(os_error(ret));
+            }
+            let completion = ((*cqe).user_data, (*cqe).res);
+            io_uring_cqe_seen(&mut self.ring, cqe);
+            return Ok(completion);
+        }
+    }
+
+    /// Reads or writes all of `ops`, submitting up to `size` of them with a
+    /// single syscall, and waits for every one.
+    unsafe fn run(&mut self, ops: &mut [Op], write: bool) -> Result<()> {
+        let mut pending: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].len > 0).collect();
+        while !pending.is_empty() {
+            let n = pending.len().min(self.size);
+            let batch: Vec<usize> = pending.drain(..n).collect();
+            let iovecs: Vec<libc::iovec> = batch
+                .iter()
+                .map(|&i| libc::iovec {
+                    iov_base: ops[i].ptr.add(ops[i].done) as *mut std::ffi::c_void,
+                    iov_len: ops[i].len - ops[i].done,
+                })
+                .collect();
+            let mut first_err = None;
+            let mut prepared = 0;
+            for (slot, &i) in batch.iter().enumerate() {
+                let sqe = match self.get_sqe() {
+                    Ok(sqe) => sqe,
+                    Err(e) => {
+                        first_err = Some(e);
+                        // Those not prepared are never submitted.
+                        break;
+                    }
+                };
+                let op = &ops[i];
+                let (fd, flags) = op.target;
+                let offset = off_t::try_from(op.offset + op.done as u64).unwrap();
+                if write {
+                    io_uring_prep_writev(sqe, fd, &iovecs[slot], 1, offset);
+                } else {
+                    io_uring_prep_readv(sqe, fd, &iovecs[slot], 1, offset);
+                }
+                io_uring_sqe_set_flags(sqe, flags);
+                (*sqe).user_data = u64::try_from(slot).unwrap();
+                prepared += 1;
+            }
+
+            // The kernel holds pointers to `iovecs` and the buffers until
+            // the whole batch is reaped.
+            let submitted = self.submit_batch(prepared, |slot, res| {
+                let i = batch[slot];
+                if res < 0 {
+                    first_err.get_or_insert(os_error(res));
+                } else if res == 0 {
+                    first_err.get_or_insert(Error::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    )));
+                } else {
+                    ops[i].done += usize::try_from(res).unwrap();
+                    if ops[i].done < ops[i].len {
+                        pending.push(i);
+                    }
                 }
+            });
+            if let Err(e) = submitted {
+                first_err.get_or_insert(e);
             }
-            if i < self.free_slots.capacity() {
-                // println!("release i = {}", i);
-                self.free_slots.push(i);
-                // release
-                self.iovecs[i].iov_base = std::ptr::null_mut();
-                self.iovecs[i].iov_len = 0;
-                // drop arc buf
-                self.buf[i] = None;
+            if let Some(e) = first_err {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` through the registered buffers, a chunk in each, as
+    /// many chunks per submission as there are buffers.
+    unsafe fn write_fixed(
+        &mut self,
+        target: (RawFd, libc::c_uint),
+        buf: &[u8],
+        offset: LogOffset,
+    ) -> Result<()> {
+        let chunk_size = self.write_buffers[0].len();
+        let chunks: Vec<(LogOffset, &[u8])> = buf
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| (offset + (i * chunk_size) as u64, chunk))
+            .collect();
+        let mut short = vec![];
+        for round in chunks.chunks(self.write_buffers.len().min(self.size)) {
+            let mut first_err = None;
+            let mut prepared = 0;
+            for (b, &(chunk_offset, chunk)) in round.iter().enumerate() {
+                self.write_buffers[b][..chunk.len()].copy_from_slice(chunk);
+                let sqe = match self.get_sqe() {
+                    Ok(sqe) => sqe,
+                    Err(e) => {
+                        first_err = Some(e);
+                        break;
+                    }
+                };
+                let (fd, flags) = target;
+                io_uring_prep_write_fixed(
+                    sqe,
+                    fd,
+                    self.write_buffers[b].as_mut_ptr() as *mut std::ffi::c_void,
+                    u32::try_from(chunk.len()).unwrap(),
+                    off_t::try_from(chunk_offset).unwrap(),
+                    u16::try_from(b).unwrap(),
+                );
+                io_uring_sqe_set_flags(sqe, flags);
+                (*sqe).user_data = u64::try_from(b).unwrap();
+                prepared += 1;
+            }
+
+            let submitted = self.submit_batch(prepared, |b, res| {
+                let (chunk_offset, chunk) = round[b];
+                if res < 0 {
+                    first_err.get_or_insert(os_error(res));
+                    return;
+                }
+                let written = usize::try_from(res).unwrap();
+                if written < chunk.len() {
+                    // The rest goes from the caller buffer.
+                    short.push(Op {
+                        target,
+                        offset: chunk_offset + written as u64,
+                        ptr: chunk[written..].as_ptr() as *mut u8,
+                        len: chunk.len() - written,
+                        done: 0,
+                    });
+                }
+            });
+            if let Err(e) = submitted {
+                first_err.get_or_insert(e);
+            }
+            if let Some(e) = first_err {
+                return Err(e);
             }
-            io_uring_cqe_seen(&mut self.ring, cqe);
         }
+        self.run(&mut short, true)
+    }
+
+    unsafe fn fsync(&mut self, target: (RawFd, libc::c_uint)) -> Result<()> {
+        let sqe = self.get_sqe()?;
+        let (fd, flags) = target;
+        io_uring_prep_fsync(sqe, fd, IORING_FSYNC_DATASYNC);
+        io_uring_sqe_set_flags(sqe, flags);
+        (*sqe).user_data = 0;
+        let mut res = 0;
+        self.submit_batch(1, |_, r| res = r)?;
+        if res < 0 {
+            return Err(os_error(res));
+        }
+        Ok(())
     }
 
     pub fn pwrite_all(
         &mut self,
-        buf: &mut [u8],
-        data: Arc<T>,
+        file: &File,
+        buf: &[u8],
         offset: LogOffset,
         fsync: bool,
     ) -> Result<()> {
+        self.check()?;
+        if !self.takes(file) {
+            file.write_all_at(buf, offset)?;
+            if fsync {
+                file.sync_data()?;
+            }
+            return Ok(());
+        }
+        let target = self.target(file);
         unsafe {
-            // 1. drain completed operations
-            self.drain_cqe()?;
-
-            // it is easier to track queue len if num
-            // of iovecs will be the same as sqes
-            let mut reserve = 1;
+            if self.write_buffers.is_empty() {
+                let mut ops = [Op {
+                    target,
+                    offset,
+                    // only read by the kernel
+                    ptr: buf.as_ptr() as *mut u8,
+                    len: buf.len(),
+                    done: 0,
+                }];
+                self.run(&mut ops, true)?;
+            } else {
+                self.write_fixed(target, buf, offset)?;
+            }
             if fsync {
-                reserve += 1;
+                self.fsync(target)?;
             }
+        }
+        Ok(())
+    }
 
-            // 2. reserve SQE slots
-            while self.free_slots.len() < reserve {
-                let ret = io_uring_submit_and_wait(
-                    &mut self.ring,
-                    libc::c_uint::try_from(reserve).unwrap(),
-                );
-                if ret < 0 {
-                    return Err(Error::Io(io::Error::from_raw_os_error(-ret)));
-                }
-                self.drain_cqe()?;
+    pub fn pread_batch(&mut self, reads: &mut [ReadRequest<'_>]) -> Result<()> {
+        self.check()?;
+        let mut ops = Vec::with_capacity(reads.len());
+        for read in reads.iter_mut() {
+            if !self.takes(read.file) {
+                read.file.read_exact_at(read.buf, read.offset)?;
+                continue;
             }
+            ops.push(Op {
+                target: self.target(read.file),
+                offset: read.offset,
+                ptr: read.buf.as_mut_ptr(),
+                len: read.buf.len(),
+                done: 0,
+            });
+        }
+        unsafe { self.run(&mut ops, false) }
+    }
+}
 
-            // 3. build write() SQE
-            {
-                let sqe = io_uring_get_sqe(&mut self.ring);
-                if sqe.is_null() {
-                    return Err(Error::Io(io::Error::new(
-                        io::ErrorKind::Other,
-                        "unexpected lack of sqes",
-                    )));
-                }
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // `run` and `fsync` reap everything they submit, nothing is in flight.
+        // Exiting also drops the registered files and buffers.
+        unsafe {
+            io_uring_queue_exit(&mut self.ring);
+        };
+    }
+}
 
-                let i = self.free_slots.pop().unwrap();
-                self.buf[i] = Some(data);
-                self.iovecs[i].iov_base = buf.as_mut_ptr() as *mut std::ffi::c_void;
-                self.iovecs[i].iov_len = buf.len();
+/// A `Uring` shared by the threads of a process. Batches take turns, each
+/// is submitted at once.
+pub struct UringEngine {
+    ring: Mutex<Uring>,
+    sqpoll: bool,
+}
 
-                io_uring_prep_writev(
-                    sqe,
-                    // for registered file at index 0
-                    // self.file.as_raw_fd(),
-                    0,
-                    &self.iovecs[i],
-                    1,
-                    off_t::try_from(offset).unwrap(),
-                );
-                io_uring_sqe_set_flags(sqe, IOSQE_FIXED_FILE);
-                (*sqe).user_data = u64::try_from(i).unwrap();
-                // println!("write with i = {}", i);
-            }
+impl UringEngine {
+    pub fn new(cfg: &UringConfig) -> Result<UringEngine> {
+        let ring = Uring::with_config(cfg)?;
+        Ok(UringEngine {
+            sqpoll: ring.sqpoll,
+            ring: Mutex::new(ring),
+        })
+    }
+}
 
-            // 4. build fsync() SQE
-            if fsync {
-                let sqe = io_uring_get_sqe(&mut self.ring);
-                if sqe.is_null() {
-                    return Err(Error::Io(io::Error::new(
-                        io::ErrorKind::Other,
-                        "unexpected lack of sqes",
-                    )));
-                }
+impl IoEngine for UringEngine {
+    fn name(&self) -> &'static str {
+        if self.sqpoll {
+            "io_uring-sqpoll"
+        } else {
+            "io_uring"
+        }
+    }
 
-                let i = self.free_slots.pop().unwrap();
-                // mark explicitly that this is fsync op and it has no data.
-                // drain_cqe must ignore fsyncs that got EINVAL due to the
-                // overlap with the existing executing fsyncs.
-                self.iovecs[i].iov_len = 0;
+    fn syscalls(&self) -> u64 {
+        self.ring.lock().unwrap().enters
+    }
 
-                io_uring_prep_fsync(
-                    sqe,
-                    // for registered file at index 0
-                    // self.file.as_raw_fd(),
-                    0,
-                    IORING_FSYNC_DATASYNC,
-                );
-                // io_uring_sqe_set_flags(sqe, IOSQE_FIXED_FILE);
-                io_uring_sqe_set_flags(sqe, IOSQE_IO_DRAIN | IOSQE_FIXED_FILE);
-                (*sqe).user_data = u64::try_from(i).unwrap();
-                // println!("fsync with i = {}", i);
-            }
+    fn register_file(&self, file: &Arc<File>) {
+        self.ring.lock().unwrap().register_file(file)
+    }
 
-            // 5. submit
-            let ret = io_uring_submit(&mut self.ring);
-            if ret < 0 {
-                return Err(Error::Io(io::Error::from_raw_os_error(-ret)));
-            }
-        };
+    fn unregister_file(&self, file: &Arc<File>) {
+        self.ring.lock().unwrap().unregister_file(file)
+    }
 
-        Ok(())
+    fn pwrite_all(
+        &self,
+        file: &Arc<File>,
+        buf: &[u8],
+        offset: LogOffset,
+        fsync: bool,
+    ) -> Result<()> {
+        self.ring
+            .lock()
+            .unwrap()
+            .pwrite_all(file, buf, offset, fsync)
+    }
+
+    fn pread_batch(&self, reads: &mut [ReadRequest<'_>]) -> Result<()> {
+        self.ring.lock().unwrap().pread_batch(reads)
     }
 }
 
-impl<T> Drop for Uring<T> {
-    fn drop(&mut self) {
-        unsafe {
-            // flush unfinished ops
-            if self.free_slots.len() < self.iovecs.len() {
-                let ret = io_uring_submit_and_wait(
-                    &mut self.ring,
-                    u32::try_from(self.iovecs.len() - self.free_slots.len()).unwrap(),
-                );
-                if ret < 0 {
-                    panic!(Error::Io(io::Error::from_raw_os_error(-ret)));
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            self.drain_cqe().unwrap();
+    use crate::engine::tests::check_engine;
 
-            // auto on exit: io_uring_unregister_files(&mut self.ring);
-            io_uring_queue_exit(&mut self.ring);
+    #[test]
+    fn test_uring_engine() {
+        let plain = UringConfig {
+            fixed_files: 0,
+            write_buffers: 0,
+            ..UringConfig::default()
+        };
+        // Three chunks of the 10000 byte write, over two rounds.
+        let small_buffers = UringConfig {
+            write_buffers: 2,
+            write_buffer_size: 4096,
+            ..UringConfig::default()
         };
+        let sqpoll = UringConfig {
+            sqpoll: true,
+            sqpoll_idle: std::time::Duration::from_millis(10),
+            ..UringConfig::default()
+        };
+        for cfg in &[plain, UringConfig::default(), small_buffers, sqpoll] {
+            // Not every kernel or sandbox lets us in.
+            let engine = match UringEngine::new(cfg) {
+                Ok(engine) => engine,
+                Err(_) => continue,
+            };
+            check_engine(&engine);
+            assert!(engine.syscalls() > 0 || engine.sqpoll);
+        }
     }
 }
@@ -1,6 +1,18 @@
+#[cfg(all(unix, feature = "io_uring"))]
+#[macro_use]
+extern crate slog_global;
+
+mod engine;
 mod io_uring;
+mod log;
 mod result;
 
+pub use crate::engine::{IoEngine, ReadRequest, ThreadPoolIo, UringConfig};
+#[cfg(all(unix, feature = "io_uring"))]
+pub use crate::io_uring::UringEngine;
+pub use crate::log::{CompactStats, LogConfig, LogStore, Meta};
+pub use crate::result::{Error, Result};
+
 pub type LogOffset = u64;
 
 #[cfg(test)]
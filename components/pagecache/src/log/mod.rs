@@ -0,0 +1,643 @@
+//! A log-structured store for small objects. Values are appended to
+//! segments of bounded size instead of getting a file each, an in-memory
+//! index maps every key to its last record, and compaction rewrites the
+//! segments that are mostly dead.
+
+mod segment;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::segment::{Entry, Segment};
+use crate::engine::{self, IoEngine, ReadRequest, UringConfig};
+use crate::{Error, LogOffset, Result};
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    /// Holds the segments and nothing else.
+    pub dir: PathBuf,
+    /// A segment is sealed once the next record would take it over this.
+    pub segment_size: u64,
+    /// Larger values are refused.
+    pub max_value_size: u64,
+    /// Threads doing IO when io_uring is unavailable.
+    pub io_threads: usize,
+    pub uring: UringConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Meta {
+    pub size: u64,
+    /// Unix seconds.
+    pub written: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactStats {
+    /// Segments rewritten and removed.
+    pub segments: usize,
+    /// Bytes of dead records dropped with them.
+    pub reclaimed: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    segment: u64,
+    offset: LogOffset,
+    len: u32,
+    written: u64,
+}
+
+struct Inner {
+    engine: Arc<dyn IoEngine>,
+    dir: PathBuf,
+    segment_size: u64,
+    index: HashMap<String, Location>,
+    // The last one is the active segment, all others are sealed.
+    segments: BTreeMap<u64, Segment>,
+}
+
+pub struct LogStore {
+    engine: Arc<dyn IoEngine>,
+    max_value_size: u64,
+    inner: Mutex<Inner>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl LogStore {
+    /// Opens the segments under `cfg.dir` and rebuilds the index from their
+    /// footers. The segment that was active when the process stopped has
+    /// no footer, its records are scanned up to the first torn one.
+    pub fn open(cfg: LogConfig) -> Result<LogStore> {
+        let engine = engine::open(cfg.io_threads, &cfg.uring)?;
+        LogStore::open_with_engine(cfg, engine)
+    }
+
+    pub fn open_with_engine(cfg: LogConfig, engine: Arc<dyn IoEngine>) -> Result<LogStore> {
+        if cfg.segment_size < cfg.max_value_size + segment::HEADER_SIZE {
+            return Err(Error::Unsupported(
+                "segment size must hold the largest value".into(),
+            ));
+        }
+        fs::create_dir_all(&cfg.dir)?;
+        let mut ids = vec![];
+        for entry in fs::read_dir(&cfg.dir)? {
+            if let Some(id) = entry?.file_name().to_str().and_then(segment::parse_id) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let mut inner = Inner {
+            engine: engine.clone(),
+            dir: cfg.dir,
+            segment_size: cfg.segment_size,
+            index: HashMap::new(),
+            segments: BTreeMap::new(),
+        };
+        for (i, &id) in ids.iter().enumerate() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(segment::path(&inner.dir, id))?;
+            let file_len = file.metadata()?.len();
+            let (entries, size, sealed) = match segment::read_footer(&file, file_len) {
+                Ok(Some((entries, size))) => (entries, size, true),
+                Ok(None) | Err(Error::Corruption(_)) => {
+                    let (entries, size) = segment::scan(&file, file_len)?;
+                    // Appends go on from the last good record.
+                    file.set_len(size)?;
+                    (entries, size, false)
+                }
+                Err(e) => return Err(e),
+            };
+            let file = Arc::new(file);
+            engine.register_file(&file);
+            inner.segments.insert(
+                id,
+                Segment {
+                    id,
+                    file,
+                    size,
+                    live: 0,
+                    tombstones: vec![],
+                    entries: vec![],
+                    sealed,
+                },
+            );
+            for entry in &entries {
+                inner.apply(id, entry);
+            }
+            if !sealed {
+                inner.segments.get_mut(&id).unwrap().entries = entries;
+                if i + 1 < ids.len() {
+                    inner.seal(id)?;
+                }
+            }
+        }
+        let next_id = match inner.segments.values().next_back() {
+            Some(active) if !active.sealed => None,
+            Some(active) => Some(active.id + 1),
+            None => Some(0),
+        };
+        if let Some(id) = next_id {
+            inner.create(id)?;
+        }
+        Ok(LogStore {
+            engine,
+            max_value_size: cfg.max_value_size,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    pub fn max_value_size(&self) -> u64 {
+        self.max_value_size
+    }
+
+    /// `io_uring` or `thread-pool`.
+    pub fn engine_name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_many(&[key])?.pop().unwrap())
+    }
+
+    /// Reads all of `keys` in one batch.
+    pub fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let found: Vec<Option<(Arc<File>, Location)>> = {
+            let inner = self.inner.lock().unwrap();
+            keys.iter()
+                .map(|key| {
+                    let loc = inner.index.get(*key)?;
+                    Some((inner.segments[&loc.segment].file.clone(), *loc))
+                })
+                .collect()
+        };
+        let mut values: Vec<Option<Vec<u8>>> = found
+            .iter()
+            .map(|found| found.as_ref().map(|(_, loc)| vec![0; loc.len as usize]))
+            .collect();
+        // A segment compacted meanwhile is unlinked, but stays readable
+        // through the open file.
+        let mut reads: Vec<ReadRequest<'_>> = found
+            .iter()
+            .zip(keys)
+            .zip(values.iter_mut())
+            .filter_map(|((found, key), value)| {
+                let (file, loc) = found.as_ref()?;
+                Some(ReadRequest {
+                    file,
+                    offset: segment::value_offset(loc.offset, key),
+                    buf: value.as_mut()?.as_mut_slice(),
+                })
+            })
+            .collect();
+        self.engine
+            .pread_batch(&mut reads)
+            .map_err(segment::eof_is_corruption)?;
+        drop(reads);
+        Ok(values)
+    }
+
+    pub fn meta(&self, key: &str) -> Option<Meta> {
+        let inner = self.inner.lock().unwrap();
+        inner.index.get(key).map(|loc| Meta {
+            size: u64::from(loc.len),
+            written: loc.written,
+        })
+    }
+
+    /// Every key with its meta, in no particular order.
+    pub fn metas(&self) -> Vec<(String, Meta)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .index
+            .iter()
+            .map(|(key, loc)| {
+                let meta = Meta {
+                    size: u64::from(loc.len),
+                    written: loc.written,
+                };
+                (key.clone(), meta)
+            })
+            .collect()
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        if value.len() as u64 > self.max_value_size {
+            return Err(Error::Unsupported(format!(
+                "value of {} bytes is over the limit of {}",
+                value.len(),
+                self.max_value_size
+            )));
+        }
+        if key.len() > u16::MAX as usize {
+            return Err(Error::Unsupported("key is too long".into()));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.append(key, value, now(), false)
+    }
+
+    /// Returns false if there was no such key.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.index.contains_key(key) {
+            return Ok(false);
+        }
+        inner.append(key, &[], now(), true)?;
+        Ok(true)
+    }
+
+    /// Rewrites the live records of every sealed segment whose live bytes
+    /// are under `max_live_ratio` of its size, then removes the segment.
+    /// Reads and writes go on meanwhile.
+    pub fn compact(&self, max_live_ratio: f64) -> Result<CompactStats> {
+        let candidates: Vec<u64> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .segments
+                .values()
+                .filter(|s| s.sealed && (s.live as f64) < s.size as f64 * max_live_ratio)
+                .map(|s| s.id)
+                .collect()
+        };
+        let mut stats = CompactStats::default();
+        for id in candidates {
+            stats.reclaimed += self.compact_segment(id)?;
+            stats.segments += 1;
+        }
+        Ok(stats)
+    }
+
+    fn compact_segment(&self, id: u64) -> Result<u64> {
+        let (file, size, keys, tombstones, older) = {
+            let inner = self.inner.lock().unwrap();
+            let seg = match inner.segments.get(&id) {
+                Some(seg) => seg,
+                None => return Ok(0),
+            };
+            let keys: Vec<String> = inner
+                .index
+                .iter()
+                .filter(|(_, loc)| loc.segment == id)
+                .map(|(key, _)| key.clone())
+                .collect();
+            let older: Vec<Arc<File>> = inner
+                .segments
+                .range(..id)
+                .map(|(_, seg)| seg.file.clone())
+                .collect();
+            (
+                seg.file.clone(),
+                seg.size,
+                keys,
+                seg.tombstones.clone(),
+                older,
+            )
+        };
+        let mut copied = 0;
+        // One record at a time and the read without the lock, so writers only
+        // wait for a single append.
+        for key in keys {
+            let loc = {
+                let inner = self.inner.lock().unwrap();
+                match inner.index.get(&key) {
+                    Some(loc) if loc.segment == id => *loc,
+                    _ => continue,
+                }
+            };
+            let value = segment::read_value(
+                &*self.engine,
+                &file,
+                segment::value_offset(loc.offset, &key),
+                loc.len,
+            )?;
+            let mut inner = self.inner.lock().unwrap();
+            // Written again or deleted during the read, the copy is stale.
+            match inner.index.get(&key) {
+                Some(cur) if cur.segment == id && cur.offset == loc.offset => {}
+                _ => continue,
+            }
+            inner.append(&key, &value, loc.written, false)?;
+            copied += segment::record_size(&key, loc.len);
+        }
+        // A tombstone must outlive the older records it hides, unless the key
+        // was written again since. With none left it goes with the segment.
+        let hidden = if tombstones.is_empty() {
+            HashSet::new()
+        } else {
+            older_values(&older)?
+        };
+        for key in tombstones {
+            if !hidden.contains(&key) {
+                continue;
+            }
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.index.contains_key(&key) {
+                inner.append(&key, &[], now(), true)?;
+            }
+        }
+        // The copies must be on disk before the source goes. Segments sealed
+        // meanwhile were synced with their footer, the active one is not.
+        let (active, dir) = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(seg) = inner.segments.remove(&id) {
+                self.engine.unregister_file(&seg.file);
+            }
+            let active = inner
+                .segments
+                .values()
+                .next_back()
+                .map(|seg| seg.file.clone());
+            (active, inner.dir.clone())
+        };
+        if let Some(active) = active {
+            active.sync_data()?;
+        }
+        fs::remove_file(segment::path(&dir, id))?;
+        Ok(size - copied)
+    }
+}
+
+/// Keys with a value record in any of the sealed segments `files`, read
+/// from their footers.
+fn older_values(files: &[Arc<File>]) -> Result<HashSet<String>> {
+    let mut keys = HashSet::new();
+    for file in files {
+        let file_len = file.metadata()?.len();
+        let entries = match segment::read_footer(file, file_len)? {
+            Some((entries, _)) => entries,
+            None => return Err(Error::Corruption("sealed segment without a footer".into())),
+        };
+        keys.extend(entries.into_iter().filter(|e| !e.tombstone).map(|e| e.key));
+    }
+    Ok(keys)
+}
+
+impl Drop for LogStore {
+    fn drop(&mut self) {
+        // The engine may be shared and outlive the store, its slots go back.
+        let inner = self.inner.get_mut().unwrap();
+        for seg in inner.segments.values() {
+            self.engine.unregister_file(&seg.file);
+        }
+    }
+}
+
+impl Inner {
+    /// Points the index at `entry`, keeping the live bytes of segments.
+    fn apply(&mut self, id: u64, entry: &Entry) {
+        let old = if entry.tombstone {
+            self.index.remove(&entry.key)
+        } else {
+            let loc = Location {
+                segment: id,
+                offset: entry.offset,
+                len: entry.len,
+                written: entry.written,
+            };
+            self.index.insert(entry.key.clone(), loc)
+        };
+        if let Some(old) = old {
+            if let Some(seg) = self.segments.get_mut(&old.segment) {
+                seg.live -= segment::record_size(&entry.key, old.len);
+            }
+        }
+        let seg = self.segments.get_mut(&id).unwrap();
+        if entry.tombstone {
+            seg.tombstones.push(entry.key.clone());
+        } else {
+            seg.live += entry.record_size();
+        }
+    }
+
+    fn append(&mut self, key: &str, value: &[u8], written: u64, tombstone: bool) -> Result<()> {
+        let record = segment::encode_record(key, value, written, tombstone);
+        let mut active = *self.segments.keys().next_back().unwrap();
+        let size = self.segments[&active].size;
+        if size > 0 && size + record.len() as u64 > self.segment_size {
+            self.seal(active)?;
+            active = self.create(active + 1)?;
+        }
+        let seg = self.segments.get_mut(&active).unwrap();
+        let offset = seg.size;
+        self.engine.pwrite_all(&seg.file, &record, offset, false)?;
+        seg.size += record.len() as u64;
+        let entry = Entry {
+            key: key.to_owned(),
+            offset,
+            len: value.len() as u32,
+            written,
+            tombstone,
+        };
+        seg.entries.push(entry.clone());
+        self.apply(active, &entry);
+        Ok(())
+    }
+
+    fn seal(&mut self, id: u64) -> Result<()> {
+        let seg = self.segments.get_mut(&id).unwrap();
+        let footer = segment::encode_footer(&seg.entries, seg.size);
+        self.engine.pwrite_all(&seg.file, &footer, seg.size, true)?;
+        seg.entries = vec![];
+        seg.sealed = true;
+        Ok(())
+    }
+
+    fn create(&mut self, id: u64) -> Result<u64> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(segment::path(&self.dir, id))?;
+        let file = Arc::new(file);
+        self.engine.register_file(&file);
+        self.segments.insert(
+            id,
+            Segment {
+                id,
+                file,
+                size: 0,
+                live: 0,
+                tombstones: vec![],
+                entries: vec![],
+                sealed: false,
+            },
+        );
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::path::Path;
+
+    // Records of a 4 byte key and a 40 byte value take 64 bytes, so three
+    // fit in a segment.
+    fn open(dir: &Path) -> LogStore {
+        LogStore::open(LogConfig {
+            dir: dir.to_path_buf(),
+            segment_size: 200,
+            max_value_size: 64,
+            io_threads: 2,
+            uring: UringConfig::default(),
+        })
+        .unwrap()
+    }
+
+    fn value(i: u8) -> Vec<u8> {
+        vec![i; 40]
+    }
+
+    fn segment_ids(store: &LogStore) -> Vec<u64> {
+        let inner = store.inner.lock().unwrap();
+        inner.segments.keys().copied().collect()
+    }
+
+    fn tombstones(store: &LogStore) -> usize {
+        let inner = store.inner.lock().unwrap();
+        inner.segments.values().map(|s| s.tombstones.len()).sum()
+    }
+
+    #[test]
+    fn test_put_get_delete_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path());
+        for i in 0..10 {
+            store.put(&format!("ac/{}", i), &value(i)).unwrap();
+        }
+        store.put("ac/3", &value(33)).unwrap();
+        assert!(store.delete("ac/4").unwrap());
+        assert!(!store.delete("ac/4").unwrap());
+        assert!(store.put("ac/big", &[0; 65]).is_err());
+        assert!(segment_ids(&store).len() > 3);
+
+        let check = |store: &LogStore| {
+            assert_eq!(store.get("ac/0").unwrap(), Some(value(0)));
+            assert_eq!(store.get("ac/3").unwrap(), Some(value(33)));
+            assert_eq!(store.get("ac/4").unwrap(), None);
+            assert_eq!(store.get("ac/9").unwrap(), Some(value(9)));
+            assert_eq!(store.meta("ac/9").unwrap().size, 40);
+            assert_eq!(store.metas().len(), 9);
+        };
+        check(&store);
+        drop(store);
+        // Sealed segments come back from their footers, the active one from
+        // its records.
+        let store = open(dir.path());
+        check(&store);
+        store.put("ac/10", &value(10)).unwrap();
+        assert_eq!(store.get("ac/10").unwrap(), Some(value(10)));
+    }
+
+    #[test]
+    fn test_get_many() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = LogConfig {
+            dir: dir.path().to_path_buf(),
+            segment_size: 200,
+            max_value_size: 64,
+            io_threads: 2,
+            uring: UringConfig::default(),
+        };
+        let engine = Arc::new(engine::ThreadPoolIo::new(2).unwrap());
+        let store = LogStore::open_with_engine(cfg, engine).unwrap();
+        assert_eq!(store.engine_name(), "thread-pool");
+        for i in 0..5 {
+            store.put(&format!("ac/{}", i), &value(i)).unwrap();
+        }
+        // Spans segments, with a miss in the middle.
+        let values = store.get_many(&["ac/4", "ac/9", "ac/0", "ac/2"]).unwrap();
+        assert_eq!(
+            values,
+            vec![Some(value(4)), None, Some(value(0)), Some(value(2))]
+        );
+        assert!(store.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path());
+        store.put("ac/0", &value(0)).unwrap();
+        store.put("ac/1", &value(1)).unwrap();
+        let active = *segment_ids(&store).last().unwrap();
+        drop(store);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment::path(dir.path(), active))
+            .unwrap();
+        // Half a header, as left by a crash in the middle of a write.
+        file.write_all(&[7; 12]).unwrap();
+        drop(file);
+
+        let store = open(dir.path());
+        assert_eq!(store.get("ac/1").unwrap(), Some(value(1)));
+        store.put("ac/2", &value(2)).unwrap();
+        drop(store);
+        let store = open(dir.path());
+        assert_eq!(store.metas().len(), 3);
+        assert_eq!(store.get("ac/2").unwrap(), Some(value(2)));
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path());
+        // Segment 0 ends up two thirds live.
+        for i in 0..3 {
+            store.put(&format!("ac/{}", i), &value(i)).unwrap();
+        }
+        store.delete("ac/0").unwrap();
+        // Segment 1 holds the tombstone of ac/0 and nothing live.
+        store.put("ac/3", &value(3)).unwrap();
+        store.put("ac/3", &value(3)).unwrap();
+        store.put("ac/3", &value(3)).unwrap();
+        assert_eq!(segment_ids(&store), vec![0, 1, 2]);
+
+        let stats = store.compact(0.5).unwrap();
+        assert_eq!(stats.segments, 1);
+        assert_eq!(stats.reclaimed, 24 + 64 + 64);
+        assert_eq!(segment_ids(&store), vec![0, 2]);
+        assert!(!segment::path(dir.path(), 1).exists());
+
+        // The tombstone moved along, segment 0 still has the old ac/0.
+        drop(store);
+        let store = open(dir.path());
+        assert_eq!(store.get("ac/0").unwrap(), None);
+        for i in 1..4 {
+            assert_eq!(store.get(&format!("ac/{}", i)).unwrap(), Some(value(i)));
+        }
+
+        store.delete("ac/1").unwrap();
+        store.delete("ac/2").unwrap();
+        // Seals the tombstones of ac/1 and ac/2.
+        for _ in 0..3 {
+            store.put("ac/3", &value(3)).unwrap();
+        }
+        assert!(tombstones(&store) >= 2);
+        // Segment 0 goes first, after it no tombstone hides anything.
+        store.compact(1.0).unwrap();
+        assert_eq!(tombstones(&store), 0);
+        assert_eq!(store.metas().len(), 1);
+        drop(store);
+        let store = open(dir.path());
+        assert_eq!(store.metas().len(), 1);
+        assert_eq!(store.get("ac/3").unwrap(), Some(value(3)));
+    }
+}
@@ -0,0 +1,252 @@
+//! On-disk layout of a segment. Records are appended one after another, and
+//! a sealed segment ends with a footer listing them, so the index is rebuilt
+//! from footers without reading the values back:
+//!
+//! ```text
+//! record:  crc u32 | flags u16 | key_len u16 | value_len u32 | written u64 | key | value
+//! entry:   flags u16 | key_len u16 | value_len u32 | offset u64 | written u64 | key
+//! footer:  entry... | footer_offset u64 | entry_count u32 | crc u32 | MAGIC u64
+//! ```
+//!
+//! Integers are little endian. The crc of a record covers everything after
+//! it, the crc of the footer covers its entries.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::engine::IoEngine;
+use crate::{Error, LogOffset, Result};
+
+pub(crate) const HEADER_SIZE: u64 = 20;
+const ENTRY_SIZE: usize = 24;
+const TRAILER_SIZE: u64 = 24;
+const MAGIC: u64 = 0x4748_4c4f_4753_4547; // "GHLOGSEG"
+
+const FLAG_TOMBSTONE: u16 = 1;
+
+/// One record of a segment, as listed by its footer.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub key: String,
+    /// Where the record starts.
+    pub offset: LogOffset,
+    pub len: u32,
+    /// Unix seconds.
+    pub written: u64,
+    pub tombstone: bool,
+}
+
+impl Entry {
+    pub fn record_size(&self) -> u64 {
+        record_size(&self.key, self.len)
+    }
+}
+
+pub(crate) fn record_size(key: &str, len: u32) -> u64 {
+    HEADER_SIZE + key.len() as u64 + u64::from(len)
+}
+
+/// Where the value of a record starts.
+pub(crate) fn value_offset(offset: LogOffset, key: &str) -> LogOffset {
+    offset + HEADER_SIZE + key.len() as u64
+}
+
+pub(crate) struct Segment {
+    pub id: u64,
+    pub file: Arc<File>,
+    /// Bytes of records, the footer excluded.
+    pub size: u64,
+    /// Bytes of records the index still points to.
+    pub live: u64,
+    /// Keys deleted by this segment, which must outlive older segments.
+    pub tombstones: Vec<String>,
+    /// Records written since the segment was opened, for the footer. Empty
+    /// once sealed.
+    pub entries: Vec<Entry>,
+    pub sealed: bool,
+}
+
+pub(crate) fn path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016x}.seg", id))
+}
+
+/// The id of a segment file, `None` for anything else.
+pub(crate) fn parse_id(name: &str) -> Option<u64> {
+    let id = name.strip_suffix(".seg")?;
+    if id.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(id, 16).ok()
+}
+
+pub(crate) fn encode_record(key: &str, value: &[u8], written: u64, tombstone: bool) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE as usize + key.len() + value.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&flags(tombstone).to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&written.to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(value);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+pub(crate) fn encode_footer(entries: &[Entry], footer_offset: LogOffset) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(entries.len() * (ENTRY_SIZE + 48) + TRAILER_SIZE as usize);
+    for entry in entries {
+        buf.extend_from_slice(&flags(entry.tombstone).to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        buf.extend_from_slice(&entry.written.to_le_bytes());
+        buf.extend_from_slice(entry.key.as_bytes());
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&footer_offset.to_le_bytes());
+    buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(&MAGIC.to_le_bytes());
+    buf
+}
+
+/// Reads the footer of a sealed segment, returns its entries and where the
+/// records end. `None` if the segment was never sealed.
+pub(crate) fn read_footer(file: &File, file_len: u64) -> Result<Option<(Vec<Entry>, u64)>> {
+    if file_len < TRAILER_SIZE {
+        return Ok(None);
+    }
+    let mut trailer = [0; TRAILER_SIZE as usize];
+    file.read_exact_at(&mut trailer, file_len - TRAILER_SIZE)?;
+    if u64_at(&trailer, 16) != MAGIC {
+        return Ok(None);
+    }
+    let footer_offset = u64_at(&trailer, 0);
+    let count = u32_at(&trailer, 8);
+    let crc = u32_at(&trailer, 12);
+    if footer_offset > file_len - TRAILER_SIZE {
+        return Err(Error::Corruption("footer offset out of the segment".into()));
+    }
+    let mut footer = vec![0; (file_len - TRAILER_SIZE - footer_offset) as usize];
+    file.read_exact_at(&mut footer, footer_offset)?;
+    if crc32fast::hash(&footer) != crc {
+        return Err(Error::Corruption("footer checksum mismatch".into()));
+    }
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut pos = 0;
+    for _ in 0..count {
+        if pos + ENTRY_SIZE > footer.len() {
+            return Err(Error::Corruption("footer entry out of the footer".into()));
+        }
+        let tombstone = u16_at(&footer, pos) & FLAG_TOMBSTONE != 0;
+        let key_len = u16_at(&footer, pos + 2) as usize;
+        let len = u32_at(&footer, pos + 4);
+        let offset = u64_at(&footer, pos + 8);
+        let written = u64_at(&footer, pos + 16);
+        pos += ENTRY_SIZE;
+        let key = footer
+            .get(pos..pos + key_len)
+            .ok_or_else(|| Error::Corruption("footer key out of the footer".into()))?;
+        pos += key_len;
+        entries.push(Entry {
+            key: decode_key(key)?,
+            offset,
+            len,
+            written,
+            tombstone,
+        });
+    }
+    Ok(Some((entries, footer_offset)))
+}
+
+/// Reads the records of a segment that was never sealed, up to the first
+/// one that is torn or fails its checksum. Returns them and where they end.
+pub(crate) fn scan(file: &File, file_len: u64) -> Result<(Vec<Entry>, u64)> {
+    let mut entries = vec![];
+    let mut offset = 0;
+    while offset + HEADER_SIZE <= file_len {
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, offset)?;
+        let key_len = u16_at(&header, 6) as u64;
+        let len = u32_at(&header, 8);
+        let end = offset + HEADER_SIZE + key_len + u64::from(len);
+        if end > file_len {
+            break;
+        }
+        let mut body = vec![0; (end - offset - HEADER_SIZE) as usize];
+        file.read_exact_at(&mut body, offset + HEADER_SIZE)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        if hasher.finalize() != u32_at(&header, 0) {
+            break;
+        }
+        let key = match decode_key(&body[..key_len as usize]) {
+            Ok(key) => key,
+            Err(_) => break,
+        };
+        entries.push(Entry {
+            key,
+            offset,
+            len,
+            written: u64_at(&header, 12),
+            tombstone: u16_at(&header, 4) & FLAG_TOMBSTONE != 0,
+        });
+        offset = end;
+    }
+    Ok((entries, offset))
+}
+
+/// Reads `len` bytes of value at `offset`.
+pub(crate) fn read_value(
+    engine: &dyn IoEngine,
+    file: &Arc<File>,
+    offset: LogOffset,
+    len: u32,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    engine
+        .pread_exact(file, &mut buf, offset)
+        .map_err(eof_is_corruption)?;
+    Ok(buf)
+}
+
+/// The index only points inside segments, a short read means a segment
+/// was cut.
+pub(crate) fn eof_is_corruption(e: Error) -> Error {
+    match e {
+        Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Error::Corruption("value out of the segment".into())
+        }
+        e => e,
+    }
+}
+
+fn flags(tombstone: bool) -> u16 {
+    if tombstone {
+        FLAG_TOMBSTONE
+    } else {
+        0
+    }
+}
+
+fn decode_key(key: &[u8]) -> Result<String> {
+    String::from_utf8(key.to_vec()).map_err(|_| Error::Corruption("key is not utf-8".into()))
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
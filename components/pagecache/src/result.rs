@@ -1,3 +1,6 @@
+use std::fmt;
+use std::io;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -6,4 +9,44 @@ pub enum Error {
     Unsupported(String),
     /// An unexpected bug has happened. Please open an issue on github!
     ReportableBug(String),
+    /// A read or write of the underlying files failed.
+    Io(io::Error),
+    /// A segment does not hold what its checksums say.
+    Corruption(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsupported(e) => write!(f, "unsupported: {}", e),
+            Error::ReportableBug(e) => write!(f, "unexpected bug: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Corruption(e) => write!(f, "corruption: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            Error::Unsupported(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
 }
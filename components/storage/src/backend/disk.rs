@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use walkdir::WalkDir;
+
+use super::{Backend, ByteStream, Stat};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Tells apart the temporary files of writes in flight.
+static TMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps every blob in a file under `root`, named by its key.
+#[derive(Clone)]
+pub struct DiskBackend {
+    root: Arc<PathBuf>,
+}
+
+impl DiskBackend {
+    pub fn new(root: impl Into<PathBuf>) -> DiskBackend {
+        DiskBackend {
+            root: Arc::new(root.into()),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl Backend for DiskBackend {
+    fn stat(&self, key: &str) -> BoxFuture<'static, io::Result<Stat>> {
+        let p = self.path(key);
+        async move {
+            let metadata = fs::metadata(&p).await?;
+            Ok(Stat {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })
+        }
+        .boxed()
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<ByteStream>> {
+        let p = self.path(key);
+        async move {
+            let file = File::open(&p).await?;
+            let chunks = stream::try_unfold(file, |mut file| async move {
+                let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+                if file.read_buf(&mut buf).await? == 0 {
+                    return Ok(None);
+                }
+                Ok(Some((buf.freeze(), file)))
+            });
+            Ok(chunks.boxed())
+        }
+        .boxed()
+    }
+
+    fn put(&self, key: &str, data: ByteStream) -> BoxFuture<'static, io::Result<()>> {
+        let p = self.path(key);
+        async move {
+            let p_parent = p.parent().unwrap();
+            if fs::metadata(p_parent).await.is_err() {
+                fs::create_dir_all(p_parent).await?;
+            }
+            // Write aside and rename, so readers and the CAS existence check
+            // never see a half written blob.
+            let tmp = tmp_path(&p);
+            let res = match write_file(&tmp, data).await {
+                Ok(()) => fs::rename(&tmp, &p).await,
+                Err(e) => Err(e),
+            };
+            if res.is_err() {
+                let _ = fs::remove_file(&tmp).await;
+            }
+            res
+        }
+        .boxed()
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, io::Result<()>> {
+        let p = self.path(key);
+        async move { fs::remove_file(p).await }.boxed()
+    }
+
+    fn list(&self, prefix: &str) -> BoxFuture<'static, io::Result<Vec<String>>> {
+        let root = self.root.clone();
+        let prefix = prefix.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || list_files(&root, &prefix))
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        }
+        .boxed()
+    }
+}
+
+async fn write_file(p: &Path, mut data: ByteStream) -> io::Result<()> {
+    let mut file = File::create(p).await?;
+    while let Some(chunk) = data.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await
+}
+
+/// A name next to `p` no other write uses, in this process or another one
+/// sharing the directory.
+fn tmp_path(p: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(p.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    p.with_file_name(name)
+}
+
+fn list_files(root: &Path, prefix: &str) -> io::Result<Vec<String>> {
+    // Only walk the directory the prefix points into.
+    let dir = match prefix.rfind('/') {
+        Some(i) => root.join(&prefix[..i]),
+        None => root.to_path_buf(),
+    };
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut keys = vec![];
+    // Blobs being written start with a dot, so do directories that hold no
+    // blob files, such as the log store.
+    let walk = WalkDir::new(&dir)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'));
+    for entry in walk {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = match entry.path().strip_prefix(root) {
+            Ok(rel) => rel,
+            Err(_) => continue,
+        };
+        let key = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if key.starts_with(prefix) {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::backend::{once, read_all};
+
+    #[tokio::test]
+    async fn test_disk_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = DiskBackend::new(dir.path());
+        let blob = vec![7u8; CHUNK_SIZE * 2 + 1];
+        backend.put("ac/1", once(blob.clone())).await.unwrap();
+        backend.put("cas/2", once("x")).await.unwrap();
+        std::fs::create_dir(dir.path().join(".log")).unwrap();
+        std::fs::write(dir.path().join(".log/0.seg"), "x").unwrap();
+
+        assert_eq!(backend.stat("ac/1").await.unwrap().size, blob.len() as u64);
+        assert!(!backend.exists("ac/3").await.unwrap());
+        let data = read_all(backend.get("ac/1").await.unwrap()).await.unwrap();
+        assert_eq!(data, blob);
+
+        let mut keys = backend.list("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["ac/1", "cas/2"]);
+        assert_eq!(backend.list("cas/").await.unwrap(), vec!["cas/2"]);
+        assert!(backend.list("other/").await.unwrap().is_empty());
+
+        backend.delete("ac/1").await.unwrap();
+        assert!(!backend.exists("ac/1").await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = DiskBackend::new(dir.path());
+        // Every blob is made of one byte, a mix of two writes would show.
+        let puts = (0..16u8).map(|i| {
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                let blob = vec![i; CHUNK_SIZE * 4];
+                backend.put("cas/1", once(blob)).await
+            })
+        });
+        for put in futures::future::join_all(puts).await {
+            put.unwrap().unwrap();
+        }
+
+        let data = read_all(backend.get("cas/1").await.unwrap()).await.unwrap();
+        assert_eq!(data.len(), CHUNK_SIZE * 4);
+        assert!(data.iter().all(|b| *b == data[0]));
+        // No temporary file is left behind.
+        let names: Vec<_> = std::fs::read_dir(dir.path().join("cas"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["1"]);
+    }
+}
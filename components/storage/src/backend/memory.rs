@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::StreamExt;
+use tokio::io;
+
+use super::{once, Backend, ByteStream, Stat};
+
+fn not_found(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", key))
+}
+
+/// Keeps blobs in memory, for tests and throwaway instances.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    blobs: Arc<RwLock<BTreeMap<String, (Bytes, SystemTime)>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn stat(&self, key: &str) -> BoxFuture<'static, io::Result<Stat>> {
+        let res = match self.blobs.read().unwrap().get(key) {
+            Some((data, modified)) => Ok(Stat {
+                size: data.len() as u64,
+                modified: Some(*modified),
+            }),
+            None => Err(not_found(key)),
+        };
+        future::ready(res).boxed()
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<ByteStream>> {
+        let res = match self.blobs.read().unwrap().get(key) {
+            Some((data, _)) => Ok(once(data.clone())),
+            None => Err(not_found(key)),
+        };
+        future::ready(res).boxed()
+    }
+
+    fn put(&self, key: &str, mut data: ByteStream) -> BoxFuture<'static, io::Result<()>> {
+        let blobs = self.blobs.clone();
+        let key = key.to_owned();
+        async move {
+            let mut buf = BytesMut::new();
+            while let Some(chunk) = data.next().await {
+                buf.extend_from_slice(&chunk?);
+            }
+            blobs
+                .write()
+                .unwrap()
+                .insert(key, (buf.freeze(), SystemTime::now()));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, io::Result<()>> {
+        let res = match self.blobs.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(not_found(key)),
+        };
+        future::ready(res).boxed()
+    }
+
+    fn list(&self, prefix: &str) -> BoxFuture<'static, io::Result<Vec<String>>> {
+        let keys = self
+            .blobs
+            .read()
+            .unwrap()
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        future::ready(Ok(keys)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream;
+
+    use crate::backend::read_all;
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        let backend = MemoryBackend::new();
+        let chunks = vec![Ok(Bytes::from("he")), Ok(Bytes::from("llo"))];
+        backend
+            .put("ac/1", stream::iter(chunks).boxed())
+            .await
+            .unwrap();
+        backend.put("cas/2", once("x")).await.unwrap();
+
+        assert_eq!(backend.stat("ac/1").await.unwrap().size, 5);
+        assert!(backend.exists("cas/2").await.unwrap());
+        assert!(!backend.exists("cas/3").await.unwrap());
+        let data = read_all(backend.get("ac/1").await.unwrap()).await.unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(backend.list("ac/").await.unwrap(), vec!["ac/1"]);
+
+        // A failed upload leaves nothing behind.
+        let failing = stream::iter(vec![
+            Ok(Bytes::from("a")),
+            Err(io::Error::new(io::ErrorKind::Other, "reset")),
+        ]);
+        assert!(backend.put("ac/4", failing.boxed()).await.is_err());
+        assert!(!backend.exists("ac/4").await.unwrap());
+
+        backend.delete("ac/1").await.unwrap();
+        assert_eq!(
+            backend.get("ac/1").await.err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
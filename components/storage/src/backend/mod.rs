@@ -0,0 +1,70 @@
+//! Where blobs are kept. A backend only moves bytes, compression, scheduling,
+//! coalescing and metrics stay in `Storage` above it.
+
+mod disk;
+mod memory;
+mod s3;
+mod tiered;
+
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::io;
+
+pub use self::disk::DiskBackend;
+pub use self::memory::MemoryBackend;
+pub use self::s3::S3Backend;
+pub use self::tiered::TieredBackend;
+
+/// The content of a blob, in chunks.
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Turns a whole blob into a `ByteStream`.
+pub fn once(data: impl Into<Bytes>) -> ByteStream {
+    stream::once(future::ready(Ok(data.into()))).boxed()
+}
+
+/// Collects a `ByteStream` into one buffer.
+pub async fn read_all(mut data: ByteStream) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = data.next().await {
+        buf.extend_from_slice(&chunk?);
+    }
+    Ok(buf)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stat {
+    /// Size as stored, that is after compression.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Blob keys look like `ac/<digest>`, relative and `/` separated. Operations
+/// on a missing key fail with `NotFound`.
+pub trait Backend: Send + Sync {
+    fn stat(&self, key: &str) -> BoxFuture<'static, io::Result<Stat>>;
+
+    fn exists(&self, key: &str) -> BoxFuture<'static, io::Result<bool>> {
+        self.stat(key)
+            .map(|res| match res {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            })
+            .boxed()
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<ByteStream>>;
+
+    /// Replaces the blob once `data` is complete, readers never see part of
+    /// it. Nothing is stored if `data` fails.
+    fn put(&self, key: &str, data: ByteStream) -> BoxFuture<'static, io::Result<()>>;
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, io::Result<()>>;
+
+    /// Keys starting with `prefix`, in no particular order.
+    fn list(&self, prefix: &str) -> BoxFuture<'static, io::Result<Vec<String>>>;
+}
@@ -0,0 +1,271 @@
+use bytes::{Bytes, BytesMut};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::StreamExt;
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::serde_types::Part;
+use s3::Region;
+use tokio::io;
+
+use super::{once, Backend, ByteStream, Stat};
+use crate::config::S3Config;
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+fn to_io_error(e: S3Error) -> io::Error {
+    match e {
+        S3Error::Http(404, _) => io::Error::new(io::ErrorKind::NotFound, "no such key"),
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Keeps blobs in an S3 compatible bucket, as objects named `prefix + key`.
+#[derive(Clone)]
+pub struct S3Backend {
+    bucket: Bucket,
+    prefix: String,
+    part_size: usize,
+}
+
+impl S3Backend {
+    pub fn new(cfg: &S3Config) -> io::Result<S3Backend> {
+        let region = Region::Custom {
+            region: cfg.region.clone(),
+            endpoint: cfg.endpoint.clone(),
+        };
+        let credentials = Credentials::new(
+            Some(&cfg.access_key),
+            Some(&cfg.secret_key),
+            None,
+            None,
+            None,
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut bucket = Bucket::new(&cfg.bucket, region, credentials).map_err(to_io_error)?;
+        if cfg.path_style {
+            bucket = bucket.with_path_style();
+        }
+        let mut prefix = cfg.prefix.trim_start_matches('/').to_owned();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        Ok(S3Backend {
+            bucket,
+            prefix,
+            part_size: cfg.part_size.0 as usize,
+        })
+    }
+
+    fn object(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    async fn head(bucket: &Bucket, object: &str) -> io::Result<Stat> {
+        let (head, code) = bucket.head_object(object).await.map_err(to_io_error)?;
+        if code == 404 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such key"));
+        }
+        Ok(Stat {
+            size: head.content_length.unwrap_or(0) as u64,
+            modified: None,
+        })
+    }
+
+    /// Uploads the parts after `first`, which is already `part_size` long.
+    async fn put_multipart(
+        &self,
+        object: &str,
+        first: Vec<u8>,
+        data: &mut ByteStream,
+    ) -> io::Result<()> {
+        let upload = self
+            .bucket
+            .initiate_multipart_upload(object, CONTENT_TYPE)
+            .await
+            .map_err(to_io_error)?;
+        let res = self
+            .upload_parts(object, &upload.upload_id, first, data)
+            .await;
+        match res {
+            Ok(parts) => self
+                .bucket
+                .complete_multipart_upload(object, &upload.upload_id, parts)
+                .await
+                .map(|_| ())
+                .map_err(to_io_error),
+            Err(e) => {
+                // Parts of an unfinished upload are billed until aborted.
+                if let Err(abort) = self.bucket.abort_upload(object, &upload.upload_id).await {
+                    warn!("failed to abort multipart upload"; "object" => object, "err" => %abort);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        data: &mut ByteStream,
+    ) -> io::Result<Vec<Part>> {
+        let mut parts = vec![];
+        let mut part = first;
+        loop {
+            let next = fill(data, self.part_size).await?;
+            let part_number = parts.len() as u32 + 1;
+            parts.push(
+                self.bucket
+                    .put_multipart_chunk(part, object, part_number, upload_id, CONTENT_TYPE)
+                    .await
+                    .map_err(to_io_error)?,
+            );
+            match next {
+                Some(next) => part = next,
+                None => return Ok(parts),
+            }
+        }
+    }
+}
+
+/// Reads up to `size` bytes, `None` when `data` is already drained.
+async fn fill(data: &mut ByteStream, size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = BytesMut::new();
+    while buf.len() < size {
+        match data.next().await {
+            Some(chunk) => buf.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(buf.to_vec()))
+}
+
+impl Backend for S3Backend {
+    fn stat(&self, key: &str) -> BoxFuture<'static, io::Result<Stat>> {
+        let bucket = self.bucket.clone();
+        let object = self.object(key);
+        async move { S3Backend::head(&bucket, &object).await }.boxed()
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<ByteStream>> {
+        let bucket = self.bucket.clone();
+        let object = self.object(key);
+        async move {
+            let res = bucket.get_object(&object).await.map_err(to_io_error)?;
+            Ok(once(Bytes::copy_from_slice(res.bytes())))
+        }
+        .boxed()
+    }
+
+    fn put(&self, key: &str, mut data: ByteStream) -> BoxFuture<'static, io::Result<()>> {
+        let backend = self.clone();
+        let object = self.object(key);
+        async move {
+            // Blobs that fit in one part go up in a single request.
+            let first = fill(&mut data, backend.part_size)
+                .await?
+                .unwrap_or_default();
+            if first.len() < backend.part_size {
+                return backend
+                    .bucket
+                    .put_object(&object, &first)
+                    .await
+                    .map(|_| ())
+                    .map_err(to_io_error);
+            }
+            backend.put_multipart(&object, first, &mut data).await
+        }
+        .boxed()
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, io::Result<()>> {
+        let bucket = self.bucket.clone();
+        let object = self.object(key);
+        async move {
+            // S3 deletes missing objects happily, tell them apart first.
+            S3Backend::head(&bucket, &object).await?;
+            bucket
+                .delete_object(&object)
+                .await
+                .map(|_| ())
+                .map_err(to_io_error)
+        }
+        .boxed()
+    }
+
+    fn list(&self, prefix: &str) -> BoxFuture<'static, io::Result<Vec<String>>> {
+        let bucket = self.bucket.clone();
+        let strip = self.prefix.clone();
+        let prefix = self.object(prefix);
+        async move {
+            let pages = bucket.list(prefix, None).await.map_err(to_io_error)?;
+            Ok(pages
+                .into_iter()
+                .flat_map(|page| page.contents)
+                .filter_map(|object| object.key.strip_prefix(&strip).map(ToOwned::to_owned))
+                .collect())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use cibo_util::config::ReadableSize;
+
+    use crate::backend::read_all;
+
+    fn test_backend() -> S3Backend {
+        let endpoint = env::var("GREENHOUSE_TEST_S3_ENDPOINT")
+            .expect("set GREENHOUSE_TEST_S3_ENDPOINT to run the S3 tests");
+        let var = |name, default: &str| env::var(name).unwrap_or_else(|_| default.to_owned());
+        let cfg = S3Config {
+            endpoint,
+            bucket: var("GREENHOUSE_TEST_S3_BUCKET", "greenhouse-test"),
+            access_key: var("GREENHOUSE_TEST_S3_ACCESS_KEY", "minioadmin"),
+            secret_key: var("GREENHOUSE_TEST_S3_SECRET_KEY", "minioadmin"),
+            prefix: format!("test-{}/", std::process::id()),
+            part_size: ReadableSize::mb(5),
+            ..S3Config::default()
+        };
+        S3Backend::new(&cfg).unwrap()
+    }
+
+    // This needs a running S3 service, such as a local MinIO started by
+    // `docker run -p 9000:9000 minio/minio server /data`. Point
+    // `GREENHOUSE_TEST_S3_ENDPOINT` (and optionally the `_BUCKET`,
+    // `_ACCESS_KEY` and `_SECRET_KEY` variables) at it and pass
+    // `-- --ignored` to `cargo test -p storage`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_s3_backend() {
+        let backend = test_backend();
+        backend.put("ac/1", once("small")).await.unwrap();
+        // Over one part, goes through multipart upload.
+        let large = vec![3u8; 5 * 1024 * 1024 + 7];
+        backend.put("cas/2", once(large.clone())).await.unwrap();
+
+        assert_eq!(backend.stat("ac/1").await.unwrap().size, 5);
+        let data = read_all(backend.get("cas/2").await.unwrap()).await.unwrap();
+        assert_eq!(data, large);
+        assert_eq!(backend.list("ac/").await.unwrap(), vec!["ac/1"]);
+
+        for key in &["ac/1", "cas/2"] {
+            backend.delete(key).await.unwrap();
+        }
+        assert!(!backend.exists("ac/1").await.unwrap());
+        assert_eq!(
+            backend.delete("ac/1").await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use tokio::io;
+
+use super::{once, read_all, Backend, ByteStream, Stat};
+
+/// A remote backend with a local cache in front. Writes go through to both,
+/// reads missing in the cache fill it from the remote. Nothing is lost when
+/// the cache drops blobs, which is how lazygc keeps a disk cache bounded.
+#[derive(Clone)]
+pub struct TieredBackend {
+    cache: Arc<dyn Backend>,
+    remote: Arc<dyn Backend>,
+}
+
+impl TieredBackend {
+    pub fn new(cache: Arc<dyn Backend>, remote: Arc<dyn Backend>) -> TieredBackend {
+        TieredBackend { cache, remote }
+    }
+}
+
+fn is_not_found(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound
+}
+
+impl Backend for TieredBackend {
+    fn stat(&self, key: &str) -> BoxFuture<'static, io::Result<Stat>> {
+        let this = self.clone();
+        let key = key.to_owned();
+        async move {
+            match this.cache.stat(&key).await {
+                Err(e) if is_not_found(&e) => this.remote.stat(&key).await,
+                res => res,
+            }
+        }
+        .boxed()
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<ByteStream>> {
+        let this = self.clone();
+        let key = key.to_owned();
+        async move {
+            match this.cache.get(&key).await {
+                Err(e) if is_not_found(&e) => {}
+                res => return res,
+            }
+            let data = Bytes::from(read_all(this.remote.get(&key).await?).await?);
+            if let Err(e) = this.cache.put(&key, once(data.clone())).await {
+                warn!("failed to fill cache"; "key" => &key, "err" => %e);
+            }
+            Ok(once(data))
+        }
+        .boxed()
+    }
+
+    fn put(&self, key: &str, data: ByteStream) -> BoxFuture<'static, io::Result<()>> {
+        let this = self.clone();
+        let key = key.to_owned();
+        async move {
+            // Kept whole to write it twice, `Storage` holds it in memory anyway.
+            let data = Bytes::from(read_all(data).await?);
+            this.remote.put(&key, once(data.clone())).await?;
+            if let Err(e) = this.cache.put(&key, once(data)).await {
+                warn!("failed to fill cache"; "key" => &key, "err" => %e);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, io::Result<()>> {
+        let this = self.clone();
+        let key = key.to_owned();
+        async move {
+            match this.cache.delete(&key).await {
+                Err(e) if !is_not_found(&e) => return Err(e),
+                _ => {}
+            }
+            this.remote.delete(&key).await
+        }
+        .boxed()
+    }
+
+    fn list(&self, prefix: &str) -> BoxFuture<'static, io::Result<Vec<String>>> {
+        self.remote.list(prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::backend::MemoryBackend;
+
+    #[tokio::test]
+    async fn test_tiered_backend() {
+        let cache = Arc::new(MemoryBackend::new());
+        let remote = Arc::new(MemoryBackend::new());
+        let tiered = TieredBackend::new(cache.clone(), remote.clone());
+
+        tiered.put("ac/1", once("hello")).await.unwrap();
+        assert!(cache.exists("ac/1").await.unwrap());
+        assert!(remote.exists("ac/1").await.unwrap());
+
+        // Dropped from the cache, then filled again by a read.
+        cache.delete("ac/1").await.unwrap();
+        assert_eq!(tiered.stat("ac/1").await.unwrap().size, 5);
+        let data = read_all(tiered.get("ac/1").await.unwrap()).await.unwrap();
+        assert_eq!(data, b"hello");
+        assert!(cache.exists("ac/1").await.unwrap());
+
+        // Only in the remote, as written by another instance.
+        remote.put("cas/2", once("x")).await.unwrap();
+        let mut keys = tiered.list("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["ac/1", "cas/2"]);
+
+        cache.delete("ac/1").await.unwrap();
+        tiered.delete("ac/1").await.unwrap();
+        assert!(!tiered.exists("ac/1").await.unwrap());
+        assert_eq!(
+            tiered.get("ac/1").await.err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,8 +1,136 @@
 use std::default::Default;
 use std::error::Error;
 
+use cibo_util::config::{ReadableDuration, ReadableSize};
 use threadpool::config::ThreadPoolConfig;
 
+// The smallest part S3 accepts, but for the last one.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Where `Storage` keeps blobs.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Files under `cache-dir`.
+    Disk,
+    /// Memory only, lost on restart.
+    Memory,
+    /// An S3 compatible bucket, with `cache-dir` as a cache in front of it.
+    S3,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3Config {
+    // Such as `http://minio:9000`.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    // Prepended to every key, such as `greenhouse/`.
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Addresses the bucket as `endpoint/bucket`, which MinIO expects.
+    pub path_style: bool,
+    // Blobs over this are uploaded in parts of this size.
+    pub part_size: ReadableSize,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: "".to_string(),
+            region: "us-east-1".to_string(),
+            bucket: "".to_string(),
+            prefix: "".to_string(),
+            access_key: "".to_string(),
+            secret_key: "".to_string(),
+            path_style: true,
+            part_size: ReadableSize::mb(16),
+        }
+    }
+}
+
+/// Blobs up to `max-object-size` after compression go into segments under
+/// `cache-dir` instead of a file each. Only with the disk backend.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogStoreConfig {
+    pub enabled: bool,
+    pub max_object_size: ReadableSize,
+    pub segment_size: ReadableSize,
+    // Sealed segments with less than this ratio of live bytes are rewritten
+    // after each gc round.
+    pub compact_live_ratio: f64,
+    // Threads reading and writing segments where io_uring is unavailable.
+    pub io_threads: usize,
+    pub uring: UringConfig,
+}
+
+impl Default for LogStoreConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_object_size: ReadableSize::kb(16),
+            segment_size: ReadableSize::mb(64),
+            compact_live_ratio: 0.5,
+            io_threads: 4,
+            uring: UringConfig::default(),
+        }
+    }
+}
+
+/// How the log store sets up io_uring, when built with it.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UringConfig {
+    pub queue_depth: usize,
+    // A kernel thread polls for submissions, saving a syscall each while
+    // it is awake. Before Linux 5.11 it needs CAP_SYS_ADMIN and fixed files,
+    // dropped if either is missing.
+    pub sqpoll: bool,
+    // How long the poll thread waits for work before it sleeps.
+    pub sqpoll_idle: ReadableDuration,
+    // Pins the poll thread to a CPU.
+    pub sqpoll_cpu: Option<u32>,
+    // Segment files registered with the ring, others are used by descriptor.
+    pub fixed_files: usize,
+    // Buffers registered for writes, none to write from the caller buffer.
+    pub write_buffers: usize,
+    pub write_buffer_size: ReadableSize,
+}
+
+impl Default for UringConfig {
+    fn default() -> Self {
+        Self {
+            queue_depth: 64,
+            sqpoll: false,
+            sqpoll_idle: ReadableDuration::secs(1),
+            sqpoll_cpu: None,
+            fixed_files: 256,
+            write_buffers: 4,
+            write_buffer_size: ReadableSize::kb(64),
+        }
+    }
+}
+
+impl From<&UringConfig> for pagecache::UringConfig {
+    fn from(cfg: &UringConfig) -> Self {
+        Self {
+            queue_depth: cfg.queue_depth,
+            sqpoll: cfg.sqpoll,
+            sqpoll_idle: cfg.sqpoll_idle.0,
+            sqpoll_cpu: cfg.sqpoll_cpu,
+            fixed_files: cfg.fixed_files,
+            write_buffers: cfg.write_buffers,
+            write_buffer_size: cfg.write_buffer_size.0 as usize,
+        }
+    }
+}
+
 macro_rules! storage_config {
     ($struct_name:ident, $display_name:expr) => {
         #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -10,6 +138,15 @@ macro_rules! storage_config {
         #[serde(rename_all = "kebab-case")]
         pub struct $struct_name {
             pub cache_dir: String,
+            pub backend: BackendKind,
+            pub s3: S3Config,
+            pub log_store: LogStoreConfig,
+            // Lazygc starts evicting once the used ratio of the disk is over
+            // `gc-high-watermark`, and frees the gap to `gc-low-watermark`.
+            pub gc_high_watermark: f64,
+            pub gc_low_watermark: f64,
+            // Writes are rejected while the used ratio is over this.
+            pub hard_limit_watermark: f64,
             pub reading_threadpool: ThreadPoolConfig,
             pub writing_threadpool: ThreadPoolConfig,
         }
@@ -19,6 +156,64 @@ macro_rules! storage_config {
                 if self.cache_dir.is_empty() {
                     return Err("storage's cache_dir should be non-empty".into());
                 }
+                if !(0.0 < self.gc_low_watermark
+                    && self.gc_low_watermark < self.gc_high_watermark
+                    && self.gc_high_watermark < self.hard_limit_watermark
+                    && self.hard_limit_watermark <= 1.0)
+                {
+                    return Err(format!(
+                        "{} watermarks should be 0 < gc-low-watermark < gc-high-watermark < hard-limit-watermark <= 1",
+                        $display_name
+                    )
+                    .into());
+                }
+                if self.backend == BackendKind::S3 {
+                    if self.s3.endpoint.is_empty() || self.s3.bucket.is_empty() {
+                        return Err(format!(
+                            "{} s3 backend needs an endpoint and a bucket",
+                            $display_name
+                        )
+                        .into());
+                    }
+                    if self.s3.part_size.0 < MIN_PART_SIZE {
+                        return Err(format!(
+                            "{} s3 part-size should be at least 5MB",
+                            $display_name
+                        )
+                        .into());
+                    }
+                }
+                if self.log_store.enabled {
+                    if self.log_store.segment_size.0 < self.log_store.max_object_size.0 * 2 {
+                        return Err(format!(
+                            "{} log-store segment-size should be at least twice max-object-size",
+                            $display_name
+                        )
+                        .into());
+                    }
+                    if self.log_store.io_threads == 0 {
+                        return Err(format!(
+                            "{} log-store io-threads should be non-zero",
+                            $display_name
+                        )
+                        .into());
+                    }
+                    if !(0.0..=1.0).contains(&self.log_store.compact_live_ratio) {
+                        return Err(format!(
+                            "{} log-store compact-live-ratio should be within [0, 1]",
+                            $display_name
+                        )
+                        .into());
+                    }
+                    let depth = self.log_store.uring.queue_depth;
+                    if depth < 2 || depth % 2 != 0 {
+                        return Err(format!(
+                            "{} log-store uring queue-depth should be even and at least 2",
+                            $display_name
+                        )
+                        .into());
+                    }
+                }
                 Ok(())
             }
         }
@@ -31,8 +226,49 @@ impl Default for StorageConfig {
     fn default() -> Self {
         Self {
             cache_dir: "".to_string(),
+            backend: BackendKind::Disk,
+            s3: S3Config::default(),
+            log_store: LogStoreConfig::default(),
+            gc_high_watermark: 0.8,
+            gc_low_watermark: 0.6,
+            hard_limit_watermark: 0.95,
             reading_threadpool: Default::default(),
             writing_threadpool: Default::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_watermarks() {
+        let valid = StorageConfig {
+            cache_dir: "/tmp/greenhouse".to_owned(),
+            ..StorageConfig::default()
+        };
+        valid.validate().unwrap();
+
+        for (low, high, hard) in vec![
+            (0.0, 0.8, 0.95),
+            (0.8, 0.6, 0.95),
+            (0.6, 0.6, 0.95),
+            (0.6, 0.95, 0.8),
+            (0.6, 0.8, 1.1),
+        ] {
+            let cfg = StorageConfig {
+                gc_low_watermark: low,
+                gc_high_watermark: high,
+                hard_limit_watermark: hard,
+                ..valid.clone()
+            };
+            assert!(cfg.validate().is_err(), "{} {} {}", low, high, hard);
+        }
+        let full_disk = StorageConfig {
+            hard_limit_watermark: 1.0,
+            ..valid
+        };
+        full_disk.validate().unwrap();
+    }
+}
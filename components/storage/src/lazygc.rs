@@ -3,17 +3,22 @@ use std::collections::BTreeMap;
 use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time;
 
+use pagecache::LogStore;
 use walkdir::WalkDir;
 
 use crate::metrics::*;
+use crate::LOG_STORE_DIR;
 
 #[derive(Eq, Clone)]
 pub struct EntryInfo {
     pub path: PathBuf,
     pub last_access: i64,
+    // Kept in the log store under this key rather than in a file.
+    pub log_key: Option<String>,
 }
 
 impl Ord for EntryInfo {
@@ -47,6 +52,9 @@ pub struct Lazygc {
     entry_map: BTreeMap<EntryInfo, u64>,
     entry_total_size: u64,
     total_size: u64,
+
+    log_store: Option<Arc<LogStore>>,
+    compact_live_ratio: f64,
 }
 
 impl Lazygc {
@@ -58,9 +66,19 @@ impl Lazygc {
             entry_map: BTreeMap::new(),
             entry_total_size: 0,
             total_size: 0,
+            log_store: None,
+            compact_live_ratio: 0.0,
         }
     }
 
+    /// Evicts blobs from `log_store` along with files, and compacts it after
+    /// each round.
+    pub fn log_store(mut self, log_store: Arc<LogStore>, compact_live_ratio: f64) -> Lazygc {
+        self.log_store = Some(log_store);
+        self.compact_live_ratio = compact_live_ratio;
+        self
+    }
+
     pub fn start(&mut self) {
         if let Some((_, bytes_free, bytes_used)) = get_disk_usage(self.path.clone()) {
             self.total_size = bytes_free + bytes_used;
@@ -68,6 +86,12 @@ impl Lazygc {
                 info!("start to clearn");
                 self.get();
                 for (key, _) in self.entry_map.iter() {
+                    if let (Some(log_key), Some(log_store)) = (&key.log_key, &self.log_store) {
+                        if let Err(e) = log_store.delete(log_key) {
+                            error!("fail to evict from log store"; "key" => log_key, "err" => %e);
+                        }
+                        continue;
+                    }
                     info!("rm file"; "file" => &key.path.to_str());
                     if let Err(e) = std::fs::remove_file(&key.path) {
                         error!("file to rm file"; "file" => &key.path.to_str(),"err" => e.to_string());
@@ -76,10 +100,33 @@ impl Lazygc {
                 self.entry_map.clear();
             }
         }
+        self.compact();
+    }
+
+    // Reclaims the space of blobs evicted or deleted from the log store.
+    fn compact(&self) {
+        let log_store = match &self.log_store {
+            Some(log_store) => log_store,
+            None => return,
+        };
+        match log_store.compact(self.compact_live_ratio) {
+            Ok(stats) if stats.segments > 0 => info!(
+                "compacted log store";
+                "segments" => stats.segments,
+                "reclaimed" => stats.reclaimed,
+            ),
+            Ok(_) => {}
+            Err(e) => error!("fail to compact log store"; "err" => %e),
+        }
     }
 
     fn get(&mut self) {
-        for entry in WalkDir::new(&self.path).into_iter().filter_map(|e| e.ok()) {
+        // Segments are evicted blob by blob, below.
+        let log_dir = self.path.join(LOG_STORE_DIR);
+        let walk = WalkDir::new(&self.path)
+            .into_iter()
+            .filter_entry(|e| e.path() != log_dir);
+        for entry in walk.filter_map(|e| e.ok()) {
             let p = entry.path();
             let meta = match std::fs::metadata(&p) {
                 Ok(meta) => meta,
@@ -92,6 +139,7 @@ impl Lazygc {
                     EntryInfo {
                         path: p.to_path_buf(),
                         last_access: meta.ctime(),
+                        log_key: None,
                     },
                     meta.size(),
                 );
@@ -99,6 +147,22 @@ impl Lazygc {
             }
             self.clean_map();
         }
+        let metas = match &self.log_store {
+            Some(log_store) => log_store.metas(),
+            None => return,
+        };
+        for (key, meta) in metas {
+            self.entry_map.insert(
+                EntryInfo {
+                    path: self.path.join(&key),
+                    last_access: meta.written as i64,
+                    log_key: Some(key),
+                },
+                meta.size,
+            );
+            self.entry_total_size += meta.size;
+            self.clean_map();
+        }
     }
 
     fn clean_map(&mut self) {
@@ -124,6 +188,7 @@ pub struct LazygcServer {
     path: PathBuf,
     min_percent_block_free: f64,
     stop_percent_block: f64,
+    log_store: Option<(Arc<LogStore>, f64)>,
 }
 
 impl LazygcServer {
@@ -137,16 +202,25 @@ impl LazygcServer {
             path,
             min_percent_block_free,
             stop_percent_block,
+            log_store: None,
         }
     }
 
+    /// See `Lazygc::log_store`.
+    pub fn log_store(&mut self, log_store: Arc<LogStore>, compact_live_ratio: f64) {
+        self.log_store = Some((log_store, compact_live_ratio));
+    }
+
     pub fn start(&mut self) -> Result<(), io::Error> {
         let builder = thread::Builder::new().name("lazy-service".to_string());
-        let gc = Lazygc::new(
+        let mut gc = Lazygc::new(
             self.path.clone(),
             self.min_percent_block_free,
             self.stop_percent_block,
         );
+        if let Some((log_store, compact_live_ratio)) = &self.log_store {
+            gc = gc.log_store(log_store.clone(), *compact_live_ratio);
+        }
         let ten_millis = time::Duration::from_secs(10);
         let h = builder.spawn(move || loop {
             info!("lazy gc start");
@@ -8,50 +8,193 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod backend;
 pub mod config;
 mod lazygc;
 mod metrics;
+mod stats;
+mod trace;
+mod watermark;
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::Cursor;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::{Future, StreamExt};
+use pagecache::{LogConfig, LogStore};
 use threadpool::{Priority, ThreadPool};
-use tokio::io::AsyncWriteExt;
-use tokio::{fs, fs::File, io};
+use tokio::io;
 use zstd::stream::write::Decoder;
 use zstd::Encoder;
 
-use crate::config::StorageConfig;
+use crate::backend::{Backend, ByteStream, DiskBackend, MemoryBackend, S3Backend, TieredBackend};
+use crate::config::{BackendKind, StorageConfig};
 pub use crate::lazygc::Lazygc;
 pub use crate::lazygc::LazygcServer;
 pub use crate::metrics::*;
+pub use crate::stats::OpStats;
+pub use crate::watermark::DiskWatermark;
+pub use namespace::Namespace;
+
+// `io::Error` is not `Clone`, so the result shared between coalesced writers
+// is wrapped in an `Arc`.
+type SharedWrite = Shared<BoxFuture<'static, (Result<(), Arc<io::Error>>, OpStats)>>;
+
+/// The entry of a CAS write in `inflight_writes`, removed by the write task
+/// as it ends, whether or not anybody still waits for it.
+#[derive(Clone)]
+struct Inflight {
+    writes: Arc<Mutex<HashMap<String, SharedWrite>>>,
+    key: String,
+}
+
+impl Inflight {
+    fn done(&self) {
+        self.writes.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Where the log store keeps its segments, under `cache-dir`.
+pub const LOG_STORE_DIR: &str = ".log";
+
+fn key_of(path: impl AsRef<Path>) -> String {
+    path.as_ref().to_string_lossy().into_owned()
+}
+
+/// Small blobs are looked up in the log store first.
+async fn get_blob(
+    log_store: &Option<Arc<LogStore>>,
+    backend: &Arc<dyn Backend>,
+    key: &str,
+) -> io::Result<ByteStream> {
+    if let Some(log_store) = log_store {
+        if let Some(data) = log_store.get(key)? {
+            return Ok(backend::once(data));
+        }
+    }
+    backend.get(key).await
+}
 
 pub struct Storage {
+    backend: Arc<dyn Backend>,
+    log_store: Option<Arc<LogStore>>,
     reading_pool: Arc<ThreadPool>,
     writing_pool: Arc<ThreadPool>,
     basic_path: PathBuf,
+    read_only: Arc<AtomicBool>,
+    // Writes currently in progress, keyed by blob key.
+    inflight_writes: Arc<Mutex<HashMap<String, SharedWrite>>>,
 
     metric_handle: Option<thread::JoinHandle<()>>,
 }
 
 impl Storage {
+    /// Builds the backend chosen by `config.backend`, with the log store in
+    /// front of a disk backend if enabled.
     pub fn new(config: StorageConfig) -> Self {
+        let log_store = if config.backend == BackendKind::Disk && config.log_store.enabled {
+            let log_config = LogConfig {
+                dir: Path::new(&config.cache_dir).join(LOG_STORE_DIR),
+                segment_size: config.log_store.segment_size.0,
+                max_value_size: config.log_store.max_object_size.0,
+                io_threads: config.log_store.io_threads,
+                uring: (&config.log_store.uring).into(),
+            };
+            let log_store = LogStore::open(log_config)
+                .unwrap_or_else(|e| panic!("failed to open log store: {}", e));
+            info!("opened log store"; "engine" => log_store.engine_name());
+            Some(Arc::new(log_store))
+        } else {
+            None
+        };
+        let backend: Arc<dyn Backend> = match config.backend {
+            BackendKind::Disk => Arc::new(DiskBackend::new(&config.cache_dir)),
+            BackendKind::Memory => Arc::new(MemoryBackend::new()),
+            BackendKind::S3 => {
+                let remote = S3Backend::new(&config.s3)
+                    .unwrap_or_else(|e| panic!("failed to set up s3 backend: {}", e));
+                Arc::new(TieredBackend::new(
+                    Arc::new(DiskBackend::new(&config.cache_dir)),
+                    Arc::new(remote),
+                ))
+            }
+        };
+        let storage = Storage::with_backend(config, backend);
+        match log_store {
+            Some(log_store) => storage.with_log_store(log_store),
+            None => storage,
+        }
+    }
+
+    /// `config.cache_dir` is still used for the disk watermark, whatever the
+    /// backend.
+    pub fn with_backend(config: StorageConfig, backend: Arc<dyn Backend>) -> Self {
         let path = PathBuf::from(config.cache_dir);
         if !path.as_path().exists() {
             std::fs::create_dir_all(path.as_path()).unwrap();
         }
+        let read_only = Arc::new(AtomicBool::new(false));
+        let watermark = DiskWatermark::new(
+            path.clone(),
+            config.hard_limit_watermark,
+            Duration::from_secs(2),
+        );
+        let metric_handle = watermark.start(&read_only).unwrap();
         Storage {
+            backend,
+            log_store: None,
             reading_pool: Arc::new(ThreadPool::new(config.reading_threadpool)),
             writing_pool: Arc::new(ThreadPool::new(config.writing_threadpool)),
             basic_path: path,
-            metric_handle: None,
+            read_only,
+            inflight_writes: Arc::new(Mutex::new(HashMap::new())),
+            metric_handle: Some(metric_handle),
         }
     }
 
+    /// Keeps blobs up to `log_store.max_value_size()` after compression in
+    /// `log_store` rather than in the backend.
+    pub fn with_log_store(mut self, log_store: Arc<LogStore>) -> Self {
+        self.log_store = Some(log_store);
+        self
+    }
+
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        &self.backend
+    }
+
+    pub fn log_store(&self) -> Option<&Arc<LogStore>> {
+        self.log_store.as_ref()
+    }
+
+    /// Returns true while the disk is over the hard limit and writes are rejected.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    pub fn basic_path(&self) -> &Path {
+        self.basic_path.as_path()
+    }
+
+    /// The reading and writing pools, to retune them while serving.
+    pub fn thread_pools(&self) -> [&ThreadPool; 2] {
+        [&self.reading_pool, &self.writing_pool]
+    }
+
+    /// Returns the names of the reading and writing pools that are full.
+    pub fn saturated_pools(&self) -> Vec<String> {
+        let mut pools = self.reading_pool.saturated_pools();
+        pools.extend(self.writing_pool.saturated_pools());
+        pools
+    }
+
     fn priority_by_size(&self, size: u64) -> Priority {
         if size <= 1024 * 250 {
             Priority::HIGH
@@ -62,37 +205,86 @@ impl Storage {
         }
     }
 
-    async fn priority_by_metadata(
+    pub async fn read(
         &self,
         path: impl AsRef<Path> + std::marker::Send + 'static,
-    ) -> io::Result<Priority> {
-        let size = fs::metadata(path).await?.len();
-        Ok(self.priority_by_size(size))
+    ) -> io::Result<Vec<u8>> {
+        self.read_with_stats(path).await.0
     }
 
-    pub async fn read(
+    /// Same as `read`, also telling where the time went.
+    pub async fn read_with_stats(
         &self,
         path: impl AsRef<Path> + std::marker::Send + 'static,
-    ) -> io::Result<Vec<u8>> {
-        let p = self.basic_path.join(path);
-        let priority = self.priority_by_metadata(p.clone()).await?;
-        let future_fn = async move || -> io::Result<Vec<u8>> {
+    ) -> (io::Result<Vec<u8>>, OpStats) {
+        let key = key_of(path);
+        let mut stats = OpStats::default();
+        let io_start = Instant::now();
+        let size = {
+            let _stage = trace::stage("storage.metadata");
+            match self.log_store.as_ref().and_then(|log| log.meta(&key)) {
+                Some(meta) => Ok(meta.size),
+                None => self.backend.stat(&key).await.map(|stat| stat.size),
+            }
+        };
+        stats.io += io_start.elapsed();
+        stats.size = match size {
+            Ok(size) => size,
+            Err(e) => return (Err(e), stats),
+        };
+        let priority = self.priority_by_size(stats.size);
+        stats.priority = Some(priority);
+        let backend = self.backend.clone();
+        let log_store = self.log_store.clone();
+        let wait = trace::stage("storage.pool_wait");
+        let queued = Instant::now();
+        let future_fn = async move || -> (io::Result<Vec<u8>>, OpStats) {
+            wait.end();
+            stats.wait = queued.elapsed();
             let timer = STORAGE_READ_DURATION_SECONDS_HISTOGRAM_VEC.start_timer();
-            let f = fs::read(p).await?;
-            let buffer = Cursor::new(Vec::new());
-            let mut decoder = Decoder::new(buffer).unwrap();
-            decoder.write_all(&f).unwrap();
-            decoder.flush().unwrap();
-            let decoded = decoder.into_inner().into_inner();
+            let _stage = trace::stage("storage.read_file");
+            let mut decoder = Decoder::new(Cursor::new(Vec::new())).unwrap();
+            let io_start = Instant::now();
+            let mut chunks = match get_blob(&log_store, &backend, &key).await {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    stats.io += io_start.elapsed();
+                    return (Err(e), stats);
+                }
+            };
+            stats.io += io_start.elapsed();
+            // Decode chunk by chunk as they arrive.
+            loop {
+                let io_start = Instant::now();
+                let chunk = chunks.next().await;
+                stats.io += io_start.elapsed();
+                let chunk = match chunk {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => return (Err(e), stats),
+                    None => break,
+                };
+                let codec_start = Instant::now();
+                let res = decoder.write_all(&chunk);
+                stats.codec += codec_start.elapsed();
+                if let Err(e) = res {
+                    return (Err(e), stats);
+                }
+            }
+            let codec_start = Instant::now();
+            let decoded = decoder.flush().map(|_| decoder.into_inner().into_inner());
+            stats.codec += codec_start.elapsed();
             timer.observe_duration();
-            Ok(decoded)
+            (decoded, stats)
         };
-        match self.reading_pool.spawn(future_fn(), priority) {
+        match self
+            .reading_pool
+            .spawn(trace::attach(future_fn()), priority)
+        {
             Ok(middle) => match middle.await {
-                Ok(data) => data,
-                Err(e) => Err(io::Error::new(io::ErrorKind::WouldBlock, e)),
+                Ok(res) => res,
+                Err(e) => (Err(io::Error::new(io::ErrorKind::WouldBlock, e)), stats),
             },
-            Err(e) => Err(io::Error::new(io::ErrorKind::WouldBlock, e)),
+            Err(e) => (Err(io::Error::new(io::ErrorKind::WouldBlock, e)), stats),
         }
     }
 
@@ -100,9 +292,51 @@ impl Storage {
         &self,
         path: impl AsRef<Path> + std::marker::Send + 'static,
     ) -> io::Result<()> {
-        let p = self.basic_path.join(path);
-        fs::remove_file(p).await?;
-        Ok(())
+        self.delete_with_stats(path).await.0
+    }
+
+    /// Same as `delete`, also telling where the time went.
+    pub async fn delete_with_stats(
+        &self,
+        path: impl AsRef<Path> + std::marker::Send + 'static,
+    ) -> (io::Result<()>, OpStats) {
+        let key = key_of(path);
+        // The log store writes a tombstone, which blocks, so it runs in the
+        // writing pool like any other write.
+        let priority = Priority::HIGH;
+        let mut stats = OpStats {
+            priority: Some(priority),
+            ..OpStats::default()
+        };
+        let backend = self.backend.clone();
+        let log_store = self.log_store.clone();
+        let queued = Instant::now();
+        let task = async move {
+            stats.wait = queued.elapsed();
+            let io_start = Instant::now();
+            let logged = match &log_store {
+                Some(log_store) => log_store.delete(&key).map_err(io::Error::from),
+                None => Ok(false),
+            };
+            let res = match logged {
+                Err(e) => Err(e),
+                // A blob moved to the log store may have left no file behind.
+                Ok(true) => match backend.delete(&key).await {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    res => res,
+                },
+                Ok(false) => backend.delete(&key).await,
+            };
+            stats.io += io_start.elapsed();
+            (res, stats)
+        };
+        match self.writing_pool.spawn(task, priority) {
+            Ok(middle) => match middle.await {
+                Ok(res) => res,
+                Err(e) => (Err(io::Error::new(io::ErrorKind::WouldBlock, e)), stats),
+            },
+            Err(e) => (Err(io::Error::new(io::ErrorKind::WouldBlock, e)), stats),
+        }
     }
 
     pub async fn write(
@@ -110,29 +344,163 @@ impl Storage {
         data: Vec<u8>,
         path: impl AsRef<Path> + std::marker::Send + 'static,
     ) -> io::Result<()> {
-        let p = self.basic_path.join(path);
+        self.write_with_stats(data, path).await.0
+    }
+
+    /// Same as `write`, also telling where the time went. Writers coalesced
+    /// into an inflight write of the same key get the stats of that write.
+    pub async fn write_with_stats(
+        &self,
+        data: Vec<u8>,
+        path: impl AsRef<Path> + std::marker::Send + 'static,
+    ) -> (io::Result<()>, OpStats) {
+        let mut stats = OpStats::default();
+        if self.is_read_only() {
+            return (
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "storage is read-only, disk is over the hard limit",
+                )),
+                stats,
+            );
+        }
+        let key = key_of(path);
+        let namespace = Namespace::from_path(&key);
+        // A CAS key names its content, so an existing blob never needs rewriting.
+        if namespace == Namespace::Cas {
+            let io_start = Instant::now();
+            let logged = self
+                .log_store
+                .as_ref()
+                .map_or(false, |log| log.meta(&key).is_some());
+            let exists = logged || self.backend.exists(&key).await.unwrap_or(false);
+            stats.io += io_start.elapsed();
+            if exists {
+                STORAGE_WRITE_DEDUP_COUNTER
+                    .with_label_values(&["exists"])
+                    .inc();
+                return (Ok(()), stats);
+            }
+        }
+        // Other keys may be written with different content at once, each
+        // write has to land.
+        if namespace != Namespace::Cas {
+            return self.spawn_write(data, key, stats, None).await;
+        }
+        // The first writer of a CAS key does the work, concurrent writers of
+        // the same key wait for it and share its result.
+        let shared = {
+            let mut inflight = self.inflight_writes.lock().unwrap();
+            match inflight.get(&key) {
+                Some(shared) => {
+                    STORAGE_WRITE_DEDUP_COUNTER
+                        .with_label_values(&["coalesced"])
+                        .inc();
+                    shared.clone()
+                }
+                None => {
+                    // The task may end before the entry is in, it waits for
+                    // the lock held here to remove it.
+                    let entry = Inflight {
+                        writes: self.inflight_writes.clone(),
+                        key: key.clone(),
+                    };
+                    let write = self.spawn_write(data, key.clone(), stats, Some(entry));
+                    let shared = async move {
+                        let (res, stats) = write.await;
+                        (res.map_err(Arc::new), stats)
+                    }
+                    .boxed()
+                    .shared();
+                    inflight.insert(key, shared.clone());
+                    shared
+                }
+            }
+        };
+        let (res, stats) = shared.await;
+        (
+            res.map_err(|e| io::Error::new(e.kind(), e.to_string())),
+            stats,
+        )
+    }
+
+    /// Spawns the write into the writing pool right away, so it completes even
+    /// if the leading request goes away.
+    fn spawn_write(
+        &self,
+        data: Vec<u8>,
+        key: String,
+        mut stats: OpStats,
+        inflight: Option<Inflight>,
+    ) -> impl Future<Output = (io::Result<()>, OpStats)> {
         let priority = self.priority_by_size(data.len().try_into().unwrap());
-        let future_fn = async move || -> io::Result<()> {
+        stats.priority = Some(priority);
+        let backend = self.backend.clone();
+        let log_store = self.log_store.clone();
+        let wait = trace::stage("storage.pool_wait");
+        let queued = Instant::now();
+        let task_inflight = inflight.clone();
+        let future_fn = async move || -> (io::Result<()>, OpStats) {
+            wait.end();
+            stats.wait = queued.elapsed();
             let timer = STORAGE_WRITE_DURATION_SECONDS_HISTOGRAM_VEC.start_timer();
-            let p_parent = p.as_path().parent().unwrap();
-            if fs::metadata(p_parent).await.is_err() {
-                fs::create_dir_all(p_parent).await?;
+            let codec_start = Instant::now();
+            let encoded = {
+                let _stage = trace::stage("storage.zstd_encode");
+                let buffer = Cursor::new(Vec::new());
+                let mut encoder = Encoder::new(buffer, 1).unwrap();
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap().into_inner()
+            };
+            stats.codec += codec_start.elapsed();
+            stats.size = encoded.len() as u64;
+            let _stage = trace::stage("storage.write_file");
+            let io_start = Instant::now();
+            let small = log_store
+                .as_ref()
+                .filter(|log| encoded.len() as u64 <= log.max_value_size());
+            let res = match small {
+                Some(log_store) => log_store.put(&key, &encoded).map_err(io::Error::from),
+                None => match backend.put(&key, backend::once(encoded)).await {
+                    // Drops a former small version, which reads would find first.
+                    Ok(()) => match &log_store {
+                        Some(log_store) => {
+                            log_store.delete(&key).map(|_| ()).map_err(io::Error::from)
+                        }
+                        None => Ok(()),
+                    },
+                    Err(e) => Err(e),
+                },
+            };
+            stats.io += io_start.elapsed();
+            if res.is_ok() {
+                timer.observe_duration();
             }
-            let buffer = Cursor::new(Vec::new());
-            let mut encoder = Encoder::new(buffer, 1).unwrap();
-            encoder.write_all(&data).unwrap();
-            let encoded = encoder.finish().unwrap().into_inner();
-            let mut file = File::create(p).await?;
-            file.write_all(&encoded).await?;
-            timer.observe_duration();
-            Ok(())
+            if let Some(inflight) = task_inflight {
+                inflight.done();
+            }
+            (res, stats)
         };
-        match self.writing_pool.spawn(future_fn(), priority) {
-            Ok(middle) => match middle.await {
-                Ok(data) => data,
+        let handle = self
+            .writing_pool
+            .spawn(trace::attach(future_fn()), priority);
+        async move {
+            let res = match handle {
+                Ok(middle) => middle
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::WouldBlock, e)),
                 Err(e) => Err(io::Error::new(io::ErrorKind::WouldBlock, e)),
-            },
-            Err(e) => Err(io::Error::new(io::ErrorKind::WouldBlock, e)),
+            };
+            match res {
+                Ok(res) => res,
+                Err(e) => {
+                    // The task was rejected or did not get to its end.
+                    if let Some(inflight) = inflight {
+                        inflight.done();
+                    }
+                    (Err(e), stats)
+                }
+            }
         }
     }
 }
@@ -145,8 +513,200 @@ impl Drop for Storage {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    use futures::channel::oneshot;
+
+    use crate::backend::Stat;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[tokio::test]
+    async fn test_storage_on_memory_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            ..StorageConfig::default()
+        };
+        let backend = Arc::new(MemoryBackend::new());
+        let storage = Storage::with_backend(config, backend.clone());
+
+        let blob = b"hello greenhouse".repeat(100);
+        storage.write(blob.clone(), "cas/abc").await.unwrap();
+        // Stored compressed.
+        let stored = backend::read_all(backend.get("cas/abc").await.unwrap())
+            .await
+            .unwrap();
+        assert!(stored.len() < blob.len());
+
+        let (data, stats) = storage.read_with_stats("cas/abc").await;
+        assert_eq!(data.unwrap(), blob);
+        assert_eq!(stats.size, stored.len() as u64);
+        assert_eq!(stats.priority, Some(Priority::HIGH));
+
+        storage.delete("cas/abc").await.unwrap();
+        let err = storage.read("cas/abc").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(dir.path().join("cas").metadata().is_err());
+    }
+
+    // Does not compress, so it stays over the log store limit.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_small_blobs_in_log_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            ..StorageConfig::default()
+        };
+        let storage = Storage::new(config);
+        let log_store = storage.log_store().unwrap().clone();
+
+        storage.write(b"small".to_vec(), "cas/1").await.unwrap();
+        let large = noise(64 * 1024);
+        storage.write(large.clone(), "cas/2").await.unwrap();
+        assert!(log_store.meta("cas/1").is_some());
+        assert!(!dir.path().join("cas/1").exists());
+        assert!(log_store.meta("cas/2").is_none());
+        assert!(dir.path().join("cas/2").exists());
+        assert_eq!(storage.read("cas/1").await.unwrap(), b"small");
+        assert_eq!(storage.read("cas/2").await.unwrap(), large);
+
+        // An action result growing over the limit leaves the log store.
+        storage.write(b"small".to_vec(), "ac/3").await.unwrap();
+        storage.write(large.clone(), "ac/3").await.unwrap();
+        assert!(log_store.meta("ac/3").is_none());
+        assert_eq!(storage.read("ac/3").await.unwrap(), large);
+
+        storage.delete("cas/1").await.unwrap();
+        let err = storage.read("cas/1").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = storage.delete("cas/1").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Holds every put until the gate opens, and records the keys put.
+    struct GatedBackend {
+        inner: MemoryBackend,
+        gate: Shared<oneshot::Receiver<()>>,
+        puts: Mutex<Vec<String>>,
+    }
+
+    impl GatedBackend {
+        fn puts_of(&self, key: &str) -> usize {
+            self.puts
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|k| *k == key)
+                .count()
+        }
+    }
+
+    impl Backend for GatedBackend {
+        fn stat(&self, key: &str) -> BoxFuture<'static, io::Result<Stat>> {
+            self.inner.stat(key)
+        }
+
+        fn get(&self, key: &str) -> BoxFuture<'static, io::Result<ByteStream>> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: &str, data: ByteStream) -> BoxFuture<'static, io::Result<()>> {
+            self.puts.lock().unwrap().push(key.to_owned());
+            let gate = self.gate.clone();
+            let put = self.inner.put(key, data);
+            async move {
+                let _ = gate.await;
+                put.await
+            }
+            .boxed()
+        }
+
+        fn delete(&self, key: &str) -> BoxFuture<'static, io::Result<()>> {
+            self.inner.delete(key)
+        }
+
+        fn list(&self, prefix: &str) -> BoxFuture<'static, io::Result<Vec<String>>> {
+            self.inner.list(prefix)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writes_of_same_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            ..StorageConfig::default()
+        };
+        let (open, gate) = oneshot::channel();
+        let backend = Arc::new(GatedBackend {
+            inner: MemoryBackend::new(),
+            gate: gate.shared(),
+            puts: Mutex::new(vec![]),
+        });
+        let storage = Storage::with_backend(config, backend.clone());
+
+        // Every write is in flight before the gate opens.
+        let (cas_1, cas_2, ac_1, ac_2, _) = futures::join!(
+            storage.write(b"blob".to_vec(), "cas/1"),
+            storage.write(b"blob".to_vec(), "cas/1"),
+            storage.write(b"first".to_vec(), "ac/1"),
+            storage.write(b"second".to_vec(), "ac/1"),
+            async { open.send(()).unwrap() },
+        );
+        for res in vec![cas_1, cas_2, ac_1, ac_2] {
+            res.unwrap();
+        }
+        // The CAS writes share one put, each action result is put.
+        assert_eq!(backend.puts_of("cas/1"), 1);
+        assert_eq!(backend.puts_of("ac/1"), 2);
+        assert_eq!(storage.read("cas/1").await.unwrap(), b"blob");
+        let result = storage.read("ac/1").await.unwrap();
+        assert!(result == b"first" || result == b"second");
+    }
+
+    #[tokio::test]
+    async fn test_write_without_waiters() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            ..StorageConfig::default()
+        };
+        let (open, gate) = oneshot::channel();
+        let backend = Arc::new(GatedBackend {
+            inner: MemoryBackend::new(),
+            gate: gate.shared(),
+            puts: Mutex::new(vec![]),
+        });
+        let storage = Storage::with_backend(config, backend.clone());
+
+        // The only waiter goes away once the write is spawned.
+        let mut write = storage.write(b"blob".to_vec(), "cas/1").boxed();
+        assert!(futures::poll!(&mut write).is_pending());
+        drop(write);
+        assert_eq!(storage.inflight_writes.lock().unwrap().len(), 1);
+        open.send(()).unwrap();
+
+        // The write still lands, and is forgotten.
+        let give_up = Instant::now() + Duration::from_secs(10);
+        while !storage.inflight_writes.lock().unwrap().is_empty() {
+            assert!(Instant::now() < give_up, "inflight write never removed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(storage.read("cas/1").await.unwrap(), b"blob");
+        assert_eq!(backend.puts_of("cas/1"), 1);
+    }
 }
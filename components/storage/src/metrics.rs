@@ -19,6 +19,17 @@ lazy_static! {
         exponential_buckets(0.0005, 2.0, 20).unwrap()
     )
     .unwrap();
+    pub static ref STORAGE_WRITE_DEDUP_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "storage_write_dedup_total",
+        "Number of writes skipped because the key exists or is being written",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref STORAGE_READ_ONLY: IntGauge = register_int_gauge!(opts!(
+        "storage_read_only",
+        "Whether storage rejects writes because the disk is over the hard limit"
+    ))
+    .unwrap();
     pub static ref DISK_FREE: Gauge = register_gauge!(opts!(
         "bazel_cache_disk_free",
         "Free gb on bazel cache disk"
@@ -38,9 +49,11 @@ lazy_static! {
 
 pub fn get_disk_usage<P: AsRef<Path>>(path: P) -> Option<(f64, u64, u64)> {
     unsafe {
-        let mut buf: libc::statvfs = mem::MaybeUninit::uninit().assume_init();
+        let mut buf: libc::statvfs = mem::zeroed();
         let path = CString::new(path.as_ref().to_str().unwrap().as_bytes()).unwrap();
-        libc::statvfs(path.as_ptr(), &mut buf as *mut _);
+        if libc::statvfs(path.as_ptr(), &mut buf as *mut _) != 0 {
+            return None;
+        }
         let percent_blocks_free = (buf.f_bfree as f64) / (buf.f_blocks as f64) * 100.0;
         let bytes_free = (buf.f_bfree as u64) * (buf.f_bsize as u64);
         let bytes_used = (buf.f_blocks as u64 - buf.f_bfree as u64) * (buf.f_bsize as u64);
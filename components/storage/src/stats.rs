@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use threadpool::Priority;
+
+/// Where the time of one storage operation went.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpStats {
+    /// The pool that ran the operation, `None` if it never got to one.
+    pub priority: Option<Priority>,
+    /// Size of the blob on disk.
+    pub size: u64,
+    /// Time spent queued for a slot in the pool.
+    pub wait: Duration,
+    /// Time spent in filesystem calls.
+    pub io: Duration,
+    /// Time spent in zstd.
+    pub codec: Duration,
+}
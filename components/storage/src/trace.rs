@@ -0,0 +1,69 @@
+//! Tracing spans for the stages of a read or write. Everything here is a no-op
+//! unless the crate is built with the `tracing` feature.
+
+use futures::Future;
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use futures::Future;
+    use opentelemetry::global::{self, BoxedSpan};
+    use opentelemetry::trace::{FutureExt, Tracer, WithContext};
+    use opentelemetry::Context;
+
+    const TRACER_NAME: &str = "greenhouse-storage";
+
+    /// A span that ends when dropped.
+    pub struct Stage(#[allow(dead_code)] BoxedSpan);
+
+    impl Stage {
+        pub fn end(self) {}
+    }
+
+    pub fn stage(name: &'static str) -> Stage {
+        Stage(global::tracer(TRACER_NAME).start(name))
+    }
+
+    pub type Attached<F> = WithContext<F>;
+
+    pub fn attach<F: Future>(fut: F) -> Attached<F> {
+        fut.with_context(Context::current())
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use futures::Future;
+
+    pub struct Stage;
+
+    impl Stage {
+        #[inline]
+        pub fn end(self) {}
+    }
+
+    #[inline]
+    pub fn stage(_name: &'static str) -> Stage {
+        Stage
+    }
+
+    pub type Attached<F> = F;
+
+    #[inline]
+    pub fn attach<F: Future>(fut: F) -> Attached<F> {
+        fut
+    }
+}
+
+pub use self::imp::Stage;
+
+/// Starts a span named `name` under the span of the current request.
+#[inline]
+pub fn stage(name: &'static str) -> Stage {
+    imp::stage(name)
+}
+
+/// Carries the span of the current request into a future that runs in a pool.
+#[inline]
+pub fn attach<F: Future>(fut: F) -> imp::Attached<F> {
+    imp::attach(fut)
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::*;
+
+/// Watches disk usage of the cache dir and flips the storage into read-only
+/// mode once the used ratio goes past `hard_limit`. The thread exits by itself
+/// after the owning `Storage` is dropped.
+pub struct DiskWatermark {
+    path: PathBuf,
+    hard_limit: f64,
+    interval: Duration,
+}
+
+impl DiskWatermark {
+    pub fn new(path: PathBuf, hard_limit: f64, interval: Duration) -> DiskWatermark {
+        DiskWatermark {
+            path,
+            hard_limit,
+            interval,
+        }
+    }
+
+    pub fn start(self, read_only: &Arc<AtomicBool>) -> io::Result<thread::JoinHandle<()>> {
+        let read_only: Weak<AtomicBool> = Arc::downgrade(read_only);
+        // Check once before serving, so a full disk is caught at startup.
+        if let Some(flag) = read_only.upgrade() {
+            self.check(&flag);
+        }
+        thread::Builder::new()
+            .name("disk-watermark-service".to_string())
+            .spawn(move || loop {
+                thread::sleep(self.interval);
+                match read_only.upgrade() {
+                    Some(flag) => self.check(&flag),
+                    None => return,
+                }
+            })
+    }
+
+    fn check(&self, read_only: &AtomicBool) {
+        let (_, bytes_free, bytes_used) = match get_disk_usage(self.path.as_path()) {
+            Some(usage) => usage,
+            None => return,
+        };
+        let total = bytes_free + bytes_used;
+        if total == 0 {
+            return;
+        }
+        self.update(read_only, bytes_used as f64 / total as f64);
+    }
+
+    /// Sets `read_only` from the `used` ratio of the disk.
+    fn update(&self, read_only: &AtomicBool, used: f64) {
+        let over_limit = used > self.hard_limit;
+        if read_only.swap(over_limit, Ordering::SeqCst) != over_limit {
+            if over_limit {
+                warn!("disk usage over hard limit, enter read-only mode";
+                    "used" => used, "hard_limit" => self.hard_limit);
+            } else {
+                info!("disk usage back under hard limit, leave read-only mode";
+                    "used" => used, "hard_limit" => self.hard_limit);
+            }
+        }
+        STORAGE_READ_ONLY.set(over_limit as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_only_at_hard_limit() {
+        let watermark = DiskWatermark::new(PathBuf::from("."), 0.9, Duration::from_secs(1));
+        let read_only = AtomicBool::new(false);
+        watermark.update(&read_only, 0.5);
+        assert!(!read_only.load(Ordering::SeqCst));
+        // Only past the limit.
+        watermark.update(&read_only, 0.9);
+        assert!(!read_only.load(Ordering::SeqCst));
+        watermark.update(&read_only, 0.95);
+        assert!(read_only.load(Ordering::SeqCst));
+        watermark.update(&read_only, 0.97);
+        assert!(read_only.load(Ordering::SeqCst));
+        watermark.update(&read_only, 0.85);
+        assert!(!read_only.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_start_checks_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        for (hard_limit, expected) in vec![(1.0, false), (0.0, true)] {
+            let read_only = Arc::new(AtomicBool::new(!expected));
+            let watermark =
+                DiskWatermark::new(dir.path().to_owned(), hard_limit, Duration::from_millis(10));
+            let handle = watermark.start(&read_only).unwrap();
+            // Any used byte is over a zero limit.
+            assert_eq!(read_only.load(Ordering::SeqCst), expected, "{}", hard_limit);
+            // The thread exits once the flag is gone.
+            drop(read_only);
+            handle.join().unwrap();
+        }
+    }
+}
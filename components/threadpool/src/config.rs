@@ -1,6 +1,6 @@
 use std::error::Error;
 
-use cibo_util::config::ReadableSize;
+use cibo_util::config::{ReadableDuration, ReadableSize};
 use cibo_util::future_pool;
 
 macro_rules! threadpool_config {
@@ -17,6 +17,12 @@ macro_rules! threadpool_config {
             pub max_tasks_per_worker_normal: usize,
             pub max_tasks_per_worker_low: usize,
             pub stack_size: ReadableSize,
+            // A pool with no task takes work of lower priority whose own pool
+            // has every worker busy.
+            pub steal_when_idle: bool,
+            // A task not started after this is also offered to the next
+            // priority pool, and so on up to high. Zero disables it.
+            pub promote_after: ReadableDuration,
         }
 
         impl $struct_name {
@@ -53,6 +59,8 @@ macro_rules! threadpool_config {
                     max_tasks_per_worker_normal: 2000,
                     max_tasks_per_worker_low: 2000,
                     stack_size: ReadableSize::mb(1),
+                    steal_when_idle: true,
+                    promote_after: ReadableDuration::millis(500),
                 }
             }
 
@@ -125,6 +133,8 @@ impl Default for ThreadPoolConfig {
             max_tasks_per_worker_normal: 200,
             max_tasks_per_worker_low: 10,
             stack_size: ReadableSize::mb(40),
+            steal_when_idle: true,
+            promote_after: ReadableDuration::millis(500),
         }
     }
 }
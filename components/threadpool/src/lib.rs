@@ -1,15 +1,27 @@
 #![feature(trivial_bounds)]
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
 pub mod config;
+mod metrics;
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use cibo_util::future_pool::{Builder, Config, ErrorPoolFull, FuturePool};
+use futures::channel::oneshot;
 use futures::Future;
-use tokio::task::JoinHandle;
+use prometheus::Histogram;
 
 pub use crate::config::ThreadPoolConfig;
+use crate::metrics::*;
 
 // Optimize level
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -19,11 +31,102 @@ pub enum Priority {
     LOW,
 }
 
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::HIGH, Priority::NORMAL, Priority::LOW];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::HIGH => "high",
+            Priority::NORMAL => "normal",
+            Priority::LOW => "low",
+        }
+    }
+
+    /// The next priority up, `None` for high.
+    pub fn higher(self) -> Option<Priority> {
+        match self {
+            Priority::HIGH => None,
+            Priority::NORMAL => Some(Priority::HIGH),
+            Priority::LOW => Some(Priority::NORMAL),
+        }
+    }
+}
+
+/// What one priority class of a `ThreadPool` runs with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub struct ClassLimits {
+    pub workers: usize,
+    /// Tasks that may be running at once, more are rejected.
+    pub max_tasks: usize,
+}
+
+/// Resolves to the output of a task, or to `Canceled` if it panicked.
+pub type TaskHandle<T> = oneshot::Receiver<T>;
+
+/// A task that has not started yet. Every pool it is spawned on races for
+/// it, the first to poll runs it and the others finish at once.
+struct Claim<F: Future> {
+    task: Mutex<Option<(F, oneshot::Sender<F::Output>)>>,
+    queued: Instant,
+    // Of the priority the task was spawned with.
+    wait_histogram: Histogram,
+}
+
+impl<F> Claim<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    async fn run(self: Arc<Self>) {
+        let task = self.task.lock().unwrap().take();
+        if let Some((fut, tx)) = task {
+            self.wait_histogram
+                .observe(self.queued.elapsed().as_secs_f64());
+            // The handle may be gone, nobody waits for the output then.
+            let _ = tx.send(fut.await);
+        }
+    }
+}
+
+/// A `Claim` with its future type erased, for the aging thread.
+trait Waiting: Send + Sync {
+    fn started(&self) -> bool;
+
+    fn spawn_on(self: Arc<Self>, pool: &FuturePool) -> Result<(), ErrorPoolFull>;
+}
+
+impl<F> Waiting for Claim<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn started(&self) -> bool {
+        self.task.lock().unwrap().is_none()
+    }
+
+    fn spawn_on(self: Arc<Self>, pool: &FuturePool) -> Result<(), ErrorPoolFull> {
+        pool.spawn(self.run()).map(|_| ())
+    }
+}
+
+struct Aged {
+    deadline: Instant,
+    // Pool it was last spawned on.
+    priority: Priority,
+    waiting: Arc<dyn Waiting>,
+}
+
 #[derive(Clone)]
 pub struct ThreadPool {
+    name: String,
     pool_high: FuturePool,
     pool_normal: FuturePool,
     pool_low: FuturePool,
+    steal_when_idle: bool,
+    promote_after: Duration,
+    // `None` if promotion is disabled. The aging thread exits once the last
+    // clone of the pool drops it.
+    aging: Option<Arc<Mutex<Sender<Aged>>>>,
 }
 
 impl ThreadPool {
@@ -42,26 +145,301 @@ impl ThreadPool {
         let pool_high = pools.remove(2);
         let pool_normal = pools.remove(1);
         let pool_low = pools.remove(0);
-        Self {
+        let mut pool = Self {
+            name: config.name.clone(),
             pool_high,
             pool_normal,
             pool_low,
+            steal_when_idle: config.steal_when_idle,
+            promote_after: config.promote_after.0,
+            aging: None,
+        };
+        if pool.promote_after > Duration::from_secs(0) {
+            let (tx, rx) = mpsc::channel();
+            let aging = pool.clone();
+            thread::Builder::new()
+                .name(format!("{}-aging", config.name))
+                .spawn(move || aging.run_aging(rx))
+                .unwrap();
+            pool.aging = Some(Arc::new(Mutex::new(tx)));
+        }
+        pool
+    }
+
+    /// Returns the names of the priority pools that are rejecting new tasks.
+    pub fn saturated_pools(&self) -> Vec<String> {
+        [&self.pool_high, &self.pool_normal, &self.pool_low]
+            .iter()
+            .filter(|pool| pool.is_full())
+            .map(|pool| pool.name().to_owned())
+            .collect()
+    }
+
+    fn pool(&self, pri: Priority) -> &FuturePool {
+        match pri {
+            Priority::HIGH => &self.pool_high,
+            Priority::NORMAL => &self.pool_normal,
+            Priority::LOW => &self.pool_low,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn limits(&self, pri: Priority) -> ClassLimits {
+        let pool = self.pool(pri);
+        ClassLimits {
+            workers: pool.workers(),
+            max_tasks: pool.max_tasks(),
         }
     }
 
+    /// Tasks of the class of `pri` running.
+    pub fn running_tasks(&self, pri: Priority) -> usize {
+        self.pool(pri).get_running_task_count()
+    }
+
+    /// Changes the limits of the class of `pri` while it serves. `max_tasks`
+    /// applies to the next spawn. New workers take new tasks at once, the
+    /// old ones finish theirs first.
+    pub fn resize(
+        &self,
+        pri: Priority,
+        workers: Option<usize>,
+        max_tasks: Option<usize>,
+    ) -> io::Result<ClassLimits> {
+        if max_tasks == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "max tasks should be > 0",
+            ));
+        }
+        let pool = self.pool(pri);
+        if let Some(workers) = workers {
+            pool.resize(workers)?;
+        }
+        if let Some(max_tasks) = max_tasks {
+            pool.set_max_tasks(max_tasks);
+        }
+        Ok(self.limits(pri))
+    }
+
+    /// The higher priority pool that should take a task of `pri`: one with
+    /// no task at all, while every worker of `pri` is busy.
+    fn thief(&self, pri: Priority) -> Option<Priority> {
+        let own = self.pool(pri);
+        if !self.steal_when_idle || tasks(own) < own.workers() {
+            return None;
+        }
+        let mut higher = pri.higher();
+        while let Some(pri) = higher {
+            if tasks(self.pool(pri)) == 0 {
+                return Some(pri);
+            }
+            higher = pri.higher();
+        }
+        None
+    }
+
+    /// Spawns `future_fn` on the pool of `pri`, or on an idle higher one.
+    /// If it has to wait for a worker and still waits after `promote-after`,
+    /// the next pool up may start it too.
     pub fn spawn<T>(
         &self,
         future_fn: T,
         pri: Priority,
-    ) -> Result<JoinHandle<T::Output>, ErrorPoolFull>
+    ) -> Result<TaskHandle<T::Output>, ErrorPoolFull>
     where
         T: Future + Send + 'static,
         T::Output: Send + 'static,
     {
-        match pri {
-            Priority::HIGH => self.pool_high.spawn(future_fn),
-            Priority::NORMAL => self.pool_normal.spawn(future_fn),
-            Priority::LOW => self.pool_low.spawn(future_fn),
+        let (tx, rx) = oneshot::channel();
+        let claim = Arc::new(Claim {
+            task: Mutex::new(Some((future_fn, tx))),
+            queued: Instant::now(),
+            wait_histogram: THREADPOOL_QUEUE_WAIT_HISTOGRAM_VEC
+                .with_label_values(&[&self.name, pri.as_str()]),
+        });
+        let runner = match self.thief(pri) {
+            Some(thief) => {
+                THREADPOOL_STOLEN_TASK_VEC
+                    .with_label_values(&[&self.name, pri.as_str()])
+                    .inc();
+                thief
+            }
+            None => pri,
+        };
+        let pool = self.pool(runner);
+        pool.spawn(claim.clone().run())?;
+        // A task a worker took at once never waits, the aging thread is only
+        // told of those queued behind a busy pool.
+        let queued = tasks(pool) > pool.workers();
+        if let (Some(aging), Some(_), true) = (&self.aging, runner.higher(), queued) {
+            let aged = Aged {
+                deadline: Instant::now() + self.promote_after,
+                priority: runner,
+                waiting: claim,
+            };
+            // Only fails once the aging thread is gone, the task still runs
+            // on its own pool.
+            let _ = aging.lock().unwrap().send(aged);
+        }
+        Ok(rx)
+    }
+
+    /// Offers tasks still waiting at their deadline to the next pool up. A
+    /// full pool is tried again after another `promote-after`.
+    fn run_aging(self, rx: mpsc::Receiver<Aged>) {
+        let mut queue: VecDeque<Aged> = VecDeque::new();
+        loop {
+            let received = match queue.front() {
+                Some(aged) => {
+                    rx.recv_timeout(aged.deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(aged) => queue.push_back(aged),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let now = Instant::now();
+            while queue.front().map_or(false, |aged| aged.deadline <= now) {
+                let mut aged = queue.pop_front().unwrap();
+                if aged.waiting.started() {
+                    continue;
+                }
+                let next = aged.priority.higher().unwrap();
+                if aged.waiting.clone().spawn_on(self.pool(next)).is_ok() {
+                    THREADPOOL_PROMOTED_TASK_VEC
+                        .with_label_values(&[&self.name, aged.priority.as_str()])
+                        .inc();
+                    aged.priority = next;
+                }
+                if aged.priority.higher().is_some() {
+                    aged.deadline = now + self.promote_after;
+                    queue.push_back(aged);
+                }
+            }
         }
     }
 }
+
+/// Tasks running on `pool` or queued for one of its workers. A task only
+/// counts as running once a worker polls it, so a pool is starved when
+/// this is over its workers.
+fn tasks(pool: &FuturePool) -> usize {
+    pool.get_running_task_count() + pool.get_queued_task_count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cibo_util::config::ReadableDuration;
+    use futures::executor::block_on;
+
+    // Every test names its pool, the metrics it checks are labelled by it.
+    fn config(
+        name: &str,
+        steal_when_idle: bool,
+        promote_after: ReadableDuration,
+    ) -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            name: name.to_owned(),
+            high_concurrency: 1,
+            normal_concurrency: 1,
+            low_concurrency: 1,
+            steal_when_idle,
+            promote_after,
+            ..ThreadPoolConfig::default_for_test()
+        }
+    }
+
+    // Keeps the only worker of `pri` busy until the sender is dropped.
+    fn block(pool: &ThreadPool, pri: Priority) -> (mpsc::Sender<()>, TaskHandle<()>) {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let handle = pool
+            .spawn(
+                async move {
+                    started_tx.send(()).unwrap();
+                    let _ = release_rx.recv();
+                },
+                pri,
+            )
+            .unwrap();
+        started_rx.recv().unwrap();
+        (release_tx, handle)
+    }
+
+    // Spawns a low task that sends the name of the thread it ran on, which
+    // tells the pool that took it.
+    fn spawn_low(pool: &ThreadPool) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        let _handle = pool
+            .spawn(
+                async move {
+                    let name = thread::current().name().unwrap().to_owned();
+                    tx.send(name).unwrap();
+                },
+                Priority::LOW,
+            )
+            .unwrap();
+        rx
+    }
+
+    // Long enough to never fail a working pool, only bounds a broken one.
+    const GIVE_UP: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_steal_when_idle() {
+        let name = "test_steal_when_idle";
+        let pool = ThreadPool::new(config(name, true, ReadableDuration::secs(0)));
+        let (release, blocker) = block(&pool, Priority::LOW);
+        // Runs while the low worker is still blocked.
+        let runner = spawn_low(&pool).recv_timeout(GIVE_UP).unwrap();
+        assert_eq!(runner, "test_steal_when_idle-normal");
+        let stolen = THREADPOOL_STOLEN_TASK_VEC.with_label_values(&[name, "low"]);
+        assert_eq!(stolen.get(), 1);
+        drop(release);
+        block_on(blocker).unwrap();
+    }
+
+    #[test]
+    fn test_promote_after() {
+        let name = "test_promote_after";
+        let pool = ThreadPool::new(config(name, false, ReadableDuration::millis(100)));
+        let (release_high, high) = block(&pool, Priority::HIGH);
+        let (release_low, low) = block(&pool, Priority::LOW);
+        let start = Instant::now();
+        // Only the normal pool is free to start it, once it waited long
+        // enough.
+        let runner = spawn_low(&pool).recv_timeout(GIVE_UP).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(runner, "test_promote_after-normal");
+        drop(release_low);
+        drop(release_high);
+        block_on(low).unwrap();
+        block_on(high).unwrap();
+    }
+
+    #[test]
+    fn test_no_aging() {
+        let name = "test_no_aging";
+        let pool = ThreadPool::new(config(name, false, ReadableDuration::secs(0)));
+        assert!(pool.aging.is_none());
+        let (release, blocker) = block(&pool, Priority::LOW);
+        let done = spawn_low(&pool);
+        // Nothing else takes it while the low worker is blocked.
+        assert!(done.try_recv().is_err());
+        assert_eq!(pool.pool(Priority::LOW).get_queued_task_count(), 1);
+        drop(release);
+        block_on(blocker).unwrap();
+        assert_eq!(done.recv_timeout(GIVE_UP).unwrap(), "test_no_aging-low");
+        let promoted = THREADPOOL_PROMOTED_TASK_VEC.with_label_values(&[name, "low"]);
+        let stolen = THREADPOOL_STOLEN_TASK_VEC.with_label_values(&[name, "low"]);
+        assert_eq!((promoted.get(), stolen.get()), (0, 0));
+    }
+}
@@ -0,0 +1,23 @@
+use prometheus::*;
+
+lazy_static! {
+    pub static ref THREADPOOL_QUEUE_WAIT_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "threadpool_queue_wait_seconds",
+        "Bucketed histogram of time from spawn to start by task priority",
+        &["name", "priority"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref THREADPOOL_STOLEN_TASK_VEC: IntCounterVec = register_int_counter_vec!(
+        "threadpool_stolen_task_total",
+        "Number of tasks sent to an idle higher priority pool instead of their own",
+        &["name", "priority"]
+    )
+    .unwrap();
+    pub static ref THREADPOOL_PROMOTED_TASK_VEC: IntCounterVec = register_int_counter_vec!(
+        "threadpool_promoted_task_total",
+        "Number of waiting tasks also offered to the next priority pool",
+        &["name", "priority"]
+    )
+    .unwrap();
+}
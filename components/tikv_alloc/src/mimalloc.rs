@@ -1,7 +1,40 @@
 pub use crate::default::*;
 
-pub type Allocator = mimallocator::Mimalloc;
+use crate::AllocStats;
+use std::io;
+
+pub type Allocator = mimalloc_rs::MiMalloc;
 
 pub const fn allocator() -> Allocator {
-    mimallocator::Mimalloc
+    mimalloc_rs::MiMalloc
+}
+
+pub fn fetch_stats() -> io::Result<Option<AllocStats>> {
+    let mut elapsed_msecs = 0;
+    let mut user_msecs = 0;
+    let mut system_msecs = 0;
+    let mut current_rss = 0;
+    let mut peak_rss = 0;
+    let mut current_commit = 0;
+    let mut peak_commit = 0;
+    let mut page_faults = 0;
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed_msecs,
+            &mut user_msecs,
+            &mut system_msecs,
+            &mut current_rss,
+            &mut peak_rss,
+            &mut current_commit,
+            &mut peak_commit,
+            &mut page_faults,
+        );
+    }
+    Ok(Some(vec![
+        ("resident", current_rss),
+        ("peak_resident", peak_rss),
+        ("committed", current_commit),
+        ("peak_committed", peak_commit),
+        ("page_faults", page_faults),
+    ]))
 }
@@ -1,7 +1,70 @@
 pub use crate::default::*;
 
+use crate::AllocStats;
+use libc::{c_char, c_int, size_t};
+use std::io;
+
 pub type Allocator = tcmalloc::TCMalloc;
 
 pub const fn allocator() -> Allocator {
     tcmalloc::TCMalloc
 }
+
+extern "C" {
+    // From gperftools' malloc_extension_c.h.
+    fn MallocExtension_GetNumericProperty(property: *const c_char, value: *mut size_t) -> c_int;
+}
+
+fn numeric_property(property: &'static [u8]) -> Option<usize> {
+    debug_assert_eq!(property.last(), Some(&0));
+    let mut value: size_t = 0;
+    let found = unsafe {
+        MallocExtension_GetNumericProperty(property.as_ptr() as *const c_char, &mut value)
+    };
+    if found != 0 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+pub fn fetch_stats() -> io::Result<Option<AllocStats>> {
+    let allocated = numeric_property(b"generic.current_allocated_bytes\0");
+    let heap_size = numeric_property(b"generic.heap_size\0");
+    let (allocated, heap_size) = match (allocated, heap_size) {
+        (Some(allocated), Some(heap_size)) => (allocated, heap_size),
+        _ => return Ok(None),
+    };
+    let unmapped = numeric_property(b"tcmalloc.pageheap_unmapped_bytes\0").unwrap_or(0);
+    let mut stats = vec![
+        ("allocated", allocated),
+        ("heap_size", heap_size),
+        ("unmapped", unmapped),
+        // Memory held by tcmalloc that is neither in use nor returned to
+        // the OS.
+        (
+            "fragmentation",
+            heap_size.saturating_sub(unmapped).saturating_sub(allocated),
+        ),
+    ];
+    for &(name, property) in &[
+        ("pageheap_free", &b"tcmalloc.pageheap_free_bytes\0"[..]),
+        (
+            "central_cache_free",
+            &b"tcmalloc.central_cache_free_bytes\0"[..],
+        ),
+        (
+            "transfer_cache_free",
+            &b"tcmalloc.transfer_cache_free_bytes\0"[..],
+        ),
+        (
+            "thread_cache_free",
+            &b"tcmalloc.thread_cache_free_bytes\0"[..],
+        ),
+    ] {
+        if let Some(value) = numeric_property(property) {
+            stats.push((name, value));
+        }
+    }
+    Ok(Some(stats))
+}
@@ -1,9 +1,7 @@
 use std::io;
-use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use chrono::Local;
 use cibo_util::logger;
 use greenhouse::config::Config;
 
@@ -21,15 +19,6 @@ macro_rules! fatal {
     })
 }
 
-// TODO: There is a very small chance that duplicate files will be generated if there are
-// a lot of logs written in a very short time. Consider rename the rotated file with a version
-// number while rotate by size.
-fn rename_by_timestamp(path: &Path) -> io::Result<PathBuf> {
-    let mut new_path = path.to_path_buf().into_os_string();
-    new_path.push(format!("{}", Local::now().format("%Y-%m-%d-%H:%M:%S%.f")));
-    Ok(PathBuf::from(new_path))
-}
-
 pub fn initial_logger(config: &Config) {
     if config.log_file.is_empty() {
         let drainer = slog_json::Json::default(io::stdout());
@@ -42,7 +31,8 @@ pub fn initial_logger(config: &Config) {
             &config.log_file,
             config.log_rotation_timespan,
             config.log_rotation_size,
-            rename_by_timestamp,
+            config.log_retention(),
+            logger::rename_by_sequence,
         )
         .unwrap_or_else(|e| {
             fatal!(
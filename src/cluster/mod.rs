@@ -0,0 +1,209 @@
+//! Spreads keys over a static set of peers. Every node takes requests for
+//! any key, serves the keys it owns from its own storage and proxies the
+//! rest to their owners.
+//!
+//! Requests between peers carry the cluster secret, a request without it
+//! is placed like any other even if it claims to come from a peer.
+//!
+//! Forwarded requests carry the `Authorization` of the client. Peers that
+//! require client certificates see the certificate of the forwarding node,
+//! set by `client-cert-path`, instead of the one of the client.
+
+mod ring;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use awc::error::SendRequestError;
+use awc::{Client, ClientRequest, ClientResponse, Connector};
+use futures::future::join_all;
+use rustls::ClientConfig;
+
+use crate::config::ClusterConfig;
+use crate::metrics::CLUSTER_REQUESTS;
+
+pub use self::ring::Ring;
+
+/// Holds the cluster secret on requests between peers, which are always
+/// served locally.
+pub const FORWARDED_HEADER: &str = "x-greenhouse-forwarded";
+
+/// Where a key lives, as seen by the node that took the request.
+pub struct Placement {
+    /// This node is one of the owners.
+    pub local: bool,
+    /// The other owners, primary first.
+    pub peers: Vec<String>,
+}
+
+/// The view of the cluster from one worker. It holds an `awc` client, so
+/// there is one per worker, sharing the ring.
+pub struct Cluster {
+    ring: Arc<Ring>,
+    advertise_url: String,
+    replication_factor: usize,
+    timeout: Duration,
+    secret: String,
+    client: Client,
+}
+
+impl Cluster {
+    /// `tls` is used for https peers, see `tls::peer_client_config`.
+    pub fn new(cfg: &ClusterConfig, ring: Arc<Ring>, tls: Option<Arc<ClientConfig>>) -> Cluster {
+        let client = match tls {
+            Some(tls) => Client::builder()
+                .connector(Connector::new().rustls(tls))
+                .timeout(cfg.timeout.0)
+                .finish(),
+            None => Client::builder().timeout(cfg.timeout.0).finish(),
+        };
+        Cluster {
+            ring,
+            advertise_url: cfg.advertise_url.clone(),
+            replication_factor: cfg.replication_factor,
+            timeout: cfg.timeout.0,
+            secret: cfg.secret.clone(),
+            client,
+        }
+    }
+
+    /// `None` for requests from a peer, which must not travel further.
+    pub fn placement(&self, req: &HttpRequest, key: &str) -> Option<Placement> {
+        if self.is_from_peer(req) {
+            return None;
+        }
+        let owners = self.ring.owners(key, self.replication_factor);
+        Some(Placement {
+            local: owners.contains(&self.advertise_url.as_str()),
+            peers: owners
+                .into_iter()
+                .filter(|owner| *owner != self.advertise_url)
+                .map(ToOwned::to_owned)
+                .collect(),
+        })
+    }
+
+    fn is_from_peer(&self, req: &HttpRequest) -> bool {
+        let value = match req.headers().get(FORWARDED_HEADER) {
+            Some(value) => value.as_bytes(),
+            None => return false,
+        };
+        // Compared in full, so the time taken tells nothing of the secret.
+        value.len() == self.secret.len()
+            && value
+                .iter()
+                .zip(self.secret.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn request(&self, req: &HttpRequest, method: Method, peer: &str, key: &str) -> ClientRequest {
+        let mut forward = self
+            .client
+            .request(method, format!("{}/{}", peer, key))
+            .insert_header((FORWARDED_HEADER, self.secret.as_str()));
+        // The peer checks the credentials of the client again.
+        if let Some(auth) = req.headers().get(AUTHORIZATION) {
+            forward = forward.insert_header((AUTHORIZATION, auth.clone()));
+        }
+        forward
+    }
+
+    /// Reads from the first of `peers` that has the key, streaming the body.
+    pub async fn proxy_read(&self, req: &HttpRequest, peers: &[String], key: &str) -> HttpResponse {
+        for peer in peers {
+            match self.request(req, Method::GET, peer, key).send().await {
+                Ok(res) if res.status() == StatusCode::OK => {
+                    CLUSTER_REQUESTS.with_label_values(&["read", "ok"]).inc();
+                    return HttpResponse::Ok()
+                        .content_type("text/plain")
+                        .streaming(res.timeout(self.timeout));
+                }
+                Ok(res) if res.status() == StatusCode::NOT_FOUND => {
+                    CLUSTER_REQUESTS.with_label_values(&["read", "miss"]).inc();
+                }
+                Ok(res) => {
+                    CLUSTER_REQUESTS.with_label_values(&["read", "error"]).inc();
+                    warn!(
+                        "peer read failed";
+                        "peer" => peer,
+                        "key" => key,
+                        "status" => %res.status(),
+                    );
+                }
+                Err(e) => {
+                    CLUSTER_REQUESTS.with_label_values(&["read", "error"]).inc();
+                    warn!("peer read failed"; "peer" => peer, "key" => key, "err" => %e);
+                }
+            }
+        }
+        HttpResponse::NotFound().finish()
+    }
+
+    /// Writes to all `peers` at once, returns how many stored the blob.
+    pub async fn replicate_write(
+        &self,
+        req: &HttpRequest,
+        peers: &[String],
+        key: &str,
+        body: Bytes,
+    ) -> usize {
+        let writes = peers.iter().map(|peer| {
+            let forward = self.request(req, Method::PUT, peer, key);
+            let body = body.clone();
+            async move { (peer, forward.send_body(body).await) }
+        });
+        self.count_ok("write", key, join_all(writes).await)
+    }
+
+    /// Deletes from all `peers` at once, returns how many did.
+    pub async fn replicate_delete(&self, req: &HttpRequest, peers: &[String], key: &str) -> usize {
+        let deletes = peers.iter().map(|peer| {
+            let forward = self.request(req, Method::DELETE, peer, key);
+            async move { (peer, forward.send().await) }
+        });
+        self.count_ok("delete", key, join_all(deletes).await)
+    }
+
+    fn count_ok<S>(
+        &self,
+        op: &str,
+        key: &str,
+        results: Vec<(&String, Result<ClientResponse<S>, SendRequestError>)>,
+    ) -> usize {
+        let mut ok = 0;
+        for (peer, res) in results {
+            match res {
+                Ok(res) if res.status().is_success() => {
+                    CLUSTER_REQUESTS.with_label_values(&[op, "ok"]).inc();
+                    ok += 1;
+                }
+                Ok(res) => {
+                    CLUSTER_REQUESTS.with_label_values(&[op, "error"]).inc();
+                    warn!(
+                        "peer request failed";
+                        "op" => op,
+                        "peer" => peer,
+                        "key" => key,
+                        "status" => %res.status(),
+                    );
+                }
+                Err(e) => {
+                    CLUSTER_REQUESTS.with_label_values(&[op, "error"]).inc();
+                    warn!(
+                        "peer request failed";
+                        "op" => op,
+                        "peer" => peer,
+                        "key" => key,
+                        "err" => %e,
+                    );
+                }
+            }
+        }
+        ok
+    }
+}
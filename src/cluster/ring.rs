@@ -0,0 +1,112 @@
+//! A consistent-hash ring over the digest of a key.
+
+// FNV-1a, then the splitmix64 finalizer to spread close inputs. Both are
+// fixed, so every node places keys the same way.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// The digest of `ac/<digest>` or `instance/cas/<digest>`, so an action and
+/// the blobs it names don't need to live together but every key has one
+/// stable position.
+fn digest(key: &str) -> &str {
+    key.rsplit('/').find(|s| !s.is_empty()).unwrap_or(key)
+}
+
+pub struct Ring {
+    nodes: Vec<String>,
+    // Sorted points on the ring, to the index of their node.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// Each node gets `virtual_nodes` points, which evens out the share of
+    /// keys each node owns.
+    pub fn new(nodes: Vec<String>, virtual_nodes: usize) -> Ring {
+        let mut points = Vec::with_capacity(nodes.len() * virtual_nodes);
+        for (i, node) in nodes.iter().enumerate() {
+            for v in 0..virtual_nodes {
+                points.push((hash(format!("{}#{}", node, v).as_bytes()), i));
+            }
+        }
+        points.sort_unstable();
+        Ring { nodes, points }
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// The first `n` distinct nodes clockwise from the key, the first one is
+    /// the primary owner.
+    pub fn owners(&self, key: &str, n: usize) -> Vec<&str> {
+        let n = n.min(self.nodes.len());
+        let mut owners: Vec<&str> = Vec::with_capacity(n);
+        if n == 0 {
+            return owners;
+        }
+        let h = hash(digest(key).as_bytes());
+        let start = self.points.partition_point(|(p, _)| *p < h);
+        for i in 0..self.points.len() {
+            let node = &self.nodes[self.points[(start + i) % self.points.len()].1];
+            if !owners.contains(&node.as_str()) {
+                owners.push(node.as_str());
+                if owners.len() == n {
+                    break;
+                }
+            }
+        }
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("http://node{}:8080", i)).collect()
+    }
+
+    #[test]
+    fn test_owners() {
+        let ring = Ring::new(nodes(3), 64);
+        let owners = ring.owners("ac/0123abcd", 2);
+        assert_eq!(owners.len(), 2);
+        assert_ne!(owners[0], owners[1]);
+        // Placed by digest alone.
+        assert_eq!(ring.owners("instance/cas/0123abcd", 2), owners);
+        assert_eq!(ring.owners("ac/0123abcd", 5).len(), 3);
+    }
+
+    #[test]
+    fn test_balance_and_stability() {
+        let ring = Ring::new(nodes(4), 128);
+        let mut counts = vec![0; 4];
+        for i in 0..10000 {
+            let owner = ring.owners(&format!("cas/{:x}", i), 1)[0];
+            counts[ring.nodes().iter().position(|n| n == owner).unwrap()] += 1;
+        }
+        for count in counts {
+            assert!(count > 1500 && count < 3500, "unbalanced {}", count);
+        }
+
+        // Adding a node only moves keys onto it.
+        let grown = Ring::new(nodes(5), 128);
+        for i in 0..1000 {
+            let key = format!("cas/{:x}", i);
+            let before = ring.owners(&key, 1)[0];
+            let after = grown.owners(&key, 1)[0];
+            assert!(before == after || after == "http://node4:8080");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs;
@@ -6,7 +7,10 @@ use std::path::Path;
 
 use cibo_util::config::ReadableDuration;
 use cibo_util::config::ReadableSize;
+use cibo_util::logger::{Compression, Retention};
+use moni_middleware::{AccessLogFormat, RegexSet};
 use storage::config::StorageConfig;
+use storage::Namespace;
 
 pub const DEFAULT_LISTENING_ADDR: &str = "127.0.0.1:20160";
 
@@ -21,8 +25,20 @@ pub struct Config {
     pub backtrace_dir: String,
     pub log_rotation_timespan: ReadableDuration,
     pub log_rotation_size: ReadableSize,
+    pub log_rotation_compression: Compression,
+    // Rotated files beyond the newest `log-rotation-max-files`, or older than
+    // `log-rotation-max-age`, are deleted. Zero keeps them.
+    pub log_rotation_max_files: usize,
+    pub log_rotation_max_age: ReadableDuration,
     // Server listening address.
     pub metric: MetricConfig,
+    pub health: HealthConfig,
+    pub access_log: AccessLogConfig,
+    pub slow_log: SlowLogConfig,
+    pub auth: AuthConfig,
+    pub upstream: UpstreamConfig,
+    pub cluster: ClusterConfig,
+    pub tracing: TracingConfig,
     pub storage: StorageConfig,
     pub http_service: HttpServer,
 }
@@ -36,7 +52,17 @@ impl Default for Config {
             path: "".to_owned(),
             log_rotation_timespan: ReadableDuration::hours(24),
             log_rotation_size: ReadableSize::mb(300),
+            log_rotation_compression: Compression::None,
+            log_rotation_max_files: 0,
+            log_rotation_max_age: ReadableDuration::secs(0),
             metric: MetricConfig::default(),
+            health: HealthConfig::default(),
+            access_log: AccessLogConfig::default(),
+            slow_log: SlowLogConfig::default(),
+            auth: AuthConfig::default(),
+            upstream: UpstreamConfig::default(),
+            cluster: ClusterConfig::default(),
+            tracing: TracingConfig::default(),
             storage: StorageConfig::default(),
             http_service: HttpServer::default(),
         }
@@ -44,6 +70,14 @@ impl Default for Config {
 }
 
 impl Config {
+    pub fn log_retention(&self) -> Retention {
+        Retention {
+            compression: self.log_rotation_compression,
+            max_files: self.log_rotation_max_files,
+            max_age: self.log_rotation_max_age,
+        }
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> Self
     where
         P: fmt::Debug,
@@ -96,12 +130,323 @@ pub mod log_level_serde {
 #[serde(rename_all = "kebab-case")]
 pub struct MetricConfig {
     pub address: String,
+    pub threads: CollectorConfig,
+    pub process: CollectorConfig,
+    pub allocator: CollectorConfig,
+    pub tls: TlsConfig,
 }
 
 impl Default for MetricConfig {
     fn default() -> MetricConfig {
         MetricConfig {
             address: "0.0.0.0:8091".to_owned(),
+            threads: CollectorConfig::default(),
+            process: CollectorConfig {
+                enabled: false,
+                ..CollectorConfig::default()
+            },
+            allocator: CollectorConfig {
+                enabled: false,
+                ..CollectorConfig::default()
+            },
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct CollectorConfig {
+    // A section that leaves out `enabled` turns the collector on.
+    pub enabled: bool,
+    pub namespace: String,
+    // Scrapes within `interval` of the last read reuse its result.
+    pub interval: ReadableDuration,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> CollectorConfig {
+        CollectorConfig {
+            enabled: true,
+            namespace: "greenhouse".to_owned(),
+            interval: ReadableDuration::secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthConfig {
+    // `/readyz` fails once free space on the cache disk drops below this.
+    pub min_free_space: ReadableSize,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            min_free_space: ReadableSize::gb(1),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessLogConfig {
+    // Access lines go to the application log when `file` is empty.
+    pub file: String,
+    // `json`, `common`, `combined`, or a template such as `%a %r %s %b %T`.
+    pub format: String,
+    // Ratio of 2xx lines that are written, other lines are always written.
+    pub sample_success: f64,
+    pub exclude: Vec<String>,
+    pub exclude_regex: Vec<String>,
+    pub rotation_timespan: ReadableDuration,
+    pub rotation_size: ReadableSize,
+    pub rotation_compression: Compression,
+    pub rotation_max_files: usize,
+    pub rotation_max_age: ReadableDuration,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> AccessLogConfig {
+        AccessLogConfig {
+            file: "".to_owned(),
+            format: "combined".to_owned(),
+            sample_success: 1.0,
+            exclude: vec![],
+            exclude_regex: vec![],
+            rotation_timespan: ReadableDuration::hours(24),
+            rotation_size: ReadableSize::mb(300),
+            rotation_compression: Compression::None,
+            rotation_max_files: 0,
+            rotation_max_age: ReadableDuration::secs(0),
+        }
+    }
+}
+
+impl AccessLogConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        AccessLogFormat::parse(&self.format)?;
+        self.exclude_regex_set()?;
+        Ok(())
+    }
+
+    pub fn exclude_regex_set(&self) -> Result<RegexSet, String> {
+        RegexSet::new(&self.exclude_regex)
+            .map_err(|e| format!("invalid access-log exclude-regex: {}", e))
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            compression: self.rotation_compression,
+            max_files: self.rotation_max_files,
+            max_age: self.rotation_max_age,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SlowLogConfig {
+    // Slow requests go to the application log when `file` is empty.
+    pub file: String,
+    // A zero threshold turns the slow log off for that operation. The write
+    // threshold starts once the body is received, a slow upload is the
+    // client's.
+    pub read_threshold: ReadableDuration,
+    pub write_threshold: ReadableDuration,
+    pub delete_threshold: ReadableDuration,
+    pub rotation_timespan: ReadableDuration,
+    pub rotation_size: ReadableSize,
+    pub rotation_compression: Compression,
+    pub rotation_max_files: usize,
+    pub rotation_max_age: ReadableDuration,
+}
+
+impl Default for SlowLogConfig {
+    fn default() -> SlowLogConfig {
+        SlowLogConfig {
+            file: "".to_owned(),
+            read_threshold: ReadableDuration::millis(500),
+            write_threshold: ReadableDuration::secs(1),
+            delete_threshold: ReadableDuration::millis(500),
+            rotation_timespan: ReadableDuration::hours(24),
+            rotation_size: ReadableSize::mb(300),
+            rotation_compression: Compression::None,
+            rotation_max_files: 0,
+            rotation_max_age: ReadableDuration::secs(0),
+        }
+    }
+}
+
+impl SlowLogConfig {
+    pub fn retention(&self) -> Retention {
+        Retention {
+            compression: self.rotation_compression,
+            max_files: self.rotation_max_files,
+            max_age: self.rotation_max_age,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthConfig {
+    pub enabled: bool,
+    // `user:bcrypt-hash` lines, as written by `htpasswd -B`.
+    pub htpasswd_file: String,
+    // `token principal` lines.
+    pub tokens_file: String,
+    // Lets requests without credentials read.
+    pub anonymous_read: bool,
+    // Of principals not listed in `permissions`.
+    pub default_permissions: Vec<String>,
+    // Principal to any of `read`, `write` and `delete`.
+    pub permissions: HashMap<String, Vec<String>>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            enabled: false,
+            htpasswd_file: "".to_owned(),
+            tokens_file: "".to_owned(),
+            anonymous_read: false,
+            default_permissions: vec!["read".to_owned()],
+            permissions: HashMap::new(),
+        }
+    }
+}
+
+/// Another cache that local misses are fetched from, disabled when `url`
+/// is empty.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpstreamConfig {
+    pub url: String,
+    pub timeout: ReadableDuration,
+    // Also copies local writes upstream.
+    pub write_back: bool,
+    // Sent as the `Authorization` header, such as `Bearer <token>`.
+    pub authorization: String,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> UpstreamConfig {
+        UpstreamConfig {
+            url: "".to_owned(),
+            timeout: ReadableDuration::secs(10),
+            write_back: false,
+            authorization: "".to_owned(),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.url.is_empty()
+    }
+}
+
+/// Peers sharing the keyspace, disabled when `peers` is empty.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClusterConfig {
+    // The url of this node as listed in `peers`, such as `http://10.0.0.1:8080`.
+    pub advertise_url: String,
+    // Every node, this one included, in the same order on every node.
+    pub peers: Vec<String>,
+    // Number of nodes each key is written to.
+    pub replication_factor: usize,
+    // Points per node on the hash ring.
+    pub virtual_nodes: usize,
+    pub timeout: ReadableDuration,
+    // The same on every node. Peers send it on the requests they forward,
+    // which are then served locally.
+    pub secret: String,
+    // PEM CAs that sign the certificates of https peers.
+    pub ca_path: String,
+    // PEM certificate and key shown to peers that require client
+    // certificates. Peers map it to a principal, which needs the
+    // permissions of the requests it forwards.
+    pub client_cert_path: String,
+    pub client_key_path: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> ClusterConfig {
+        ClusterConfig {
+            advertise_url: "".to_owned(),
+            peers: vec![],
+            replication_factor: 1,
+            virtual_nodes: 128,
+            timeout: ReadableDuration::secs(10),
+            secret: "".to_owned(),
+            ca_path: "".to_owned(),
+            client_cert_path: "".to_owned(),
+            client_key_path: "".to_owned(),
+        }
+    }
+}
+
+impl ClusterConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.peers.contains(&self.advertise_url) {
+            return Err(format!(
+                "cluster advertise-url {} is not one of the peers",
+                self.advertise_url
+            ));
+        }
+        if self.replication_factor == 0 || self.replication_factor > self.peers.len() {
+            return Err(format!(
+                "cluster replication-factor should be between 1 and {}",
+                self.peers.len()
+            ));
+        }
+        if self.secret.is_empty() {
+            return Err("cluster secret is required".to_owned());
+        }
+        if self.client_cert_path.is_empty() != self.client_key_path.is_empty() {
+            return Err("cluster client-cert-path and client-key-path go together".to_owned());
+        }
+        if !self.client_cert_path.is_empty() && self.ca_path.is_empty() {
+            return Err("cluster client-cert-path needs ca-path".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TracingConfig {
+    // `none`, `otlp` or `json`. Only takes effect with the `tracing` feature.
+    pub exporter: String,
+    pub otlp_endpoint: String,
+    pub json_file: String,
+    pub service_name: String,
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> TracingConfig {
+        TracingConfig {
+            exporter: "none".to_owned(),
+            otlp_endpoint: "http://127.0.0.1:4317".to_owned(),
+            json_file: "./traces.json".to_owned(),
+            service_name: "greenhouse".to_owned(),
+            sample_ratio: 1.0,
         }
     }
 }
@@ -115,6 +460,8 @@ pub struct HttpServer {
     pub keepalive: ReadableDuration,
     pub client_timeout: ReadableDuration,
     pub client_shutdown: ReadableDuration,
+    pub max_blob_size: BlobSizeLimit,
+    pub tls: TlsConfig,
 }
 
 impl Default for HttpServer {
@@ -125,6 +472,93 @@ impl Default for HttpServer {
             keepalive: ReadableDuration::secs(10),
             client_timeout: ReadableDuration::millis(5000),
             client_shutdown: ReadableDuration::millis(5000),
+            max_blob_size: BlobSizeLimit::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+/// TLS of a listener, which serves plain HTTP when `cert_path` is empty.
+/// The files are read again on SIGHUP.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Default)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    // PEM certificate chain and private key.
+    pub cert_path: String,
+    pub key_path: String,
+    // PEM CAs that sign client certificates, enables mTLS.
+    pub client_ca_path: String,
+    // Also accepts clients without a certificate.
+    pub client_auth_optional: bool,
+    // Client certificate subject, as `CN=ci, O=example`, to principal. Other
+    // subjects map to their common name.
+    pub principals: HashMap<String, String>,
+}
+
+impl TlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.cert_path.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cert_path.is_empty() != self.key_path.is_empty() {
+            return Err("tls cert-path and key-path go together".to_owned());
+        }
+        if !self.client_ca_path.is_empty() && !self.is_enabled() {
+            return Err("tls client-ca-path needs cert-path and key-path".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlobSizeLimit {
+    pub ac: ReadableSize,
+    pub cas: ReadableSize,
+    pub other: ReadableSize,
+}
+
+impl BlobSizeLimit {
+    pub fn limit(&self, namespace: Namespace) -> u64 {
+        match namespace {
+            Namespace::Ac => self.ac.0,
+            Namespace::Cas => self.cas.0,
+            Namespace::Other => self.other.0,
         }
     }
 }
+
+impl Default for BlobSizeLimit {
+    fn default() -> BlobSizeLimit {
+        BlobSizeLimit {
+            ac: ReadableSize::mb(64),
+            cas: ReadableSize::mb(64),
+            other: ReadableSize::mb(64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_tls() {
+        let tls = |cert: &str, key: &str, client_ca: &str| TlsConfig {
+            cert_path: cert.to_owned(),
+            key_path: key.to_owned(),
+            client_ca_path: client_ca.to_owned(),
+            ..TlsConfig::default()
+        };
+        tls("", "", "").validate().unwrap();
+        tls("a.crt", "a.key", "").validate().unwrap();
+        tls("a.crt", "a.key", "ca.crt").validate().unwrap();
+        assert!(tls("a.crt", "", "").validate().is_err());
+        assert!(tls("", "a.key", "").validate().is_err());
+        // Client certificates without a listener certificate.
+        assert!(tls("", "", "ca.crt").validate().is_err());
+    }
+}
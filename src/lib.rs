@@ -5,6 +5,11 @@ extern crate slog_global;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod cluster;
 pub mod config;
 pub mod metrics;
 pub mod route;
+pub mod slow_log;
+pub mod tls;
+pub mod trace;
+pub mod upstream;
@@ -1,5 +1,5 @@
 use prometheus::*;
-use prometheus::{Counter, Gauge};
+use prometheus::{Counter, Gauge, IntCounterVec};
 
 lazy_static! {
     pub static ref FILES_EVICTED: Counter = register_counter!(opts!(
@@ -32,4 +32,34 @@ lazy_static! {
         "Hours since last access of most recently evicted file (at eviction time)"
     ))
     .unwrap();
+    pub static ref PAYLOAD_TOO_LARGE: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_payload_too_large_total",
+        "Number of uploads rejected for exceeding max-blob-size",
+        &["namespace", "reason"]
+    )
+    .unwrap();
+    pub static ref SLOW_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_slow_requests_total",
+        "Number of storage requests over the slow-log threshold of their operation",
+        &["op"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_READS: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_upstream_reads_total",
+        "Number of local misses looked up upstream, by hit, miss or error",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_WRITE_BACKS: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_upstream_write_backs_total",
+        "Number of local writes copied upstream, by ok or error",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref CLUSTER_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "greenhouse_cluster_requests_total",
+        "Number of requests sent to peers, by operation and ok, miss or error",
+        &["op", "result"]
+    )
+    .unwrap();
 }
@@ -0,0 +1,227 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use storage::{get_disk_usage, Storage};
+use tokio::fs;
+
+use crate::config::HealthConfig;
+
+const PROBE_FILE: &str = ".greenhouse_ready_probe";
+
+#[derive(Serialize)]
+struct FailedCheck {
+    check: &'static str,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    failed: Vec<FailedCheck>,
+}
+
+pub async fn healthz(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+pub async fn readyz(
+    _req: HttpRequest,
+    storage: web::Data<Storage>,
+    cfg: web::Data<HealthConfig>,
+) -> HttpResponse {
+    let storage = storage.get_ref();
+    let mut failed = vec![];
+
+    let probe = storage.basic_path().join(PROBE_FILE);
+    let writable = match fs::write(&probe, b"probe").await {
+        Ok(()) => fs::remove_file(&probe).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = writable {
+        failed.push(FailedCheck {
+            check: "cache_dir_writable",
+            reason: e.to_string(),
+        });
+    }
+
+    match get_disk_usage(storage.basic_path()) {
+        Some((_, bytes_free, _)) if bytes_free < cfg.min_free_space.0 => {
+            failed.push(FailedCheck {
+                check: "free_space",
+                reason: format!(
+                    "{} bytes free, need at least {}",
+                    bytes_free, cfg.min_free_space.0
+                ),
+            })
+        }
+        Some(_) => {}
+        None => failed.push(FailedCheck {
+            check: "free_space",
+            reason: "failed to get disk usage".to_owned(),
+        }),
+    }
+
+    if storage.is_read_only() {
+        failed.push(FailedCheck {
+            check: "read_only",
+            reason: "disk is over the hard limit, writes are rejected".to_owned(),
+        });
+    }
+
+    let saturated = storage.saturated_pools();
+    if !saturated.is_empty() {
+        failed.push(FailedCheck {
+            check: "threadpool",
+            reason: format!("saturated pools: {}", saturated.join(",")),
+        });
+    }
+
+    if failed.is_empty() {
+        HttpResponse::Ok().json(Readiness {
+            status: "ok",
+            failed,
+        })
+    } else {
+        warn!("readiness check failed"; "failed" => failed.len());
+        HttpResponse::ServiceUnavailable().json(Readiness {
+            status: "unavailable",
+            failed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::sync::Arc;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use cibo_util::config::ReadableSize;
+    use serde_json::Value;
+    use storage::backend::MemoryBackend;
+    use storage::config::StorageConfig;
+    use threadpool::Priority;
+
+    // Pools are named after the test, their metrics are shared by the
+    // whole process.
+    fn storage(
+        dir: &tempfile::TempDir,
+        name: &str,
+        hard_limit_watermark: f64,
+    ) -> web::Data<Storage> {
+        let mut config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            hard_limit_watermark,
+            ..StorageConfig::default()
+        };
+        config.reading_threadpool.name = format!("{}-reading", name);
+        config.writing_threadpool.name = format!("{}-writing", name);
+        web::Data::new(Storage::with_backend(
+            config,
+            Arc::new(MemoryBackend::new()),
+        ))
+    }
+
+    // Returns the status of `/readyz` and the names of the failed checks.
+    async fn ready(
+        storage: &web::Data<Storage>,
+        min_free_space: ReadableSize,
+    ) -> (StatusCode, Vec<String>) {
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .app_data(web::Data::new(HealthConfig { min_free_space }))
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz)),
+        )
+        .await;
+        let req = TestRequest::get().uri("/healthz").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status();
+        let body: Value = test::read_body_json(resp).await;
+        let failed = body["failed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["check"].as_str().unwrap().to_owned())
+            .collect();
+        (status, failed)
+    }
+
+    #[actix_rt::test]
+    async fn test_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir, "test_ready", 1.0);
+        let (status, failed) = ready(&storage, ReadableSize(0)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(failed.is_empty(), "{:?}", failed);
+    }
+
+    #[actix_rt::test]
+    async fn test_low_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir, "test_low_free_space", 1.0);
+        let (status, failed) = ready(&storage, ReadableSize(u64::MAX)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(failed, vec!["free_space"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        // Any used byte is over a zero limit, the storage starts read-only.
+        let storage = storage(&dir, "test_read_only", 0.0);
+        let (status, failed) = ready(&storage, ReadableSize(0)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(failed, vec!["read_only"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_cache_dir_not_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir, "test_cache_dir_not_writable", 1.0);
+        // Permissions don't stop root, a missing dir stops everyone.
+        std::fs::remove_dir_all(storage.basic_path()).unwrap();
+        let (status, failed) = ready(&storage, ReadableSize(0)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            failed.contains(&"cache_dir_writable".to_owned()),
+            "{:?}",
+            failed
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_saturated_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir, "test_saturated_pool", 1.0);
+        let reading = storage.thread_pools()[0];
+        reading.resize(Priority::LOW, None, Some(1)).unwrap();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let blocker = reading
+            .spawn(
+                async move {
+                    started_tx.send(()).unwrap();
+                    let _ = release_rx.recv();
+                },
+                Priority::LOW,
+            )
+            .unwrap();
+        started_rx.recv().unwrap();
+
+        let (status, failed) = ready(&storage, ReadableSize(0)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(failed, vec!["threadpool"]);
+
+        drop(release_tx);
+        blocker.await.unwrap();
+        let (status, _) = ready(&storage, ReadableSize(0)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
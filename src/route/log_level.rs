@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use cibo_util::config::ReadableDuration;
+use cibo_util::logger::{self, get_level_by_string, get_string_by_level};
+
+#[derive(Serialize)]
+struct LogLevel {
+    level: &'static str,
+    // Set while a temporary level is in effect.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revert_to: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revert_in_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SetLogLevel {
+    level: String,
+    ttl: Option<ReadableDuration>,
+}
+
+fn current() -> LogLevel {
+    let revert = logger::get_log_level_revert();
+    LogLevel {
+        level: get_string_by_level(logger::get_log_level()),
+        revert_to: revert.map(|(level, _)| get_string_by_level(level)),
+        revert_in_secs: revert
+            .map(|(_, at)| at.saturating_duration_since(Instant::now()).as_secs()),
+    }
+}
+
+pub async fn get_log_level(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(current())
+}
+
+/// Sets the global log level, for good or for `ttl` when given.
+pub async fn set_log_level(_req: HttpRequest, body: web::Json<SetLogLevel>) -> HttpResponse {
+    let level = match get_level_by_string(&body.level) {
+        Some(level) => level,
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body(format!("unknown log level {}", body.level))
+        }
+    };
+    match body.ttl {
+        Some(ttl) if ttl.0.as_secs() > 0 => {
+            logger::set_log_level_with_ttl(level, ttl.0);
+            info!("log level changed"; "level" => &body.level, "ttl" => %ttl);
+        }
+        _ => {
+            logger::set_log_level(level);
+            info!("log level changed"; "level" => &body.level);
+        }
+    }
+    HttpResponse::Ok().json(current())
+}
@@ -1,25 +1,42 @@
+mod health;
+mod log_level;
 mod metric;
+mod profile;
 mod storage_handle;
+mod thread_pool;
 
+use std::any::Any;
 use std::convert::TryInto;
 use std::net;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time;
 use std::time::Duration;
 
+use actix_web::dev::{Extensions, Server, ServerHandle};
 use actix_web::web::Data;
-use actix_web::{dev::ServerHandle, rt, web, App, HttpServer};
-use moni_middleware::Moni;
+use actix_web::{guard, rt, web, App, HttpServer};
+use auth_middleware::{Auth, Credentials, Permissions};
+use cibo_util::config::{ReadableDuration, ReadableSize};
+use cibo_util::logger::{self, Retention, RotateBySize, RotateByTime, RotatingFileLoggerBuilder};
+use moni_middleware::{AccessLogFormat, LogWriter, Moni, RegexSet};
 use net2::TcpBuilder;
 use storage::{DiskMetric, LazygcServer, Storage};
 
-
-
-use crate::config::Config;
+use crate::cluster::{Cluster, Ring};
+use crate::config::{
+    AccessLogConfig, AuthConfig, Config, HealthConfig, MetricConfig, SlowLogConfig, TlsConfig,
+};
+use crate::route::health::{healthz, readyz};
+use crate::route::log_level::{get_log_level, set_log_level};
 use crate::route::metric::metric;
+use crate::route::profile::{allocator_stats, heap_profile};
 use crate::route::storage_handle::{delete, read, write};
+use crate::route::thread_pool::{get_thread_pools, set_thread_pool};
+use crate::slow_log::SlowLog;
+use crate::tls::{self, ReloadableTls};
+use crate::upstream::Upstream;
 
 #[inline]
 fn unused_addr(address: String) -> net::SocketAddr {
@@ -31,23 +48,166 @@ fn unused_addr(address: String) -> net::SocketAddr {
     tcp.local_addr().unwrap()
 }
 
-async fn run_app(_tx: mpsc::Sender<ServerHandle>, cfg: &Config) -> std::io::Result<()> {
-    info!("listen to {}", &cfg.http_service.addr);
-    let storage_config = cfg.storage.clone();
+fn rotating_writer(
+    name: &str,
+    file: &str,
+    rotation_timespan: ReadableDuration,
+    rotation_size: ReadableSize,
+    retention: Retention,
+) -> Option<LogWriter> {
+    if file.is_empty() {
+        return None;
+    }
+    let logger = RotatingFileLoggerBuilder::new(file, logger::rename_by_sequence)
+        .add_rotator(RotateByTime::new(rotation_timespan))
+        .add_rotator(RotateBySize::new(rotation_size))
+        .retention(retention)
+        .build()
+        .unwrap_or_else(|e| panic!("failed to open log {}: {}", file, e));
+    let writer = LogWriter::new(name, logger)
+        .unwrap_or_else(|e| panic!("failed to start writer of {}: {}", file, e));
+    Some(writer)
+}
+
+fn access_log_writer(cfg: &AccessLogConfig) -> Option<LogWriter> {
+    rotating_writer(
+        "access-log",
+        &cfg.file,
+        cfg.rotation_timespan,
+        cfg.rotation_size,
+        cfg.retention(),
+    )
+}
+
+fn slow_log(cfg: &SlowLogConfig) -> SlowLog {
+    let writer = rotating_writer(
+        "slow-log",
+        &cfg.file,
+        cfg.rotation_timespan,
+        cfg.rotation_size,
+        cfg.retention(),
+    );
+    SlowLog::new(cfg, writer)
+}
+
+fn auth(cfg: &AuthConfig) -> Auth {
+    if !cfg.enabled {
+        return Auth::disabled();
+    }
+    let permissions = |names: &[String]| {
+        Permissions::parse(names).unwrap_or_else(|e| panic!("invalid auth permissions: {}", e))
+    };
+    let mut credentials = Credentials::new(permissions(&cfg.default_permissions));
+    if !cfg.htpasswd_file.is_empty() {
+        credentials
+            .load_htpasswd(&cfg.htpasswd_file)
+            .unwrap_or_else(|e| panic!("failed to load {}: {}", cfg.htpasswd_file, e));
+    }
+    if !cfg.tokens_file.is_empty() {
+        credentials
+            .load_tokens(&cfg.tokens_file)
+            .unwrap_or_else(|e| panic!("failed to load {}: {}", cfg.tokens_file, e));
+    }
+    for (principal, names) in &cfg.permissions {
+        credentials.set_permissions(principal.clone(), permissions(names));
+    }
+    Auth::new(credentials, cfg.anonymous_read)
+}
+
+/// Loads the TLS of a listener and reloads it on SIGHUP. Must be called
+/// inside the runtime of the listener.
+fn listener_tls(cfg: &TlsConfig, name: &'static str) -> Option<Arc<ReloadableTls>> {
+    cfg.validate()
+        .unwrap_or_else(|e| panic!("invalid TLS config of {} listener: {}", name, e));
+    if !cfg.is_enabled() {
+        return None;
+    }
+    let tls = ReloadableTls::new(cfg)
+        .unwrap_or_else(|e| panic!("failed to load TLS of {} listener: {}", name, e));
+    rt::spawn(tls::reload_on_sighup(name, tls.clone()));
+    Some(tls)
+}
+
+/// `cfg` must have been validated.
+fn moni(cfg: &AccessLogConfig, exclude_regex: &RegexSet, writer: &Option<LogWriter>) -> Moni {
+    let mut moni = Moni::new()
+        .log_format(AccessLogFormat::parse(&cfg.format).unwrap())
+        .sample_success(cfg.sample_success)
+        .exclude_regex(exclude_regex.clone());
+    for path in &cfg.exclude {
+        moni = moni.exclude(path.clone());
+    }
+    if let Some(writer) = writer {
+        moni = moni.access_log(writer.clone());
+    }
+    moni
+}
+
+/// Builds the storage listener of a node, bound and ready to run. Must be
+/// called inside an actix system.
+pub fn http_server(cfg: &Config, storage: Data<Storage>) -> Server {
+    let blob_size_limit = Data::new(cfg.http_service.max_blob_size.clone());
+    let access_log_config = cfg.access_log.clone();
+    access_log_config
+        .validate()
+        .unwrap_or_else(|e| panic!("invalid access log config: {}", e));
+    let exclude_regex = access_log_config.exclude_regex_set().unwrap();
+    let access_log = access_log_writer(&access_log_config);
+    let slow_log = Data::new(slow_log(&cfg.slow_log));
+    let auth = auth(&cfg.auth);
+    let tls = listener_tls(&cfg.http_service.tls, "http");
+    let conn_tls = tls.clone();
+    let upstream = Some(cfg.upstream.clone()).filter(|u| u.is_enabled());
+    if let Some(upstream) = &upstream {
+        info!("reading through upstream {}", upstream.url);
+    }
+    let cluster = Some(cfg.cluster.clone())
+        .filter(|c| c.is_enabled())
+        .map(|c| {
+            c.validate()
+                .unwrap_or_else(|e| panic!("invalid cluster config: {}", e));
+            info!("joining cluster"; "peers" => c.peers.join(","), "self" => &c.advertise_url);
+            let ring = Arc::new(Ring::new(c.peers.clone(), c.virtual_nodes));
+            let peer_tls = tls::peer_client_config(&c)
+                .unwrap_or_else(|e| panic!("failed to load TLS of cluster peers: {}", e));
+            (c, ring, peer_tls)
+        });
 
     // srv is server controller type, `dev::Server`
     let listener = unused_addr(cfg.http_service.addr.clone());
     let server = HttpServer::new(move || {
-        App::new()
-            .wrap(Moni::new())
-            .app_data(web::PayloadConfig::new(1 << 26))
-            .app_data(Data::new(Storage::new(storage_config.clone())))
-            .service(
-                web::resource("/{tail:.*}")
-                    .route(web::get().to(read))
-                    .route(web::put().to(write))
-                    .route(web::delete().to(delete)),
-            )
+        let app = App::new()
+            // Inside `Moni`, so rejected requests are logged and counted too.
+            .wrap(auth.clone())
+            .wrap(moni(&access_log_config, &exclude_regex, &access_log))
+            .app_data(storage.clone())
+            .app_data(blob_size_limit.clone())
+            .app_data(slow_log.clone());
+        // The `awc` client is bound to the worker thread.
+        let app = match &upstream {
+            Some(cfg) => app.app_data(Data::new(Upstream::new(
+                cfg,
+                blob_size_limit.get_ref().clone(),
+            ))),
+            None => app,
+        };
+        let app = match &cluster {
+            Some((cfg, ring, peer_tls)) => {
+                app.app_data(Data::new(Cluster::new(cfg, ring.clone(), peer_tls.clone())))
+            }
+            None => app,
+        };
+        app.service(
+            web::resource("/{tail:.*}")
+                .route(web::get().to(read))
+                .route(web::put().to(write))
+                .route(web::delete().to(delete)),
+        )
+    })
+    .on_connect(move |conn: &dyn Any, ext: &mut Extensions| {
+        if let Some(tls) = &conn_tls {
+            tls.on_connect(conn, ext);
+        }
     })
     .workers(cfg.http_service.http_worker)
     .client_request_timeout(Duration::from_millis(
@@ -58,19 +218,92 @@ async fn run_app(_tx: mpsc::Sender<ServerHandle>, cfg: &Config) -> std::io::Resu
     ))
     .keep_alive(Duration::from_secs(
         cfg.http_service.keepalive.as_secs().try_into().unwrap(),
-    ))
-    .bind(format!("{}", listener))
+    ));
+    match tls {
+        Some(tls) => server.bind_rustls(format!("{}", listener), tls.server_config()),
+        None => server.bind(format!("{}", listener)),
+    }
     .unwrap_or_else(|_| panic!("Can not bind to {}", &cfg.http_service.addr))
-    .run();
-    server.await
+    .run()
+}
+
+async fn run_app(
+    _tx: mpsc::Sender<ServerHandle>,
+    cfg: &Config,
+    storage: Data<Storage>,
+) -> std::io::Result<()> {
+    info!("listen to {}", &cfg.http_service.addr);
+    crate::trace::init(&cfg.tracing)
+        .unwrap_or_else(|e| panic!("failed to initialize tracing: {}", e));
+    http_server(cfg, storage).await
 }
 
-async fn run_metrics(metric_address: String) -> std::io::Result<()> {
-    let server = HttpServer::new(move || App::new().route("/prometheus", web::get().to(metric)))
-        .workers(1)
-        .bind(metric_address.clone())
-        .unwrap_or_else(move |_| panic!("Can not bind to {}", metric_address))
-        .run();
+fn start_collectors(cfg: &MetricConfig) {
+    if cfg.threads.enabled {
+        cibo_util::metrics::monitor_threads(&*cfg.threads.namespace, cfg.threads.interval.0)
+            .unwrap_or_else(|e| crit!("failed to start monitor thread: {}", e));
+    }
+    if cfg.process.enabled {
+        cibo_util::metrics::monitor_process(&*cfg.process.namespace, cfg.process.interval.0)
+            .unwrap_or_else(|e| crit!("failed to start monitor process: {}", e));
+    }
+    if cfg.allocator.enabled {
+        cibo_util::metrics::monitor_allocator_stats(
+            &*cfg.allocator.namespace,
+            cfg.allocator.interval.0,
+        )
+        .unwrap_or_else(|e| crit!("failed to start monitor allocator: {}", e));
+    }
+}
+
+async fn run_metrics(
+    metric_address: String,
+    tls: TlsConfig,
+    auth_config: AuthConfig,
+    health_config: HealthConfig,
+    storage: Data<Storage>,
+) -> std::io::Result<()> {
+    let health_config = Data::new(health_config);
+    let tls = listener_tls(&tls, "metric");
+    // Changing the node takes the write permission of the storage listener,
+    // so does a heap dump, which shows blobs and credentials in memory.
+    let auth = auth(&auth_config);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(storage.clone())
+            .app_data(health_config.clone())
+            .route("/prometheus", web::get().to(metric))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+            .service(
+                web::resource("/debug/pprof/heap")
+                    .guard(guard::Get())
+                    .wrap(auth.clone().require(Permissions::WRITE))
+                    .to(heap_profile),
+            )
+            .route("/debug/allocator/stats", web::get().to(allocator_stats))
+            .route("/debug/log-level", web::get().to(get_log_level))
+            .service(
+                web::resource("/debug/log-level")
+                    .guard(guard::Put())
+                    .wrap(auth.clone())
+                    .to(set_log_level),
+            )
+            .route("/debug/thread-pools", web::get().to(get_thread_pools))
+            .service(
+                web::resource("/debug/thread-pools")
+                    .guard(guard::Put())
+                    .wrap(auth.clone())
+                    .to(set_thread_pool),
+            )
+    })
+    .workers(1);
+    let server = match tls {
+        Some(tls) => server.bind_rustls(metric_address.clone(), tls.server_config()),
+        None => server.bind(metric_address.clone()),
+    }
+    .unwrap_or_else(move |_| panic!("Can not bind to {}", metric_address))
+    .run();
     server.await
 }
 
@@ -79,15 +312,35 @@ pub fn run(cfg: Config) {
     let pathbuf = Path::new(&storage_config.cache_dir).to_path_buf();
     let ten_millis = time::Duration::from_secs(2);
     let mut metric_backend = DiskMetric::new(ten_millis, pathbuf.clone());
-    let mut lazygc_backend = LazygcServer::new(pathbuf.clone(), 0.8, 0.6);
+    let mut lazygc_backend = LazygcServer::new(
+        pathbuf.clone(),
+        storage_config.gc_high_watermark,
+        storage_config.gc_low_watermark,
+    );
     let metric_address = cfg.metric.address.clone();
+    let metric_tls = cfg.metric.tls.clone();
+    let auth_config = cfg.auth.clone();
+    let health_config = cfg.health.clone();
+    // One storage is shared by every http worker and the admin listener, so
+    // readiness sees the same pools that serve traffic.
+    let compact_live_ratio = storage_config.log_store.compact_live_ratio;
+    let storage = Data::new(Storage::new(storage_config));
+    if let Some(log_store) = storage.log_store() {
+        lazygc_backend.log_store(log_store.clone(), compact_live_ratio);
+    }
+    let metric_storage = storage.clone();
     metric_backend.start().unwrap();
     lazygc_backend.start().unwrap();
-    cibo_util::metrics::monitor_threads("greenhouse")
-        .unwrap_or_else(|e| crit!("failed to start monitor thread: {}", e));
+    start_collectors(&cfg.metric);
     let (tx, rx) = mpsc::channel();
     thread::spawn(move || {
-        let server_future = run_metrics(metric_address.clone());
+        let server_future = run_metrics(
+            metric_address.clone(),
+            metric_tls,
+            auth_config,
+            health_config,
+            metric_storage,
+        );
         rt::System::with_tokio_rt(|| {
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
@@ -98,7 +351,7 @@ pub fn run(cfg: Config) {
         .block_on(server_future)
     });
 
-    let server_future = run_app(tx, &cfg);
+    let server_future = run_app(tx, &cfg, storage);
     rt::System::with_tokio_rt(|| {
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use tikv_alloc::error::ProfError;
+use tokio::fs;
+
+const DEFAULT_PROFILE_SECONDS: u64 = 10;
+const MAX_PROFILE_SECONDS: u64 = 600;
+
+// jemalloc has one global profiler, only one dump runs at a time.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Turns profiling off and releases the slot, also when the request is
+/// dropped half way.
+struct ProfilingGuard;
+
+impl Drop for ProfilingGuard {
+    fn drop(&mut self) {
+        match tikv_alloc::deactivate_prof() {
+            Ok(()) | Err(ProfError::MemProfilingNotEnabled) => {}
+            Err(e) => warn!("failed to deactivate heap profiling"; "err" => e.to_string()),
+        }
+        PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+}
+
+fn prof_error_response(e: ProfError) -> HttpResponse {
+    match e {
+        ProfError::MemProfilingNotEnabled => HttpResponse::NotImplemented()
+            .content_type("text/plain")
+            .body("heap profiling is unavailable, build greenhouse with `--features mem-profiling` and run with MALLOC_CONF=prof:true"),
+        e => HttpResponse::InternalServerError()
+            .content_type("text/plain")
+            .body(e.to_string()),
+    }
+}
+
+async fn profile(seconds: u64) -> Result<Vec<u8>, ProfError> {
+    tikv_alloc::activate_prof()?;
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    let dir = tempfile::Builder::new().prefix("heap_profile").tempdir()?;
+    let path = dir.path().join("heap.prof");
+    let path = path
+        .into_os_string()
+        .into_string()
+        .map_err(ProfError::PathEncodingError)?;
+    tikv_alloc::dump_prof(&path)?;
+    Ok(fs::read(&path).await?)
+}
+
+/// Turns on heap profiling for `seconds`, then returns the dumped profile.
+pub async fn heap_profile(_req: HttpRequest, query: web::Query<ProfileQuery>) -> HttpResponse {
+    let seconds = query
+        .seconds
+        .unwrap_or(DEFAULT_PROFILE_SECONDS)
+        .min(MAX_PROFILE_SECONDS);
+    if PROFILING.swap(true, Ordering::SeqCst) {
+        return HttpResponse::Conflict()
+            .content_type("text/plain")
+            .body("another heap profile is running");
+    }
+    let _guard = ProfilingGuard;
+    info!("start heap profiling"; "seconds" => seconds);
+    match profile(seconds).await {
+        Ok(data) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(data),
+        Err(e) => {
+            error!("failed to profile heap"; "err" => e.to_string());
+            prof_error_response(e)
+        }
+    }
+}
+
+pub async fn allocator_stats(_req: HttpRequest) -> HttpResponse {
+    let stats = tikv_alloc::dump_stats();
+    if stats.is_empty() {
+        return HttpResponse::NotImplemented()
+            .content_type("text/plain")
+            .body("allocator stats are only available with jemalloc");
+    }
+    HttpResponse::Ok().content_type("text/plain").body(stats)
+}
@@ -1,23 +1,112 @@
+use std::io::ErrorKind;
+
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::StatusCode;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use futures::StreamExt;
-use storage::Storage;
+use storage::{Namespace, Storage};
+
+use crate::cluster::{Cluster, Placement};
+use crate::config::BlobSizeLimit;
+use crate::metrics::PAYLOAD_TOO_LARGE;
+use crate::slow_log::{Op, SlowLog};
+use crate::upstream::Upstream;
+
+/// Where the key lives, `None` outside a cluster or for requests from a peer,
+/// which are served locally.
+fn placement<'a>(
+    cluster: &'a Option<web::Data<Cluster>>,
+    req: &HttpRequest,
+    key: &str,
+) -> Option<(&'a Cluster, Placement)> {
+    let cluster = cluster.as_ref()?;
+    cluster.placement(req, key).map(|p| (cluster.get_ref(), p))
+}
+
+fn no_owner_stored(op: &str) -> HttpResponse {
+    HttpResponse::BadGateway()
+        .content_type("text/plain")
+        .body(format!("no owner of the key could {} it", op))
+}
 
-pub async fn delete<'a>(req: HttpRequest, storage: web::Data<Storage>) -> HttpResponse {
+fn payload_too_large(namespace: Namespace, reason: &str, limit: u64) -> HttpResponse {
+    PAYLOAD_TOO_LARGE
+        .with_label_values(&[namespace.as_str(), reason])
+        .inc();
+    HttpResponse::PayloadTooLarge()
+        .content_type("text/plain")
+        .body(format!(
+            "blob exceeds max-blob-size {} of {}",
+            limit,
+            namespace.as_str()
+        ))
+}
+
+pub async fn delete<'a>(
+    req: HttpRequest,
+    storage: web::Data<Storage>,
+    slow_log: web::Data<SlowLog>,
+    cluster: Option<web::Data<Cluster>>,
+) -> HttpResponse {
     let mut url = req.uri().to_string();
     url.remove(0);
-    let data = storage.get_ref().delete(url).await;
+    let placement = placement(&cluster, &req, &url);
+    let replicated = match &placement {
+        Some((cluster, p)) => cluster.replicate_delete(&req, &p.peers, &url).await,
+        None => 0,
+    };
+    if let Some((_, Placement { local: false, .. })) = placement {
+        return match replicated {
+            0 => no_owner_stored("delete"),
+            _ => HttpResponse::Ok().content_type("text/plain").finish(),
+        };
+    }
+    let timer = slow_log.timer(Op::Delete);
+    let (data, stats) = storage.get_ref().delete_with_stats(url.clone()).await;
+    slow_log.observe(Op::Delete, &timer, &req, &url, &stats, data.as_ref().err());
     match data {
         Ok(()) => HttpResponse::Ok().content_type("text/plain").finish(),
+        Err(_) if replicated > 0 => HttpResponse::Ok().content_type("text/plain").finish(),
         Err(e) => HttpResponse::BadRequest().body(e.to_string()),
     }
 }
-pub async fn read<'a>(req: HttpRequest, storage: web::Data<Storage>) -> HttpResponse {
+pub async fn read<'a>(
+    req: HttpRequest,
+    storage: web::Data<Storage>,
+    slow_log: web::Data<SlowLog>,
+    upstream: Option<web::Data<Upstream>>,
+    cluster: Option<web::Data<Cluster>>,
+) -> HttpResponse {
     let mut url = req.uri().to_string();
     url.remove(0);
-    let data = storage.get_ref().read(url).await;
-    match data {
-        Ok(result) => HttpResponse::Ok().content_type("text/plain").body(result),
-        Err(e) => {
+    let placement = placement(&cluster, &req, &url);
+    if let Some((cluster, p)) = &placement {
+        if !p.local {
+            return cluster.proxy_read(&req, &p.peers, &url).await;
+        }
+    }
+    let timer = slow_log.timer(Op::Read);
+    let (data, stats) = storage.get_ref().read_with_stats(url.clone()).await;
+    slow_log.observe(Op::Read, &timer, &req, &url, &stats, data.as_ref().err());
+    match (data, upstream) {
+        (Ok(result), _) => HttpResponse::Ok().content_type("text/plain").body(result),
+        (Err(e), upstream) if e.kind() == ErrorKind::NotFound => {
+            // A write this node missed, while down or full, is still on the
+            // other owners.
+            if let Some((cluster, p)) = &placement {
+                if !p.peers.is_empty() {
+                    let res = cluster.proxy_read(&req, &p.peers, &url).await;
+                    if res.status() != StatusCode::NOT_FOUND {
+                        return res;
+                    }
+                }
+            }
+            match upstream {
+                Some(upstream) => upstream.read_through(url, storage).await,
+                None => HttpResponse::NotFound().finish(),
+            }
+        }
+        (Err(e), _) => {
             error!("fail to read";"err" => e.to_string(),"url" => req.uri().to_string());
             HttpResponse::NotFound().finish()
         }
@@ -28,21 +117,199 @@ pub async fn write<'a>(
     req: HttpRequest,
     mut body: web::Payload,
     storage: web::Data<Storage>,
+    limits: web::Data<BlobSizeLimit>,
+    slow_log: web::Data<SlowLog>,
+    upstream: Option<web::Data<Upstream>>,
+    cluster: Option<web::Data<Cluster>>,
 ) -> Result<HttpResponse, Error> {
     let mut url = req.uri().to_string();
     url.remove(0);
+    let placement = placement(&cluster, &req, &url);
+    let local = placement.as_ref().map_or(true, |(_, p)| p.local);
+    // Reject before the body is streamed in, reads are still served.
+    if local && storage.get_ref().is_read_only() {
+        return Ok(HttpResponse::InsufficientStorage()
+            .content_type("text/plain")
+            .body("storage is read-only, disk is over the hard limit"));
+    }
+
+    let namespace = Namespace::from_path(req.path());
+    let limit = limits.limit(namespace);
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(len) = content_length {
+        if len > limit {
+            return Ok(payload_too_large(namespace, "content_length", limit));
+        }
+    }
 
     let mut buf = web::BytesMut::new();
     while let Some(item) = body.next().await {
         buf.extend_from_slice(&item?);
+        // Chunked uploads have no Content-Length, cut them off as soon as
+        // they grow past the limit.
+        if buf.len() as u64 > limit {
+            return Ok(payload_too_large(namespace, "stream", limit));
+        }
     }
-    match storage.get_ref().write(buf.to_vec(), url.clone()).await {
-        Ok(_) => Ok(HttpResponse::Ok().into()),
+    let buf = buf.freeze();
+    // Peers forwarding a write have copied it upstream already.
+    let upstream = upstream.filter(|_| cluster.is_none() || placement.is_some());
+    let replicate = async {
+        match &placement {
+            Some((cluster, p)) => {
+                cluster
+                    .replicate_write(&req, &p.peers, &url, buf.clone())
+                    .await
+            }
+            None => 0,
+        }
+    };
+    if !local {
+        if replicate.await == 0 {
+            return Ok(no_owner_stored("store"));
+        }
+        if let Some(upstream) = upstream {
+            upstream.write_back(url, buf);
+        }
+        return Ok(HttpResponse::Ok().into());
+    }
+
+    let timer = slow_log.timer(Op::Write);
+    let (replicated, (res, stats)) = futures::join!(
+        replicate,
+        storage
+            .get_ref()
+            .write_with_stats(buf.to_vec(), url.clone())
+    );
+    slow_log.observe(Op::Write, &timer, &req, &url, &stats, res.as_ref().err());
+    match res {
+        Ok(_) => {
+            if let Some(upstream) = upstream {
+                upstream.write_back(url, buf);
+            }
+            Ok(HttpResponse::Ok().into())
+        }
         Err(e) => {
             error!("fail to writing";"url" => url,"err" => e.to_string());
+            if replicated > 0 {
+                return Ok(HttpResponse::Ok().into());
+            }
             Ok(HttpResponse::BadRequest()
                 .content_type("text/plain")
                 .body(e.to_string()))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use cibo_util::config::ReadableSize;
+    use storage::backend::MemoryBackend;
+    use storage::config::StorageConfig;
+
+    use crate::config::SlowLogConfig;
+
+    // A macro, the type of the service can't be named.
+    macro_rules! service {
+        ($storage:expr, $limits:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($storage))
+                    .app_data(web::Data::new($limits))
+                    .app_data(web::Data::new(SlowLog::new(
+                        &SlowLogConfig::default(),
+                        None,
+                    )))
+                    .service(
+                        web::resource("/{tail:.*}")
+                            .route(web::get().to(read))
+                            .route(web::put().to(write)),
+                    ),
+            )
+            .await
+        };
+    }
+
+    fn storage(dir: &tempfile::TempDir, hard_limit_watermark: f64) -> Storage {
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            hard_limit_watermark,
+            ..StorageConfig::default()
+        };
+        Storage::with_backend(config, Arc::new(MemoryBackend::new()))
+    }
+
+    #[actix_rt::test]
+    async fn test_write_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        // Any used byte is over a zero limit, the storage starts read-only.
+        let app = service!(storage(&dir, 0.0), BlobSizeLimit::default());
+        let req = TestRequest::put()
+            .uri("/cas/abc")
+            .set_payload("blob")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    #[actix_rt::test]
+    async fn test_write_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = BlobSizeLimit {
+            cas: ReadableSize::kb(1),
+            ..BlobSizeLimit::default()
+        };
+        let app = service!(storage(&dir, 1.0), limits);
+        let too_large = |reason: &str| {
+            PAYLOAD_TOO_LARGE
+                .with_label_values(&[Namespace::Cas.as_str(), reason])
+                .get()
+        };
+        let (by_length, by_stream) = (too_large("content_length"), too_large("stream"));
+
+        // Refused by its Content-Length, the body is never read.
+        let req = TestRequest::put()
+            .uri("/cas/declared")
+            .set_payload("blob")
+            .insert_header((CONTENT_LENGTH, "1025"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(too_large("content_length"), by_length + 1);
+
+        // Claims to fit, cut off once the body grows past the limit.
+        let req = TestRequest::put()
+            .uri("/cas/streamed")
+            .set_payload(vec![0; 1025])
+            .insert_header((CONTENT_LENGTH, "1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(too_large("stream"), by_stream + 1);
+
+        // Exactly the limit is fine.
+        let req = TestRequest::put()
+            .uri("/cas/fits")
+            .set_payload(vec![7; 1024])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = TestRequest::get().uri("/cas/fits").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, vec![7; 1024]);
+        for key in &["/cas/declared", "/cas/streamed"] {
+            let req = TestRequest::get().uri(key).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", key);
+        }
+    }
+}
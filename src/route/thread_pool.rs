@@ -0,0 +1,162 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use storage::Storage;
+use threadpool::{ClassLimits, Priority, ThreadPool};
+
+#[derive(Serialize)]
+struct ClassState {
+    priority: &'static str,
+    #[serde(flatten)]
+    limits: ClassLimits,
+    running_tasks: usize,
+}
+
+#[derive(Serialize)]
+struct PoolState {
+    name: String,
+    classes: Vec<ClassState>,
+}
+
+/// Leaves what is not given as is.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SetPoolLimits {
+    pool: String,
+    priority: String,
+    workers: Option<usize>,
+    max_tasks: Option<usize>,
+}
+
+fn state(pool: &ThreadPool) -> PoolState {
+    PoolState {
+        name: pool.name().to_owned(),
+        classes: Priority::ALL
+            .iter()
+            .map(|&pri| ClassState {
+                priority: pri.as_str(),
+                limits: pool.limits(pri),
+                running_tasks: pool.running_tasks(pri),
+            })
+            .collect(),
+    }
+}
+
+fn bad_request(msg: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("text/plain")
+        .body(msg)
+}
+
+pub async fn get_thread_pools(_req: HttpRequest, storage: web::Data<Storage>) -> HttpResponse {
+    let pools: Vec<PoolState> = storage.thread_pools().iter().map(|p| state(p)).collect();
+    HttpResponse::Ok().json(pools)
+}
+
+/// Changes the workers or task limit of one priority class of a pool until
+/// restart, without dropping the tasks it runs.
+pub async fn set_thread_pool(
+    _req: HttpRequest,
+    storage: web::Data<Storage>,
+    body: web::Json<SetPoolLimits>,
+) -> HttpResponse {
+    let pools = storage.thread_pools();
+    let pool = match pools.iter().find(|p| p.name() == body.pool) {
+        Some(pool) => pool,
+        None => return bad_request(format!("unknown thread pool {}", body.pool)),
+    };
+    let pri = match Priority::ALL.iter().find(|p| p.as_str() == body.priority) {
+        Some(&pri) => pri,
+        None => return bad_request(format!("unknown priority {}", body.priority)),
+    };
+    match pool.resize(pri, body.workers, body.max_tasks) {
+        Ok(limits) => {
+            info!(
+                "thread pool resized";
+                "pool" => &body.pool,
+                "priority" => &body.priority,
+                "workers" => limits.workers,
+                "max_tasks" => limits.max_tasks,
+            );
+            HttpResponse::Ok().json(state(pool))
+        }
+        Err(e) => bad_request(format!("failed to resize {}: {}", body.pool, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use serde_json::json;
+    use storage::backend::MemoryBackend;
+    use storage::config::StorageConfig;
+    use threadpool::ThreadPoolConfig;
+
+    #[actix_rt::test]
+    async fn test_set_thread_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool_config = |name: &str| ThreadPoolConfig {
+            name: name.to_owned(),
+            ..ThreadPoolConfig::default_for_test()
+        };
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            reading_threadpool: pool_config("reading"),
+            writing_threadpool: pool_config("writing"),
+            ..StorageConfig::default()
+        };
+        let storage = web::Data::new(Storage::with_backend(
+            config,
+            Arc::new(MemoryBackend::new()),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(storage.clone())
+                .route("/debug/thread-pools", web::put().to(set_thread_pool)),
+        )
+        .await;
+        let put = |body: serde_json::Value| {
+            TestRequest::put()
+                .uri("/debug/thread-pools")
+                .set_json(body)
+                .to_request()
+        };
+
+        for body in vec![
+            json!({"pool": "nope", "priority": "low", "workers": 3}),
+            json!({"pool": "writing", "priority": "urgent", "workers": 3}),
+            json!({"pool": "writing", "priority": "low", "max-tasks": 0}),
+        ] {
+            let resp = test::call_service(&app, put(body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        let writing = storage.thread_pools()[1];
+        assert_eq!(
+            writing.limits(Priority::LOW),
+            ClassLimits {
+                workers: 2,
+                max_tasks: 4000,
+            }
+        );
+
+        let resp = test::call_service(
+            &app,
+            put(json!({"pool": "writing", "priority": "low", "workers": 3, "max-tasks": 100})),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            writing.limits(Priority::LOW),
+            ClassLimits {
+                workers: 3,
+                max_tasks: 100,
+            }
+        );
+        // Only the class asked for changes.
+        assert_eq!(writing.limits(Priority::HIGH).workers, 2);
+    }
+}
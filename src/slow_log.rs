@@ -0,0 +1,188 @@
+use std::io;
+use std::time::Duration;
+
+use actix_web::HttpRequest;
+use cibo_util::logger::current_request_id;
+use cibo_util::time::SlowTimer;
+use moni_middleware::LogWriter;
+use storage::OpStats;
+
+use crate::config::SlowLogConfig;
+use crate::metrics::SLOW_REQUESTS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Read,
+    Write,
+    Delete,
+}
+
+impl Op {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SlowEntry<'a> {
+    time: String,
+    op: &'static str,
+    key: &'a str,
+    size: u64,
+    pool: Option<&'static str>,
+    total_ms: u128,
+    wait_ms: u128,
+    io_ms: u128,
+    codec_ms: u128,
+    client: Option<&'a str>,
+    request_id: Option<&'a str>,
+    error: Option<String>,
+}
+
+/// Records the storage requests that take longer than the threshold of their
+/// operation, with enough detail to tell a slow disk from a starved pool.
+pub struct SlowLog {
+    read: Duration,
+    write: Duration,
+    delete: Duration,
+    // Entries go to the application log when there is no writer.
+    writer: Option<LogWriter>,
+}
+
+impl SlowLog {
+    pub fn new(cfg: &SlowLogConfig, writer: Option<LogWriter>) -> SlowLog {
+        SlowLog {
+            read: cfg.read_threshold.0,
+            write: cfg.write_threshold.0,
+            delete: cfg.delete_threshold.0,
+            writer,
+        }
+    }
+
+    fn threshold(&self, op: Op) -> Duration {
+        match op {
+            Op::Read => self.read,
+            Op::Write => self.write,
+            Op::Delete => self.delete,
+        }
+    }
+
+    pub fn timer(&self, op: Op) -> SlowTimer {
+        SlowTimer::from(self.threshold(op))
+    }
+
+    /// Logs the request if `timer` is past the threshold of `op`. A zero
+    /// threshold turns the slow log off for `op`.
+    pub fn observe(
+        &self,
+        op: Op,
+        timer: &SlowTimer,
+        req: &HttpRequest,
+        key: &str,
+        stats: &OpStats,
+        error: Option<&io::Error>,
+    ) {
+        if self.threshold(op).as_nanos() == 0 || !timer.is_slow() {
+            return;
+        }
+        SLOW_REQUESTS.with_label_values(&[op.as_str()]).inc();
+        let conn = req.connection_info();
+        let request_id = current_request_id();
+        let entry = SlowEntry {
+            time: chrono::Local::now().to_rfc3339(),
+            op: op.as_str(),
+            key,
+            size: stats.size,
+            pool: stats.priority.map(|p| p.as_str()),
+            total_ms: timer.elapsed().as_millis(),
+            wait_ms: stats.wait.as_millis(),
+            io_ms: stats.io.as_millis(),
+            codec_ms: stats.codec.as_millis(),
+            client: conn.peer_addr(),
+            request_id: request_id.as_deref(),
+            error: error.map(|e| e.to_string()),
+        };
+        match &self.writer {
+            Some(writer) => writer.write(serde_json::to_string(&entry).unwrap()),
+            None => warn!(
+                "slow request";
+                "op" => entry.op,
+                "key" => entry.key,
+                "size" => entry.size,
+                "pool" => entry.pool.unwrap_or("none"),
+                "total_ms" => entry.total_ms as u64,
+                "wait_ms" => entry.wait_ms as u64,
+                "io_ms" => entry.io_ms as u64,
+                "codec_ms" => entry.codec_ms as u64,
+                "client" => entry.client.unwrap_or("-"),
+                "error" => entry.error,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use actix_web::test::TestRequest;
+    use cibo_util::config::ReadableDuration;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_observe() {
+        let cfg = SlowLogConfig {
+            read_threshold: ReadableDuration::millis(1),
+            write_threshold: ReadableDuration::hours(1),
+            delete_threshold: ReadableDuration::secs(0),
+            ..SlowLogConfig::default()
+        };
+        let buffer = Buffer::default();
+        let slow_log = SlowLog::new(
+            &cfg,
+            Some(LogWriter::new("slow-log", buffer.clone()).unwrap()),
+        );
+        let req = TestRequest::default().to_http_request();
+        let stats = OpStats::default();
+
+        let read = slow_log.timer(Op::Read);
+        let write = slow_log.timer(Op::Write);
+        let delete = slow_log.timer(Op::Delete);
+        thread::sleep(Duration::from_millis(20));
+        slow_log.observe(Op::Read, &read, &req, "cas/slow", &stats, None);
+        // Under its threshold.
+        slow_log.observe(Op::Write, &write, &req, "cas/fast", &stats, None);
+        // Turned off.
+        slow_log.observe(Op::Delete, &delete, &req, "cas/off", &stats, None);
+
+        // Writes out what is queued.
+        drop(slow_log);
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", written);
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry["op"], "read");
+        assert_eq!(entry["key"], "cas/slow");
+        assert!(entry["total_ms"].as_u64().unwrap() >= 1);
+    }
+}
@@ -0,0 +1,328 @@
+//! rustls server configuration whose certificate, key and client CA can be
+//! swapped while running. Handshakes after a reload use the new files,
+//! established connections are left alone.
+
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use auth_middleware::ClientCertPrincipal;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerified,
+    ClientCertVerifier, ClientHello, ResolvesServerCert,
+};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, ClientConfig, DistinguishedNames, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{ClusterConfig, TlsConfig};
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificate in {}", path)));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => {}
+            None => return Err(invalid_data(format!("no private key in {}", path))),
+        }
+    }
+}
+
+fn load_certified_key(cfg: &TlsConfig) -> io::Result<CertifiedKey> {
+    let certs = load_certs(&cfg.cert_path)?;
+    let key = load_key(&cfg.key_path)?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| invalid_data(format!("unsupported private key in {}", cfg.key_path)))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn load_client_verifier(cfg: &TlsConfig) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&cfg.client_ca_path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_data(format!("bad client CA {}: {}", cfg.client_ca_path, e)))?;
+    }
+    Ok(if cfg.client_auth_optional {
+        AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+    } else {
+        AllowAnyAuthenticatedClient::new(roots)
+    })
+}
+
+/// What a node uses to reach https peers, `None` if `ca-path` is not set.
+/// Unlike the listener, it is not reloaded.
+pub fn peer_client_config(cfg: &ClusterConfig) -> io::Result<Option<Arc<ClientConfig>>> {
+    if cfg.ca_path.is_empty() {
+        return Ok(None);
+    }
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&cfg.ca_path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_data(format!("bad peer CA {}: {}", cfg.ca_path, e)))?;
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = if cfg.client_cert_path.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let certs = load_certs(&cfg.client_cert_path)?;
+        let key = load_key(&cfg.client_key_path)?;
+        builder
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid_data(format!("bad client certificate: {}", e)))?
+    };
+    Ok(Some(Arc::new(config)))
+}
+
+/// The TLS state of one listener.
+pub struct ReloadableTls {
+    cfg: TlsConfig,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+    client_verifier: Option<RwLock<Arc<dyn ClientCertVerifier>>>,
+}
+
+impl ReloadableTls {
+    pub fn new(cfg: &TlsConfig) -> io::Result<Arc<ReloadableTls>> {
+        let client_verifier = if cfg.client_ca_path.is_empty() {
+            None
+        } else {
+            Some(RwLock::new(load_client_verifier(cfg)?))
+        };
+        Ok(Arc::new(ReloadableTls {
+            cfg: cfg.clone(),
+            certified_key: RwLock::new(Arc::new(load_certified_key(cfg)?)),
+            client_verifier,
+        }))
+    }
+
+    /// Reads the files again. Nothing changes unless all of them load.
+    pub fn reload(&self) -> io::Result<()> {
+        let certified_key = load_certified_key(&self.cfg)?;
+        let client_verifier = match self.client_verifier {
+            Some(_) => Some(load_client_verifier(&self.cfg)?),
+            None => None,
+        };
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        if let (Some(current), Some(verifier)) = (&self.client_verifier, client_verifier) {
+            *current.write().unwrap() = verifier;
+        }
+        Ok(())
+    }
+
+    /// Client auth is on when a client CA is configured at startup, a reload
+    /// can't turn it on or off.
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if self.client_verifier.is_some() {
+            builder.with_client_cert_verifier(Arc::new(ClientVerifier(self.clone())))
+        } else {
+            builder.with_no_client_auth()
+        };
+        builder.with_cert_resolver(Arc::new(CertResolver(self.clone())))
+    }
+
+    /// Maps the subject of the client certificate to a principal, through
+    /// `principals` or else by its common name. Installed as the `on_connect`
+    /// hook of the listener.
+    pub fn on_connect(&self, conn: &dyn Any, ext: &mut Extensions) {
+        let tls = match conn.downcast_ref::<TlsStream<TcpStream>>() {
+            Some(tls) => tls,
+            None => return,
+        };
+        let cert = match tls.get_ref().1.peer_certificates() {
+            Some(certs) if !certs.is_empty() => &certs[0],
+            _ => return,
+        };
+        if let Some(principal) = self.principal(cert) {
+            ext.insert(ClientCertPrincipal(Arc::from(principal)));
+        }
+    }
+
+    fn principal(&self, cert: &Certificate) -> Option<String> {
+        let cert = match x509_parser::parse_x509_certificate(&cert.0) {
+            Ok((_, cert)) => cert,
+            Err(e) => {
+                warn!("failed to parse client certificate"; "err" => %e);
+                return None;
+            }
+        };
+        let subject = cert.subject().to_string();
+        match self.cfg.principals.get(&subject) {
+            Some(principal) => Some(principal.clone()),
+            None => cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(ToOwned::to_owned),
+        }
+    }
+}
+
+struct CertResolver(Arc<ReloadableTls>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.certified_key.read().unwrap().clone())
+    }
+}
+
+struct ClientVerifier(Arc<ReloadableTls>);
+
+impl ClientVerifier {
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        // Only built when a client CA is configured.
+        self.0
+            .client_verifier
+            .as_ref()
+            .unwrap()
+            .read()
+            .unwrap()
+            .clone()
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.current().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        self.current().client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.current().client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.current()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
+/// Reloads `tls` on every SIGHUP, until the runtime stops.
+pub async fn reload_on_sighup(name: &'static str, tls: Arc<ReloadableTls>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("failed to listen to SIGHUP, certificates won't reload"; "err" => %e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match tls.reload() {
+            Ok(()) => info!("reloaded TLS certificates"; "listener" => name),
+            Err(e) => error!("failed to reload TLS certificates"; "listener" => name, "err" => %e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    // PEM files under tests/tls, signed by its ca.pem.
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn cert(name: &str) -> Certificate {
+        load_certs(&fixture(name)).unwrap().remove(0)
+    }
+
+    fn served_cert(tls: &ReloadableTls) -> Certificate {
+        tls.certified_key.read().unwrap().cert[0].clone()
+    }
+
+    // Copies the pair of `server` to where the listener loads it from.
+    fn install(dir: &Path, server: &str) {
+        fs::copy(fixture(&format!("{}.pem", server)), dir.join("server.pem")).unwrap();
+        fs::copy(fixture(&format!("{}.key", server)), dir.join("server.key")).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_reload_on_sighup() {
+        let dir = tempfile::tempdir().unwrap();
+        install(dir.path(), "server-a");
+        let cfg = TlsConfig {
+            cert_path: dir.path().join("server.pem").to_str().unwrap().to_owned(),
+            key_path: dir.path().join("server.key").to_str().unwrap().to_owned(),
+            client_ca_path: fixture("ca.pem"),
+            ..TlsConfig::default()
+        };
+        let tls = ReloadableTls::new(&cfg).unwrap();
+        assert_eq!(served_cert(&tls), cert("server-a.pem"));
+
+        // A broken file leaves the served certificate alone.
+        fs::write(&cfg.cert_path, "garbage").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(served_cert(&tls), cert("server-a.pem"));
+
+        install(dir.path(), "server-b");
+        // Keeps SIGHUP from ending the process until the task listens.
+        let _hangup = signal(SignalKind::hangup()).unwrap();
+        actix_web::rt::spawn(reload_on_sighup("test", tls.clone()));
+        let start = Instant::now();
+        while served_cert(&tls) != cert("server-b.pem") {
+            assert!(start.elapsed() < Duration::from_secs(10), "not reloaded");
+            unsafe { libc::raise(libc::SIGHUP) };
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn test_principal() {
+        let mut cfg = TlsConfig {
+            cert_path: fixture("server-a.pem"),
+            key_path: fixture("server-a.key"),
+            ..TlsConfig::default()
+        };
+        cfg.principals
+            .insert("CN=builder, O=example".to_owned(), "ci".to_owned());
+        let tls = ReloadableTls::new(&cfg).unwrap();
+
+        assert_eq!(tls.principal(&cert("builder.pem")).as_deref(), Some("ci"));
+        // Other subjects map to their common name.
+        assert_eq!(
+            tls.principal(&cert("server-b.pem")).as_deref(),
+            Some("localhost")
+        );
+        assert_eq!(tls.principal(&cert("no-cn.pem")), None);
+        assert_eq!(tls.principal(&Certificate(b"garbage".to_vec())), None);
+    }
+}
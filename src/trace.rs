@@ -0,0 +1,183 @@
+use std::error::Error;
+
+use crate::config::TracingConfig;
+
+#[cfg(feature = "tracing")]
+mod exporter {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use async_trait::async_trait;
+    use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry::trace::TraceError;
+
+    /// Writes each finished span as one JSON line, a local stand-in for a
+    /// collector.
+    #[derive(Debug)]
+    pub struct JsonFileExporter {
+        file: File,
+    }
+
+    impl JsonFileExporter {
+        pub fn new(path: impl AsRef<Path>) -> io::Result<JsonFileExporter> {
+            let file = OpenOptions::new().append(true).create(true).open(path)?;
+            Ok(JsonFileExporter { file })
+        }
+    }
+
+    fn unix_nanos(time: SystemTime) -> u128 {
+        time.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default()
+    }
+
+    #[async_trait]
+    impl SpanExporter for JsonFileExporter {
+        async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+            for span in batch {
+                let attributes: serde_json::Map<String, serde_json::Value> = span
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| (k.as_str().to_owned(), v.as_str().into_owned().into()))
+                    .collect();
+                let line = serde_json::json!({
+                    "trace_id": span.span_context.trace_id().to_string(),
+                    "span_id": span.span_context.span_id().to_string(),
+                    "parent_span_id": span.parent_span_id.to_string(),
+                    "name": span.name,
+                    "start_time_unix_nano": unix_nanos(span.start_time) as u64,
+                    "end_time_unix_nano": unix_nanos(span.end_time) as u64,
+                    "attributes": attributes,
+                });
+                writeln!(self.file, "{}", line).map_err(|e| TraceError::Other(Box::new(e)))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Installs the global tracer provider. Must be called inside a tokio
+/// runtime, the otlp exporter spawns its batch task on it.
+#[cfg(feature = "tracing")]
+pub fn init(cfg: &TracingConfig) -> Result<(), Box<dyn Error>> {
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::{self as sdktrace, Sampler};
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+
+    if cfg.exporter == "none" {
+        return Ok(());
+    }
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let config = sdktrace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            cfg.sample_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            cfg.service_name.clone(),
+        )]));
+    match cfg.exporter.as_str() {
+        "otlp" => {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(cfg.otlp_endpoint.clone()),
+                )
+                .with_trace_config(config)
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        }
+        "json" => {
+            let provider = sdktrace::TracerProvider::builder()
+                .with_simple_exporter(exporter::JsonFileExporter::new(&cfg.json_file)?)
+                .with_config(config)
+                .build();
+            global::set_tracer_provider(provider);
+        }
+        exporter => return Err(format!("unknown tracing exporter {}", exporter).into()),
+    }
+    info!("tracing enabled"; "exporter" => &cfg.exporter);
+    Ok(())
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn init(cfg: &TracingConfig) -> Result<(), Box<dyn Error>> {
+    if cfg.exporter != "none" {
+        warn!("tracing is configured but greenhouse is built without the `tracing` feature");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use opentelemetry::global;
+    use opentelemetry::trace::{FutureExt, Span, TraceContextExt, Tracer};
+    use opentelemetry::Context;
+    use storage::backend::MemoryBackend;
+    use storage::config::StorageConfig;
+    use storage::Storage;
+
+    #[actix_rt::test]
+    async fn test_json_exporter() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_file = dir.path().join("traces.json");
+        init(&TracingConfig {
+            exporter: "json".to_owned(),
+            json_file: json_file.to_str().unwrap().to_owned(),
+            ..TracingConfig::default()
+        })
+        .unwrap();
+
+        let config = StorageConfig {
+            cache_dir: dir.path().join("cache").to_str().unwrap().to_owned(),
+            ..StorageConfig::default()
+        };
+        let storage = Storage::with_backend(config, Arc::new(MemoryBackend::new()));
+        let request = global::tracer("test").start("request");
+        let request_id = request.span_context().span_id().to_string();
+        let trace_id = request.span_context().trace_id().to_string();
+        let cx = Context::current_with_span(request);
+        storage
+            .write(b"traced".to_vec(), "cas/traced")
+            .with_context(cx.clone())
+            .await
+            .unwrap();
+        let data = storage.read("cas/traced").with_context(cx.clone()).await;
+        assert_eq!(data.unwrap(), b"traced");
+        cx.span().end();
+        // Waits until every span is written out.
+        global::shutdown_tracer_provider();
+
+        let spans: HashMap<String, serde_json::Value> = std::fs::read_to_string(&json_file)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let span: serde_json::Value = serde_json::from_str(line).unwrap();
+                (span["name"].as_str().unwrap().to_owned(), span)
+            })
+            .collect();
+        assert_eq!(spans["request"]["span_id"], request_id.as_str());
+        for stage in &[
+            "storage.pool_wait",
+            "storage.zstd_encode",
+            "storage.write_file",
+            "storage.metadata",
+            "storage.read_file",
+        ] {
+            let span = spans.get(*stage).unwrap_or_else(|| panic!("no {}", stage));
+            assert_eq!(span["trace_id"], trace_id.as_str(), "{}", stage);
+            assert_eq!(span["parent_span_id"], request_id.as_str(), "{}", stage);
+            assert!(span["end_time_unix_nano"].as_u64() >= span["start_time_unix_nano"].as_u64());
+        }
+    }
+}
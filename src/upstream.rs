@@ -0,0 +1,293 @@
+//! Another HTTP cache that local misses fall back to, and that local writes
+//! are optionally copied to.
+
+use std::time::Duration;
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, BytesMut, Data};
+use actix_web::{rt, HttpResponse};
+use awc::error::SendRequestError;
+use awc::Client;
+use futures::stream::{self, StreamExt};
+use storage::{Namespace, Storage};
+
+use crate::config::{BlobSizeLimit, UpstreamConfig};
+use crate::metrics::{UPSTREAM_READS, UPSTREAM_WRITE_BACKS};
+
+/// An upstream cache. It holds an `awc` client, so there is one per worker.
+pub struct Upstream {
+    client: Client,
+    url: String,
+    timeout: Duration,
+    write_back: bool,
+    // Blobs fetched from upstream over the limit are served, not stored.
+    limits: BlobSizeLimit,
+}
+
+impl Upstream {
+    pub fn new(cfg: &UpstreamConfig, limits: BlobSizeLimit) -> Upstream {
+        let mut builder = Client::builder().timeout(cfg.timeout.0);
+        if !cfg.authorization.is_empty() {
+            builder = builder.add_default_header(("Authorization", cfg.authorization.clone()));
+        }
+        Upstream {
+            client: builder.finish(),
+            url: cfg.url.trim_end_matches('/').to_owned(),
+            timeout: cfg.timeout.0,
+            write_back: cfg.write_back,
+            limits,
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.url, key)
+    }
+
+    /// Fetches `key` after a local miss. The body is streamed to the client
+    /// as it arrives and stored locally once complete. Only an upstream 404
+    /// is a 404, an upstream that can't answer is a 502, or a 504 if it
+    /// timed out.
+    pub async fn read_through(&self, key: String, storage: Data<Storage>) -> HttpResponse {
+        let res = match self.client.get(self.url(&key)).send().await {
+            Ok(res) => res,
+            Err(e) => {
+                UPSTREAM_READS.with_label_values(&["error"]).inc();
+                warn!("upstream read failed"; "key" => &key, "err" => %e);
+                return match e {
+                    SendRequestError::Timeout => HttpResponse::GatewayTimeout().finish(),
+                    _ => HttpResponse::BadGateway().finish(),
+                };
+            }
+        };
+        match res.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => {
+                UPSTREAM_READS.with_label_values(&["miss"]).inc();
+                return HttpResponse::NotFound().finish();
+            }
+            status => {
+                UPSTREAM_READS.with_label_values(&["error"]).inc();
+                warn!("upstream read failed"; "key" => &key, "status" => %status);
+                return match status {
+                    StatusCode::GATEWAY_TIMEOUT => HttpResponse::GatewayTimeout().finish(),
+                    _ => HttpResponse::BadGateway().finish(),
+                };
+            }
+        }
+
+        // The client ends the body quietly when the connection drops, so
+        // a short body is only told by its length.
+        let expected = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        // The client timeout only covers the response head.
+        let res = res.timeout(self.timeout);
+        let limit = self.limits.limit(Namespace::from_path(&key));
+        // `buf` turns `None` once the blob grows too large to keep. A hit is
+        // counted once the whole body came, the status is sent by then.
+        let body = stream::unfold(Some((res, Some(BytesMut::new()), 0)), move |state| {
+            let key = key.clone();
+            let storage = storage.clone();
+            async move {
+                let (mut res, mut buf, mut received) = state?;
+                match res.next().await {
+                    Some(Ok(chunk)) => {
+                        received += chunk.len() as u64;
+                        if let Some(b) = &mut buf {
+                            if b.len() as u64 + chunk.len() as u64 > limit {
+                                debug!("upstream blob too large to store"; "key" => &key);
+                                buf = None;
+                            } else {
+                                b.extend_from_slice(&chunk);
+                            }
+                        }
+                        Some((Ok(chunk), Some((res, buf, received))))
+                    }
+                    Some(Err(e)) => {
+                        UPSTREAM_READS.with_label_values(&["error"]).inc();
+                        warn!("upstream read failed"; "key" => &key, "err" => %e);
+                        Some((Err(e), None))
+                    }
+                    None if matches!(expected, Some(len) if len != received) => {
+                        UPSTREAM_READS.with_label_values(&["error"]).inc();
+                        warn!(
+                            "upstream read failed";
+                            "key" => &key,
+                            "received" => received,
+                            "expected" => expected,
+                        );
+                        Some((Err(PayloadError::Incomplete(None)), None))
+                    }
+                    None => {
+                        UPSTREAM_READS.with_label_values(&["hit"]).inc();
+                        if let Some(buf) = buf {
+                            store(storage, key, buf);
+                        }
+                        None
+                    }
+                }
+            }
+        });
+        HttpResponse::Ok()
+            .content_type("text/plain")
+            .streaming(body)
+    }
+
+    /// Copies a local write to upstream in the background, if enabled.
+    pub fn write_back(&self, key: String, data: Bytes) {
+        if !self.write_back {
+            return;
+        }
+        let req = self.client.put(self.url(&key));
+        rt::spawn(async move {
+            match req.send_body(data).await {
+                Ok(res) if res.status().is_success() => {
+                    UPSTREAM_WRITE_BACKS.with_label_values(&["ok"]).inc();
+                }
+                Ok(res) => {
+                    UPSTREAM_WRITE_BACKS.with_label_values(&["error"]).inc();
+                    warn!("upstream write-back failed"; "key" => key, "status" => %res.status());
+                }
+                Err(e) => {
+                    UPSTREAM_WRITE_BACKS.with_label_values(&["error"]).inc();
+                    warn!("upstream write-back failed"; "key" => key, "err" => %e);
+                }
+            }
+        });
+    }
+}
+
+fn store(storage: Data<Storage>, key: String, buf: BytesMut) {
+    rt::spawn(async move {
+        if let Err(e) = storage.write(buf.to_vec(), key.clone()).await {
+            warn!("failed to store upstream blob"; "key" => key, "err" => %e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use actix_web::body::to_bytes;
+    use actix_web::{web, App, HttpServer};
+    use cibo_util::config::{ReadableDuration, ReadableSize};
+    use storage::backend::MemoryBackend;
+    use storage::config::StorageConfig;
+
+    // Serves each outcome the cache has to tell apart.
+    async fn stub_upstream() -> String {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/cas/hit", web::get().to(|| async { "blob" }))
+                .route(
+                    "/cas/miss",
+                    web::get().to(|| async { HttpResponse::NotFound().finish() }),
+                )
+                .route(
+                    "/cas/down",
+                    web::get().to(|| async { HttpResponse::ServiceUnavailable().finish() }),
+                )
+                .route(
+                    "/cas/slow",
+                    web::get().to(|| async {
+                        rt::time::sleep(Duration::from_secs(5)).await;
+                        "late"
+                    }),
+                )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        rt::spawn(server.run());
+        url
+    }
+
+    // Promises a body of 8 bytes, sends 4 and hangs up, to any request.
+    fn cut_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut head = [0; 1024];
+                let _ = stream.read(&mut head);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 8\r\n\r\npart");
+            }
+        });
+        url
+    }
+
+    fn upstream_at(url: String) -> Upstream {
+        let cfg = UpstreamConfig {
+            url,
+            timeout: ReadableDuration::millis(500),
+            ..UpstreamConfig::default()
+        };
+        let limits = BlobSizeLimit {
+            ac: ReadableSize::mb(1),
+            cas: ReadableSize::mb(1),
+            other: ReadableSize::mb(1),
+        };
+        Upstream::new(&cfg, limits)
+    }
+
+    fn reads(result: &str) -> i64 {
+        UPSTREAM_READS.with_label_values(&[result]).get()
+    }
+
+    #[actix_rt::test]
+    async fn test_read_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            cache_dir: dir.path().to_str().unwrap().to_owned(),
+            ..StorageConfig::default()
+        };
+        let storage = Data::new(Storage::with_backend(
+            config,
+            Arc::new(MemoryBackend::new()),
+        ));
+        let upstream = upstream_at(stub_upstream().await);
+        let read = |key: &str| upstream.read_through(key.to_owned(), storage.clone());
+
+        let (hits, errors) = (reads("hit"), reads("error"));
+        let res = read("cas/hit").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "blob");
+        assert_eq!(reads("hit"), hits + 1);
+
+        assert_eq!(read("cas/miss").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(read("cas/down").await.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(read("cas/slow").await.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(reads("error"), errors + 2);
+
+        // Cut off after the head, only counted as an error.
+        let res = upstream_at(cut_upstream())
+            .read_through("cas/cut".to_owned(), storage.clone())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(to_bytes(res.into_body()).await.is_err());
+        assert_eq!(reads("hit"), hits + 1);
+        assert_eq!(reads("error"), errors + 3);
+
+        // Nothing listens there.
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let res = upstream_at(format!("http://{}", closed))
+            .read_through("cas/hit".to_owned(), storage.clone())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(reads("error"), errors + 4);
+    }
+}
@@ -0,0 +1,152 @@
+use std::net::TcpListener;
+
+use actix_web::web::{Bytes, Data};
+use greenhouse::config::{ClusterConfig, Config};
+use greenhouse::route;
+use storage::Storage;
+use tempfile::TempDir;
+
+struct Node {
+    url: String,
+    storage: Data<Storage>,
+    _dir: TempDir,
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts `n` nodes on localhost ports, in the current actix system.
+fn start_cluster(n: usize, replication_factor: usize) -> Vec<Node> {
+    let ports: Vec<u16> = (0..n).map(|_| free_port()).collect();
+    let peers: Vec<String> = ports
+        .iter()
+        .map(|port| format!("http://127.0.0.1:{}", port))
+        .collect();
+    ports
+        .iter()
+        .zip(&peers)
+        .map(|(port, url)| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut cfg = Config::default();
+            cfg.http_service.addr = format!("127.0.0.1:{}", port);
+            cfg.http_service.http_worker = 1;
+            cfg.storage.cache_dir = dir.path().to_str().unwrap().to_owned();
+            cfg.cluster = ClusterConfig {
+                advertise_url: url.clone(),
+                peers: peers.clone(),
+                replication_factor,
+                secret: "peers only".to_owned(),
+                ..ClusterConfig::default()
+            };
+            let storage = Data::new(Storage::new(cfg.storage.clone()));
+            actix_rt::spawn(route::http_server(&cfg, storage.clone()));
+            Node {
+                url: url.clone(),
+                storage,
+                _dir: dir,
+            }
+        })
+        .collect()
+}
+
+async fn copies(nodes: &[Node], key: &str) -> usize {
+    let mut copies = 0;
+    for node in nodes {
+        if node.storage.read(key.to_owned()).await.is_ok() {
+            copies += 1;
+        }
+    }
+    copies
+}
+
+#[actix_rt::test]
+async fn test_cluster_replicates_and_proxies() {
+    let nodes = start_cluster(3, 2);
+    let client = awc::Client::default();
+    let key = "cas/0f1e2d3c4b5a69788796a5b4c3d2e1f0";
+    let blob = Bytes::from(vec![42u8; 4096]);
+
+    let res = client
+        .put(format!("{}/{}", nodes[0].url, key))
+        .send_body(blob.clone())
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(copies(&nodes, key).await, 2);
+
+    // Every node serves the key, owner or not.
+    for node in &nodes {
+        let mut res = client
+            .get(format!("{}/{}", node.url, key))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200, "read through {}", node.url);
+        assert_eq!(res.body().await.unwrap(), blob);
+    }
+
+    let res = client
+        .delete(format!("{}/{}", nodes[2].url, key))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(copies(&nodes, key).await, 0);
+    for node in &nodes {
+        let res = client
+            .get(format!("{}/{}", node.url, key))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+    }
+}
+
+#[actix_rt::test]
+async fn test_cluster_spreads_keys() {
+    let nodes = start_cluster(3, 1);
+    let client = awc::Client::default();
+    let keys: Vec<String> = (0..30).map(|i| format!("ac/{:032x}", i)).collect();
+    for (i, key) in keys.iter().enumerate() {
+        let res = client
+            .put(format!("{}/{}", nodes[i % nodes.len()].url, key))
+            .send_body(format!("action {}", i))
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+    }
+    for key in &keys {
+        assert_eq!(copies(&nodes, key).await, 1, "{}", key);
+    }
+    // Each node owns a share of the keys.
+    for node in &nodes {
+        let mut owned = 0;
+        for key in &keys {
+            if node.storage.read(key.clone()).await.is_ok() {
+                owned += 1;
+            }
+        }
+        assert!(owned > 0, "{} owns no key", node.url);
+    }
+}
+
+#[actix_rt::test]
+async fn test_cluster_ignores_forged_forwarding() {
+    let nodes = start_cluster(3, 2);
+    let client = awc::Client::default();
+    let key = "cas/00112233445566778899aabbccddeeff";
+    // Taken as from a peer, the blob would only be written where it landed.
+    let res = client
+        .put(format!("{}/{}", nodes[1].url, key))
+        .insert_header((greenhouse::cluster::FORWARDED_HEADER, "1"))
+        .send_body(vec![7u8; 1024])
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(copies(&nodes, key).await, 2);
+}